@@ -188,6 +188,11 @@ def plugin_command(cmd: str, payload: dict = None, timeout: float = 5.0, session
     if not is_plugin_available():
         return {"success": False, "error": "pane-bridge plugin not installed"}
 
+    # A running daemon keeps a pipe open per command, which saves starting zellij
+    reply = daemon_bridge(cmd, payload, timeout=timeout, session=session)
+    if reply is not None:
+        return reply
+
     plugin_url = f"file://{_plugin_path_cache}"
     payload_json = json.dumps(payload) if payload else "{}"
 
@@ -212,10 +217,15 @@ def plugin_command(cmd: str, payload: dict = None, timeout: float = 5.0, session
         # returncode 124 means timeout killed it (expected), but we got output
         stdout = result.stdout.strip()
         if stdout:
-            try:
-                return json.loads(stdout)
-            except json.JSONDecodeError:
-                return {"success": True, "raw": stdout}
+            # Long requests write {"progress": ...} lines before the reply itself
+            for line in reversed(stdout.splitlines()):
+                try:
+                    reply = json.loads(line)
+                except json.JSONDecodeError:
+                    continue
+                if isinstance(reply, dict) and "progress" not in reply:
+                    return reply
+            return {"success": True, "raw": stdout}
 
         # No output - check if there was an error
         if result.stderr:
@@ -284,27 +294,80 @@ def is_daemon_running(session: str = None) -> bool:
     return os.path.exists(socket_path)
 
 
+def _daemon_exchange(request: dict, socket_path: str, timeout: float) -> dict:
+    """Send one newline-delimited request to the daemon and read its reply line."""
+    sock = socket_module.socket(socket_module.AF_UNIX, socket_module.SOCK_STREAM)
+    try:
+        sock.settimeout(timeout)
+        sock.connect(socket_path)
+        sock.sendall((json.dumps(request) + "\n").encode('utf-8'))
+        return _read_daemon_reply(sock)
+    finally:
+        sock.close()
+
+
+def _read_daemon_reply(sock) -> dict:
+    """Read one newline-terminated JSON reply from a daemon connection."""
+    response = b""
+    while not response.endswith(b"\n"):
+        chunk = sock.recv(65536)
+        if not chunk:
+            break
+        response += chunk
+    return json.loads(response.decode('utf-8'))
+
+
 def daemon_request(request: dict, session: str = None, timeout: float = 10.0) -> dict:
     """Send a request to the daemon and return the response."""
     socket_path = get_daemon_socket_path(session)
     if not os.path.exists(socket_path):
         return {"success": False, "error": "Daemon not running"}
 
-    sock = socket_module.socket(socket_module.AF_UNIX, socket_module.SOCK_STREAM)
     try:
-        sock.settimeout(timeout)
-        sock.connect(socket_path)
-        sock.send(json.dumps(request).encode('utf-8'))
-        response = sock.recv(65536).decode('utf-8')
-        return json.loads(response)
+        return _daemon_exchange(request, socket_path, timeout)
     except socket_module.timeout:
         return {"success": False, "error": "Daemon request timed out"}
     except ConnectionRefusedError:
         return {"success": False, "error": "Daemon connection refused"}
     except Exception as e:
         return {"success": False, "error": str(e)}
+
+
+def daemon_bridge(cmd: str, payload: dict = None, timeout: float = 5.0, session: str = None) -> Optional[dict]:
+    """Send a plugin command through the daemon's open pipes.
+
+    Returns None when no daemon could take it (none running, unreachable, or
+    too old to know `bridge`), so the caller can fall back to `zellij pipe`.
+    Once the request is sent the command may have run, so failures after that
+    are reported instead of sending it a second time.
+    """
+    socket_path = get_daemon_socket_path(session)
+    if not os.path.exists(socket_path):
+        return None
+    request = {
+        "cmd": "bridge", "name": cmd, "payload": payload or {},
+        "timeout": timeout, "plugin": _plugin_path_cache,
+    }
+    sock = socket_module.socket(socket_module.AF_UNIX, socket_module.SOCK_STREAM)
+    try:
+        sock.settimeout(timeout + 2)
+        try:
+            sock.connect(socket_path)
+            sock.sendall((json.dumps(request) + "\n").encode('utf-8'))
+        except OSError:
+            return None
+        try:
+            reply = _read_daemon_reply(sock)
+        except socket_module.timeout:
+            return {"success": False, "error": "Daemon request timed out"}
+        except (OSError, ValueError) as e:
+            return {"success": False, "error": f"Daemon reply lost after sending the command: {e}"}
     finally:
         sock.close()
+    # The plugin's own "Unknown command '<name>'" is a real answer, only the daemon's means it is too old
+    if reply.get("error") == "Unknown command: bridge":
+        return None
+    return reply
 
 
 def daemon_read_pane(pane_id: int, full: bool = False, tail: int = None,
@@ -5,6 +5,11 @@ Zellij Daemon - Runs INSIDE a Zellij session to provide pane reading.
 Since it runs attached to the session, dump-screen works correctly.
 Listens on a Unix socket for commands from the MCP server.
 
+Requests and replies are newline-delimited JSON, any number per connection.
+`{"cmd": "bridge", "name": "<command>", "payload": {...}}` passes a command to
+the pane-bridge plugin over a `zellij pipe` kept open per command name, so a
+command costs a line on a pipe instead of a fresh `zellij` process.
+
 Usage:
     # Start daemon (typically in a hidden/background pane)
     python zellij-daemon.py --socket /tmp/zellij-daemon-<session>.sock
@@ -15,6 +20,7 @@ Usage:
 import argparse
 import json
 import os
+import queue
 import re
 import signal
 import socket
@@ -24,6 +30,76 @@ import threading
 import time
 
 
+class BridgeChannel:
+    """A long-lived `zellij pipe` to the plugin for one command name.
+
+    Without a payload argument `zellij pipe` sends each stdin line as its own
+    message, and the plugin answers a pipe's messages in order, so requests
+    are written one at a time and the next reply line is theirs.
+    """
+
+    def __init__(self, plugin_path: str, name: str):
+        self.plugin_path = plugin_path
+        self.name = name
+        self.lock = threading.Lock()
+        self.proc = None
+        self.lines = None
+
+    def _open(self):
+        self.proc = subprocess.Popen(
+            ['zellij', 'pipe', '-p', f'file://{self.plugin_path}', '-n', self.name],
+            stdin=subprocess.PIPE, stdout=subprocess.PIPE, stderr=subprocess.DEVNULL,
+            text=True, bufsize=1
+        )
+        self.lines = queue.Queue()
+        threading.Thread(target=self._read, args=(self.proc, self.lines), daemon=True).start()
+
+    @staticmethod
+    def _read(proc, lines):
+        for line in proc.stdout:
+            lines.put(line)
+        lines.put(None)
+
+    def close(self):
+        if self.proc is not None:
+            try:
+                self.proc.kill()
+                self.proc.wait(timeout=1)
+            except Exception:
+                pass
+        self.proc = None
+
+    def request(self, payload: dict, timeout: float = 5.0) -> dict:
+        """Send one payload and wait for its reply, skipping progress lines."""
+        with self.lock:
+            if self.proc is None or self.proc.poll() is not None:
+                self._open()
+            try:
+                self.proc.stdin.write(json.dumps(payload) + '\n')
+                self.proc.stdin.flush()
+            except (BrokenPipeError, OSError) as e:
+                self.close()
+                return {'success': False, 'error': f'Pipe to plugin closed: {e}'}
+
+            deadline = time.monotonic() + timeout
+            while True:
+                try:
+                    line = self.lines.get(timeout=max(deadline - time.monotonic(), 0))
+                except queue.Empty:
+                    # A late reply would be taken for the next request's, so start over
+                    self.close()
+                    return {'success': False, 'error': f'No reply from plugin within {timeout}s'}
+                if line is None:
+                    self.close()
+                    return {'success': False, 'error': 'Pipe to plugin closed'}
+                try:
+                    reply = json.loads(line)
+                except json.JSONDecodeError:
+                    continue
+                if isinstance(reply, dict) and 'progress' not in reply:
+                    return reply
+
+
 class ZellijDaemon:
     """Daemon that runs inside Zellij to provide pane operations."""
 
@@ -35,6 +111,8 @@ class ZellijDaemon:
         self.plugin_path = os.path.expanduser(
             "~/.local/share/zellij-mcp/plugins/zellij-pane-bridge.wasm"
         )
+        self.channels = {}
+        self.channels_lock = threading.Lock()
 
     def start(self):
         """Start the daemon."""
@@ -66,69 +144,94 @@ class ZellijDaemon:
             server.close()
             if os.path.exists(self.socket_path):
                 os.unlink(self.socket_path)
+            with self.channels_lock:
+                for channel in self.channels.values():
+                    channel.close()
 
     def _handle_client(self, conn):
-        """Handle a client connection."""
+        """Handle a client connection: one reply line per request line until it closes."""
         try:
-            data = conn.recv(8192).decode('utf-8').strip()
-            if not data:
-                return
-
-            request = json.loads(data)
-            cmd = request.get('cmd', '')
-
-            if cmd == 'read':
-                pane_id = request.get('pane_id')
-                full = request.get('full', False)
-                tail = request.get('tail')
-                response = self._read_pane(pane_id, full, tail)
-            elif cmd == 'focus':
-                pane_id = request.get('pane_id')
-                response = self._focus_pane(pane_id)
-            elif cmd == 'write':
-                pane_id = request.get('pane_id')
-                chars = request.get('chars', '')
-                response = self._write_pane(pane_id, chars)
-            elif cmd == 'list':
-                response = self._list_panes()
-            elif cmd == 'status':
-                response = {'success': True, 'session': self.session, 'pid': os.getpid()}
-            elif cmd == 'stop':
-                self.running = False
-                response = {'success': True, 'message': 'Stopping daemon'}
-            else:
-                response = {'success': False, 'error': f'Unknown command: {cmd}'}
-
-            conn.send(json.dumps(response).encode('utf-8'))
-        except Exception as e:
-            try:
-                conn.send(json.dumps({'success': False, 'error': str(e)}).encode('utf-8'))
-            except:
-                pass
+            buffer = b''
+            while self.running:
+                chunk = conn.recv(65536)
+                if not chunk:
+                    break
+                buffer += chunk
+                while b'\n' in buffer:
+                    line, buffer = buffer.split(b'\n', 1)
+                    if line.strip():
+                        self._reply(conn, line)
+                # One-shot clients send a single request without a newline and wait for the answer
+                if buffer.strip() and self._is_json(buffer):
+                    self._reply(conn, buffer)
+                    buffer = b''
+        except OSError:
+            pass
         finally:
             conn.close()
 
-    def _plugin_cmd(self, cmd: str, payload: dict = None) -> dict:
-        """Execute a plugin command."""
-        if not os.path.exists(self.plugin_path):
-            return {'success': False, 'error': 'Plugin not found'}
+    @staticmethod
+    def _is_json(data: bytes) -> bool:
+        try:
+            json.loads(data)
+            return True
+        except ValueError:
+            return False
 
-        payload_json = json.dumps(payload) if payload else '{}'
+    def _reply(self, conn, line: bytes):
         try:
-            result = subprocess.run(
-                ['timeout', '5', 'zellij', 'pipe',
-                 '-p', f'file://{self.plugin_path}', '-n', cmd, '--', payload_json],
-                capture_output=True, text=True, timeout=7
+            response = self._dispatch(json.loads(line))
+        except Exception as e:
+            response = {'success': False, 'error': str(e)}
+        conn.sendall((json.dumps(response) + '\n').encode('utf-8'))
+
+    def _dispatch(self, request: dict) -> dict:
+        """Answer one request."""
+        cmd = request.get('cmd', '')
+
+        if cmd == 'bridge':
+            name = request.get('name')
+            if not name:
+                return {'success': False, 'error': 'bridge requests need a command name'}
+            return self._plugin_cmd(
+                name, request.get('payload'), request.get('timeout', 5.0), request.get('plugin')
             )
-            stdout = result.stdout.strip()
-            if stdout:
-                # Handle duplicate JSON responses
-                if '}{' in stdout:
-                    stdout = stdout.split('}{')[0] + '}'
-                return json.loads(stdout)
-            return {'success': False, 'error': 'No output'}
-        except json.JSONDecodeError as e:
-            return {'success': False, 'error': f'JSON decode error: {e}'}
+        elif cmd == 'read':
+            pane_id = request.get('pane_id')
+            full = request.get('full', False)
+            tail = request.get('tail')
+            return self._read_pane(pane_id, full, tail)
+        elif cmd == 'focus':
+            pane_id = request.get('pane_id')
+            return self._focus_pane(pane_id)
+        elif cmd == 'write':
+            pane_id = request.get('pane_id')
+            chars = request.get('chars', '')
+            return self._write_pane(pane_id, chars)
+        elif cmd == 'list':
+            return self._list_panes()
+        elif cmd == 'status':
+            with self.channels_lock:
+                channels = sorted(name for _, name in self.channels)
+            return {'success': True, 'session': self.session, 'pid': os.getpid(), 'channels': channels}
+        elif cmd == 'stop':
+            self.running = False
+            return {'success': True, 'message': 'Stopping daemon'}
+        else:
+            return {'success': False, 'error': f'Unknown command: {cmd}'}
+
+    def _plugin_cmd(self, cmd: str, payload: dict = None, timeout: float = 5.0, plugin_path: str = None) -> dict:
+        """Execute a plugin command over the channel kept open for its name."""
+        plugin_path = plugin_path or self.plugin_path
+        if not os.path.exists(plugin_path):
+            return {'success': False, 'error': 'Plugin not found'}
+
+        with self.channels_lock:
+            channel = self.channels.get((plugin_path, cmd))
+            if channel is None:
+                channel = self.channels[(plugin_path, cmd)] = BridgeChannel(plugin_path, cmd)
+        try:
+            return channel.request(payload or {}, timeout)
         except Exception as e:
             return {'success': False, 'error': str(e)}
 
@@ -0,0 +1,303 @@
+//! Headless harness for driving the pane bridge inside a real Zellij session.
+//!
+//! Each `Session` starts its own background Zellij server (Zellij gives it a
+//! pseudo-terminal of its own, no client attached), pre-grants the plugin's
+//! permissions in a private cache directory so no prompt blocks it, and talks
+//! to the plugin through `zellij pipe` exactly as the MCP server does.
+//!
+//! Requires a `zellij` binary on PATH and a built plugin. The plugin is taken
+//! from `ZELLIJ_PANE_BRIDGE_WASM`, then the release build, then the prebuilt
+//! copy at the repository root. `Session::start` returns `None` when either is
+//! missing so the tests skip instead of failing.
+
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How long a single pipe round trip may take before it counts as lost
+pub const PIPE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Permissions the plugin asks for at load or on demand (mirrors REQUIRED_PERMISSIONS
+/// and ON_DEMAND_PERMISSIONS)
+const PERMISSIONS: &[&str] = &[
+    "ReadApplicationState",
+    "WriteToStdin",
+    "ChangeApplicationState",
+    "ReadCliPipes",
+    "OpenTerminalsOrPlugins",
+    "RunCommands",
+    "WebAccess",
+    "FullHdAccess",
+];
+
+static SESSION_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Session {
+    name: String,
+    plugin: PathBuf,
+    scratch: PathBuf,
+}
+
+impl Session {
+    /// Start a fresh background session, or `None` if zellij or the plugin is unavailable
+    pub fn start() -> Option<Session> {
+        let plugin = find_plugin()?;
+        if Command::new("zellij").arg("--version").output().map(|o| !o.status.success()).unwrap_or(true) {
+            eprintln!("zellij not found on PATH, skipping");
+            return None;
+        }
+
+        let n = SESSION_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let name = format!("zpb-e2e-{}-{}", std::process::id(), n);
+        let scratch = std::env::temp_dir().join(&name);
+        std::fs::create_dir_all(scratch.join("cache/zellij")).ok()?;
+        std::fs::create_dir_all(scratch.join("config")).ok()?;
+        std::fs::write(scratch.join("cache/zellij/permissions.kdl"), permissions_kdl(&plugin)).ok()?;
+
+        let session = Session { name, plugin, scratch };
+        let status = session
+            .zellij()
+            .args(["attach", "--create-background", &session.name])
+            .status()
+            .ok()?;
+        if !status.success() {
+            eprintln!("could not start zellij session {}", session.name);
+            return None;
+        }
+
+        // The server needs a moment before it accepts pipes; ping until the plugin answers
+        let ready = session.wait_until(Duration::from_secs(20), |s| {
+            s.try_send("ping", &serde_json::json!({})).map(|r| r["success"] == true).unwrap_or(false)
+        });
+        if !ready {
+            eprintln!("plugin never answered in session {}", session.name);
+            return None;
+        }
+        Some(session)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn plugin(&self) -> &Path {
+        &self.plugin
+    }
+
+    /// A zellij invocation isolated to this session's config and permission cache
+    fn zellij(&self) -> Command {
+        let mut cmd = Command::new("zellij");
+        self.isolate(&mut cmd).env_remove("ZELLIJ_SESSION_NAME");
+        cmd
+    }
+
+    fn isolate<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        cmd.env("XDG_CACHE_HOME", self.scratch.join("cache"))
+            .env("ZELLIJ_CONFIG_DIR", self.scratch.join("config"))
+            .env_remove("ZELLIJ")
+            .stdin(Stdio::null())
+    }
+
+    /// Start the repository's `zellij-daemon.py` for this session, with its
+    /// socket in the scratch directory, or `None` if it does not come up
+    pub fn daemon(&self) -> Option<Daemon> {
+        let script = Path::new(env!("CARGO_MANIFEST_DIR")).parent()?.join("zellij-daemon.py");
+        let socket = self.scratch.join("daemon.sock");
+        let mut python = Command::new("python3");
+        python.arg(&script).arg("--socket").arg(&socket);
+        // The daemon's `zellij pipe`s find the session through the variable a pane would have
+        self.isolate(&mut python)
+            .env("ZELLIJ_SESSION_NAME", &self.name)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        let mut child = python.spawn().ok()?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !socket.exists() {
+            if Instant::now() >= deadline {
+                eprintln!("zellij-daemon.py never opened its socket, skipping");
+                kill(&mut child);
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Some(Daemon { child, socket })
+    }
+
+    /// Send a command to the plugin and return its parsed reply, panicking if none arrives
+    pub fn send(&self, cmd: &str, payload: &Value) -> Value {
+        self.try_send(cmd, payload)
+            .unwrap_or_else(|e| panic!("{} in session {}: {}", cmd, self.name, e))
+    }
+
+    /// Send a command to the plugin, waiting up to PIPE_TIMEOUT for the reply line
+    pub fn try_send(&self, cmd: &str, payload: &Value) -> Result<Value, String> {
+        let plugin_url = format!("file:{}", self.plugin.display());
+        let mut child = self
+            .zellij()
+            .args(["--session", &self.name, "pipe", "-p", &plugin_url, "-n", cmd, "--"])
+            .arg(payload.to_string())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("spawn failed: {}", e))?;
+
+        // zellij pipe does not exit after the reply, so read up to the reply line and kill it.
+        // Progress lines of long requests come first and carry no `success`.
+        let stdout = child.stdout.take().ok_or("no stdout")?;
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || is_reply(&line) {
+                    break;
+                }
+            }
+            let _ = tx.send(line);
+        });
+        let line = rx.recv_timeout(PIPE_TIMEOUT);
+        kill(&mut child);
+
+        let line = line.map_err(|_| "timed out waiting for reply".to_string())?;
+        serde_json::from_str(line.trim()).map_err(|e| format!("bad reply {:?}: {}", line, e))
+    }
+
+    /// Send a command and return its `data`, panicking if the plugin reported failure
+    pub fn data(&self, cmd: &str, payload: &Value) -> Value {
+        let reply = self.send(cmd, payload);
+        assert_eq!(reply["success"], true, "{} failed: {}", cmd, reply);
+        reply["data"].clone()
+    }
+
+    /// Run a `zellij action` against this session and return its stdout
+    pub fn action(&self, args: &[&str]) -> String {
+        let out = self
+            .zellij()
+            .args(["--session", &self.name, "action"])
+            .args(args)
+            .output()
+            .unwrap_or_else(|e| panic!("zellij action {:?}: {}", args, e));
+        String::from_utf8_lossy(&out.stdout).into_owned()
+    }
+
+    /// Current panes as reported by the bridge's `list` command
+    pub fn panes(&self) -> Vec<Value> {
+        match self.data("list", &serde_json::json!({})) {
+            Value::Array(panes) => panes,
+            other => panic!("list returned {}", other),
+        }
+    }
+
+    /// Tab names as reported by `list_tabs`
+    pub fn tab_names(&self) -> Vec<String> {
+        match self.data("list_tabs", &serde_json::json!({})) {
+            Value::Array(tabs) => tabs.iter().filter_map(|t| t["name"].as_str().map(String::from)).collect(),
+            other => panic!("list_tabs returned {}", other),
+        }
+    }
+
+    /// Poll `check` every 200ms until it holds or `timeout` passes
+    pub fn wait_until(&self, timeout: Duration, mut check: impl FnMut(&Session) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if check(self) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.zellij().args(["kill-session", &self.name]).output();
+        let _ = self.zellij().args(["delete-session", "--force", &self.name]).output();
+        let _ = std::fs::remove_dir_all(&self.scratch);
+    }
+}
+
+/// A running `zellij-daemon.py`, stopped when dropped
+pub struct Daemon {
+    child: Child,
+    socket: PathBuf,
+}
+
+impl Daemon {
+    /// Send newline-delimited requests over one connection and read a reply line for each
+    pub fn exchange(&self, requests: &[Value]) -> Result<Vec<Value>, String> {
+        let stream = UnixStream::connect(&self.socket).map_err(|e| format!("connect failed: {}", e))?;
+        stream.set_read_timeout(Some(PIPE_TIMEOUT)).map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        let mut writer = stream;
+        let mut replies = Vec::new();
+        for request in requests {
+            writeln!(writer, "{}", request).map_err(|e| format!("write failed: {}", e))?;
+            let mut line = String::new();
+            reader.read_line(&mut line).map_err(|e| format!("no reply to {}: {}", request, e))?;
+            replies.push(serde_json::from_str(line.trim()).map_err(|e| format!("bad reply {:?}: {}", line, e))?);
+        }
+        Ok(replies)
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        kill(&mut self.child);
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+fn is_reply(line: &str) -> bool {
+    !line.trim().is_empty()
+        && serde_json::from_str::<Value>(line.trim()).map(|v| v.get("progress").is_none()).unwrap_or(true)
+}
+
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn find_plugin() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var("ZELLIJ_PANE_BRIDGE_WASM") {
+        return Some(PathBuf::from(path));
+    }
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent()?;
+    let candidates = [
+        root.join("zellij-pane-bridge/target/wasm32-wasip1/release/zellij-pane-bridge.wasm"),
+        root.join("zellij-pane-bridge.wasm"),
+    ];
+    let found = candidates.into_iter().find(|p| p.exists());
+    if found.is_none() {
+        eprintln!("no built plugin found (set ZELLIJ_PANE_BRIDGE_WASM), skipping");
+    }
+    found
+}
+
+/// Zellij's permission cache format: the plugin location followed by its granted
+/// permissions. Written under the bare path and the `file:` URLs since the key
+/// has changed between Zellij releases.
+fn permissions_kdl(plugin: &Path) -> String {
+    let mut kdl = String::new();
+    for key in [
+        plugin.display().to_string(),
+        format!("file:{}", plugin.display()),
+        // As the MCP server and zellij-daemon.py address it
+        format!("file://{}", plugin.display()),
+    ] {
+        kdl.push_str(&format!("\"{}\" {{\n", key));
+        for p in PERMISSIONS {
+            kdl.push_str(&format!("    {}\n", p));
+        }
+        kdl.push_str("}\n");
+    }
+    kdl
+}
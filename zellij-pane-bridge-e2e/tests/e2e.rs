@@ -0,0 +1,110 @@
+//! End-to-end checks against a live Zellij session. Each test skips (passes
+//! with a note on stderr) when zellij or the built plugin is not available.
+
+use serde_json::{json, Value};
+use std::time::Duration;
+use zellij_pane_bridge_e2e::Session;
+
+const SETTLE: Duration = Duration::from_secs(10);
+
+macro_rules! session_or_skip {
+    () => {
+        match Session::start() {
+            Some(s) => s,
+            None => return,
+        }
+    };
+}
+
+fn pane_running(s: &Session, needle: &str) -> Option<Value> {
+    s.panes()
+        .into_iter()
+        .find(|p| p["command"].as_str().map(|c| c.contains(needle)).unwrap_or(false))
+}
+
+#[test]
+fn run_tracks_exit_status() {
+    let s = session_or_skip!();
+
+    let reply = s.send("run", &json!({"command": "sh", "args": ["-c", "sleep 1; exit 3"]}));
+    assert_eq!(reply["success"], true, "run failed: {}", reply);
+
+    assert!(
+        s.wait_until(SETTLE, |s| pane_running(s, "exit 3").is_some()),
+        "command pane never appeared in {}",
+        s.name()
+    );
+    let pane_id = pane_running(&s, "exit 3").unwrap()["id"].clone();
+
+    assert!(
+        s.wait_until(SETTLE, |s| {
+            s.data("command_status", &json!({"pane_id": pane_id}))["exited"] == true
+        }),
+        "exit was never recorded for pane {}",
+        pane_id
+    );
+    let status = s.data("command_status", &json!({"pane_id": pane_id}));
+    assert_eq!(status["exit_code"], 3, "unexpected status: {}", status);
+}
+
+#[test]
+fn protected_pane_refuses_close() {
+    let s = session_or_skip!();
+
+    let terminal = s
+        .panes()
+        .into_iter()
+        .find(|p| p["is_plugin"] == false)
+        .expect("session has no terminal pane");
+    s.data("protect", &json!({"pane_id": terminal["id"]}));
+
+    let reply = s.send("close", &json!({"pane_id": terminal["id"]}));
+    assert_eq!(reply["success"], false, "close of protected pane succeeded: {}", reply);
+    assert_eq!(reply["error_code"], "protected", "unexpected error: {}", reply);
+
+    // The refusal is reported and the pane is still there
+    let report = s.data("protection_report", &json!({}));
+    assert!(report.to_string().contains("close"), "violation not reported: {}", report);
+    assert!(s.panes().iter().any(|p| p["id"] == terminal["id"]));
+}
+
+#[test]
+fn tab_lifecycle() {
+    let s = session_or_skip!();
+
+    s.data("new_tab", &json!({"name": "e2e-tab"}));
+    assert!(
+        s.wait_until(SETTLE, |s| s.tab_names().iter().any(|n| n == "e2e-tab")),
+        "new tab never listed: {:?}",
+        s.tab_names()
+    );
+
+    // Cross-check against zellij's own view of the layout
+    assert!(s.action(&["query-tab-names"]).contains("e2e-tab"));
+
+    s.data("close_tab", &json!({"index": 1, "force": true}));
+    assert!(
+        s.wait_until(SETTLE, |s| !s.tab_names().iter().any(|n| n == "e2e-tab")),
+        "tab never closed: {:?}",
+        s.tab_names()
+    );
+}
+
+#[test]
+fn daemon_bridges_commands_over_one_connection() {
+    let s = session_or_skip!();
+    let Some(daemon) = s.daemon() else {
+        return;
+    };
+
+    // Several requests on one connection, each through the pipe the daemon keeps open
+    let ping = json!({"cmd": "bridge", "name": "ping", "payload": {}, "plugin": s.plugin()});
+    let replies = daemon.exchange(&[ping.clone(), ping.clone(), ping]).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(replies.len(), 3);
+    for reply in &replies {
+        assert_eq!(reply["success"], true, "ping through the daemon failed: {}", reply);
+    }
+
+    let status = daemon.exchange(&[json!({"cmd": "status"})]).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(status[0]["channels"], json!(["ping"]), "unexpected status: {}", status[0]);
+}
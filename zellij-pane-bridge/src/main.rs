@@ -3,683 +3,8668 @@
 //! Enables full pane control by ID without focus stealing.
 //! Designed for AI agents that need to manage multiple panes autonomously.
 
+#[macro_use]
+mod trace;
+mod wire;
+
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use wire::Encoding;
 use zellij_tile::prelude::*;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 struct State {
     panes: HashMap<usize, Vec<PaneInfo>>,
     tabs: Vec<TabInfo>,
     command_results: HashMap<u32, CommandResult>,
     /// The pane that was focused when we received the first command - this is Claude's pane
     protected_pane_id: Option<u32>,
+    /// Panes whose title or command matches one of these are protected too
+    protect_patterns: Vec<regex::Regex>,
+    /// Compiled `prompt_title_patterns`: a title matching one means the shell is at its prompt
+    prompt_patterns: Vec<regex::Regex>,
+    /// Command panes the bridge opened, which `enforce_ownership` lets commands change
+    owned_panes: BTreeSet<u32>,
+    /// Other panes commands may change under `enforce_ownership`, given with `grant_pane`
+    granted_panes: BTreeSet<u32>,
+    /// How the command panes the bridge opened were started, for `duplicate`
+    /// and `export_layout_kdl`
+    pane_launches: HashMap<u32, Launch>,
+    /// Opaque handle of every terminal pane seen, which unlike its id is never reused
+    pane_handles: HashMap<u32, String>,
+    /// Handles of panes that have closed, oldest first, so stale handles get a clear error
+    closed_handles: VecDeque<String>,
+    /// Highest `nonce` accepted so far
+    last_nonce: Option<u64>,
+    /// `ts_ms` and payload hash of requests accepted within the replay window
+    seen_requests: VecDeque<(u64, u64)>,
+    /// Whether the bridge has toggled pane frames an odd number of times
+    pane_frames_toggled: bool,
+    /// Named sets of panes that can be operated on as one unit
+    groups: BTreeMap<String, Vec<u32>>,
+    /// Exact-size resizes still converging, advanced one step per PaneUpdate
+    pending_resizes: HashMap<u32, PendingResize>,
+    config: Config,
+    /// Settings from the layout, kept so runtime overrides can be reverted
+    base_configuration: BTreeMap<String, String>,
+    /// Settings changed at runtime via `set_config`
+    config_overrides: BTreeMap<String, String>,
+    /// Whether the configured autostart panes have been launched
+    autostarted: bool,
+    /// Whether `restore_on_load` still has to look at the session once it is known
+    restore_pending: bool,
+    /// Version string of the Zellij host, as reported by the plugin API or `zellij --version`
+    zellij_version: Option<String>,
+    /// Outcome of the last prompt for each permission the bridge asked for
+    permissions: BTreeMap<String, PermissionState>,
+    /// What the reply to the current pipe should wait for before it is sent
+    defer_reply: Option<Waiting>,
+    /// Things the current command went ahead despite, returned as the reply's `warnings`
+    warnings: Vec<String>,
+    /// When the pipe message being handled runs out of its `max_request_ms` budget
+    request_deadline: Option<Instant>,
+    /// CLI replies held until what they wait for arrives, in the order their requests came in
+    deferred_replies: Vec<DeferredReply>,
+    /// CLI pipes blocked on a held reply. Each is unblocked once, after its last
+    /// held reply is out; Zellij does not say when a pipe goes away, so the
+    /// reply deadlines are what let an abandoned one go.
+    blocked_pipes: BTreeSet<String>,
+    /// CLI pipe and envelope of the request being handled, when it may get progress lines
+    progress_target: Option<(String, Envelope)>,
+    /// Outcome of each capability probe started by the last `self_test`
+    probes: BTreeMap<&'static str, ProbeStatus>,
+    /// Remaining pages of oversized replies, by continuation token
+    pending_pages: HashMap<String, VecDeque<serde_json::Value>>,
+    pending_page_order: VecDeque<String>,
+    page_counter: u64,
+    loaded_at: Option<Instant>,
+    /// Per-command counters since load
+    stats: BTreeMap<String, CommandStats>,
+    /// How long each command line has taken in command panes, across reloads
+    timings: BTreeMap<String, CommandTiming>,
+    /// Mutating commands captured since `record_start`
+    recording: Option<Vec<RecordedStep>>,
+    /// Finished recordings by name, for `replay`
+    recordings: BTreeMap<String, Vec<RecordedStep>>,
+    /// Workspace templates by name
+    templates: BTreeMap<String, Template>,
+    /// Single-tab templates by name, for `new_tab_from_template`
+    tab_templates: BTreeMap<String, TabTemplate>,
+    /// Name of the tab that was active before the current one, for `goto_previous_tab`
+    previous_tab: Option<String>,
+    /// Saved layouts by name, mirrored to SNAPSHOTS_PATH
+    snapshots: BTreeMap<String, Snapshot>,
+    /// Last state written to STATE_PATH, so unchanged state is not rewritten
+    persisted: String,
+    /// Whether state restored at load still has to be checked against the first manifest
+    reconcile_pending: bool,
+    /// What was restored and dropped at load, reported by the first `session_info`
+    recovery: Option<serde_json::Value>,
+    /// When the next periodic checkpoint is due, while `checkpoint_secs` is set
+    next_checkpoint: Option<Instant>,
+    /// Commands refused since load because they targeted a protected pane, by command
+    protection_violations: BTreeMap<String, u64>,
+    /// The most recent of those refusals, oldest first
+    protection_log: VecDeque<ProtectionViolation>,
+    /// Inverses of the latest reversible commands, most recent last
+    undo_stack: VecDeque<UndoStep>,
+    /// Writes held back to keep `write_gap_ms` between writes to the same pane
+    write_queues: HashMap<u32, VecDeque<QueuedWrite>>,
+    /// When each pane was last written to
+    last_write: HashMap<u32, Instant>,
+    /// Bumped on every command result change, see `CommandResult::updated`
+    result_counter: u64,
+    /// Terminal pane id to its tab and position in that tab's pane list
+    pane_index: HashMap<u32, (usize, usize)>,
+    /// Exact title to the terminal panes carrying it
+    title_index: HashMap<String, Vec<u32>>,
+    /// Serialized `PaneSummary` of every pane, per tab, rebuilt only for tabs that changed
+    summary_cache: HashMap<usize, Vec<serde_json::Value>>,
+    /// Tabs whose cached summaries are out of date, refreshed after a burst of updates settles
+    stale_summaries: BTreeSet<usize>,
+    /// When the last manifest that changed anything arrived
+    last_manifest_change: Option<Instant>,
+    /// When recent host-reaching commands were accepted, for `max_commands_per_minute`
+    recent_commands: VecDeque<Instant>,
+    /// Replies of forwarded commands by forward id, `None` until they arrive
+    forwards: HashMap<u64, Option<serde_json::Value>>,
+    forward_counter: u64,
+    /// Responses of `fetch` requests by fetch id, `None` until they arrive
+    fetches: HashMap<u64, Option<serde_json::Value>>,
+    fetch_counter: u64,
+    /// Host folder chosen with `change_host_dir`, which relative cwds resolve against
+    host_dir: Option<String>,
+    /// Output files of `run_captured` commands by capture id, which is not a job id
+    captured_jobs: BTreeMap<u64, CapturedJob>,
+    capture_counter: u64,
+    /// Runs held back by `max_running`, by job id
+    jobs: BTreeMap<u64, Job>,
+    job_counter: u64,
+    /// Handles of runs whose pane has not opened yet, with when they were started
+    opening: HashMap<String, Instant>,
+    /// When `panic` last ran; runs started before it are closed as their panes open
+    panicked_at: Option<Instant>,
+    /// Command panes wearing a status badge, with their title minus the badge once known
+    badge_titles: HashMap<u32, Option<String>>,
+    /// Highlighted panes, with their title before the highlight and when it ends
+    highlights: HashMap<u32, (String, Instant)>,
+    /// Recent changes for `poll_events`, oldest first, at most `EVENT_LOG_CAPACITY`
+    events: VecDeque<BridgeEvent>,
+    /// Cursor the next logged event gets
+    event_cursor: u64,
 }
 
-#[derive(Clone, Serialize)]
-struct CommandResult {
-    pane_id: u32,
-    exit_code: Option<i32>,
-    exited: bool,
+/// Which events `await_event` is waiting for; every part that is set must match
+#[derive(Clone)]
+struct EventFilter {
+    types: Vec<String>,
+    pane_id: Option<u32>,
+    /// Lowercased substring of the event's pane or tab title
+    title_pattern: Option<String>,
 }
 
-#[derive(Deserialize)]
-#[serde(tag = "cmd")]
-enum Command {
-    // === WRITE ===
-    #[serde(rename = "write")]
-    Write { pane_id: u32, chars: String },
+impl EventFilter {
+    fn matches(&self, event: &BridgeEvent) -> bool {
+        (self.types.is_empty() || self.types.iter().any(|t| t == event.kind))
+            && self.pane_id.is_none_or(|id| event.pane_id == Some(id))
+            && self.title_pattern.as_ref().is_none_or(|pattern| {
+                event.title.as_ref().is_some_and(|t| t.to_lowercase().contains(pattern))
+            })
+    }
+}
 
-    #[serde(rename = "write_bytes")]
-    WriteBytes { pane_id: u32, bytes: Vec<u8> },
+/// A floating pane coordinate: a number of cells or a percentage like "50%"
+#[derive(Clone, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+enum Dimension {
+    Cells(usize),
+    Text(String),
+}
 
-    // === READ ===
-    #[serde(rename = "list")]
-    List,
+impl std::fmt::Display for Dimension {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dimension::Cells(cells) => write!(f, "{}", cells),
+            Dimension::Text(text) => f.write_str(text),
+        }
+    }
+}
 
-    #[serde(rename = "query")]
-    Query { name: String },
+/// A command refused because it targeted a protected pane
+#[derive(Clone, Serialize)]
+struct ProtectionViolation {
+    ts_ms: u64,
+    command: String,
+    pane_id: Option<u32>,
+    /// The payload as it was sent
+    params: serde_json::Value,
+    error: Option<String>,
+}
 
-    #[serde(rename = "list_tabs")]
-    ListTabs,
+/// Handles of closed panes remembered before the oldest are forgotten
+const CLOSED_HANDLES_CAPACITY: usize = 256;
 
-    // === PANE CONTROL ===
-    #[serde(rename = "focus")]
-    Focus { pane_id: u32 },
+/// Refusals kept for `protection_report` before the oldest are dropped
+const PROTECTION_LOG_CAPACITY: usize = 100;
 
-    #[serde(rename = "close")]
-    Close {
-        pane_id: u32,
-        #[serde(default)]
-        force: bool,
-    },
+/// A command that reverses an earlier one, kept for `undo`
+#[derive(Clone)]
+struct UndoStep {
+    /// Name of the command it reverses
+    undoes: String,
+    inverse: Command,
+}
 
-    #[serde(rename = "hide")]
-    Hide {
-        pane_id: u32,
-        #[serde(default)]
-        force: bool,
-    },
+/// Reversible commands remembered for `undo` before the oldest are dropped
+const UNDO_CAPACITY: usize = 50;
 
-    #[serde(rename = "show")]
-    Show { pane_id: u32 },
+/// A command started by `run_captured`
+#[derive(Clone, Serialize, Deserialize)]
+struct CapturedJob {
+    command: String,
+    /// Output file as the plugin sees it, under /host
+    output: String,
+}
 
-    #[serde(rename = "clear")]
-    Clear { pane_id: u32 },
+/// A `run` that waited in the queue for `max_running` to leave room
+#[derive(Clone, Serialize, Deserialize)]
+struct Job {
+    /// The `run` to start once a slot frees
+    run: Command,
+    state: JobState,
+    /// Queued jobs with a higher priority start first
+    #[serde(default)]
+    priority: i32,
+    /// Stay queued while a job of higher priority is queued or running
+    #[serde(default)]
+    defer: bool,
+    /// Free-form tag the job was given, for finding it again after a restart
+    #[serde(default)]
+    label: Option<String>,
+    /// Directory made for the job's outputs under `artifacts_root`, as the command sees it
+    #[serde(default)]
+    artifacts: Option<String>,
+    submitted_ms: u64,
+    #[serde(default)]
+    started_ms: Option<u64>,
+    #[serde(default)]
+    finished_ms: Option<u64>,
+    /// Handle the job's pane opens with; the pane id follows once it has opened
+    #[serde(default)]
+    handle: Option<String>,
+    #[serde(default)]
+    pane_id: Option<u32>,
+    #[serde(default)]
+    exit_code: Option<i32>,
+    /// Why the job failed without an exit code
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    error_code: Option<ErrorCode>,
+}
 
-    #[serde(rename = "fullscreen")]
-    Fullscreen { pane_id: u32 },
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
 
-    #[serde(rename = "rename_pane")]
-    RenamePane { pane_id: u32, name: String },
+impl Job {
+    /// Program and arguments of the job's `run`
+    fn command(&self) -> (&str, &[String]) {
+        match &self.run {
+            Command::Run { command, args, .. } => (command, args),
+            _ => ("", &[]),
+        }
+    }
 
-    #[serde(rename = "move")]
-    Move { pane_id: u32, direction: String },
+    /// Whether the job is over, including a cancelled one whose pane has exited
+    fn finished(&self) -> bool {
+        match self.state {
+            JobState::Queued | JobState::Running => false,
+            JobState::Done | JobState::Failed => true,
+            JobState::Cancelled => self.finished_ms.is_some(),
+        }
+    }
 
-    #[serde(rename = "resize")]
-    Resize { pane_id: u32, direction: String },
+    fn to_json(&self, job_id: u64) -> serde_json::Value {
+        let (command, args) = self.command();
+        serde_json::json!({
+            "job_id": job_id,
+            "state": self.state,
+            "command": command,
+            "args": args,
+            "priority": self.priority,
+            "defer": self.defer,
+            "label": self.label,
+            "artifacts": self.artifacts,
+            "submitted_ms": self.submitted_ms,
+            "started_ms": self.started_ms,
+            "finished_ms": self.finished_ms,
+            "duration_ms": self.started_ms.zip(self.finished_ms).map(|(s, f)| f.saturating_sub(s)),
+            "pane_id": self.pane_id,
+            "handle": self.handle,
+            "exit_code": self.exit_code,
+            "error": self.error,
+            "error_code": self.error_code,
+        })
+    }
+}
 
-    #[serde(rename = "toggle_floating")]
-    TogglePaneFloating { pane_id: u32 },
+/// Finished jobs kept before the oldest are dropped
+const FINISHED_JOBS_CAPACITY: usize = 200;
 
-    // === COMMAND EXECUTION ===
-    #[serde(rename = "run")]
-    Run {
-        command: String,
-        #[serde(default)]
-        args: Vec<String>,
-        #[serde(default)]
-        cwd: Option<String>,
-        #[serde(default)]
-        floating: bool,
-    },
+/// How long a started run counts towards `max_running` before its pane opens
+const OPENING_GRACE: Duration = Duration::from_secs(10);
 
-    #[serde(rename = "rerun")]
-    Rerun { pane_id: u32 },
+/// Put in front of a pane's title by `highlight`
+const HIGHLIGHT_MARKER: &str = "👉 ";
 
-    #[serde(rename = "command_status")]
-    CommandStatus { pane_id: u32 },
+/// How long `highlight` lasts when the request does not say
+const HIGHLIGHT_DEFAULT_SECS: f64 = 5.0;
 
-    // === TAB OPERATIONS ===
-    #[serde(rename = "new_tab")]
-    NewTab {
-        #[serde(default)]
-        name: Option<String>,
-        #[serde(default)]
-        cwd: Option<String>,
-    },
+/// Folder of the host folder that `run_captured` output goes to
+const CAPTURE_DIR: &str = ".zellij-pane-bridge/jobs";
 
-    #[serde(rename = "close_tab")]
-    CloseTab {
-        index: u32,
-        #[serde(default)]
-        force: bool,
-    },
+/// Runs `$2 $3...` with stdout and stderr teed into `$1`, then writes the exit
+/// code to `$1.status` and exits with it
+const CAPTURE_WRAPPER: &str = r#"out="$1"; shift; mkdir -p "$(dirname "$out")"; { "$@"; echo $? > "$out.status"; } 2>&1 | tee "$out"; exit "$(cat "$out.status")""#;
 
-    #[serde(rename = "focus_tab")]
-    FocusTab {
-        name: String,
-        #[serde(default)]
-        create: bool,
-    },
+/// Runs `$2 $3...` with `$1` created and exported as ZELLIJ_PANE_BRIDGE_ARTIFACTS
+const ARTIFACTS_WRAPPER: &str = r#"mkdir -p "$1" && export ZELLIJ_PANE_BRIDGE_ARTIFACTS="$1" && shift && exec "$@""#;
 
-    #[serde(rename = "goto_tab")]
-    GotoTab { index: u32 },
+/// Replaced by a job's artifacts directory in the arguments of its command
+const ARTIFACTS_PLACEHOLDER: &str = "{{artifacts}}";
 
-    // === SESSION ===
-    #[serde(rename = "session_info")]
-    SessionInfo,
+/// The `run` of a job made to go through ARTIFACTS_WRAPPER, with
+/// ARTIFACTS_PLACEHOLDER in its arguments replaced by `dir`
+fn with_artifacts(run: Command, dir: &str) -> Command {
+    match run {
+        Command::Run { command, args, cwd, floating, background, x, y, width, height, queue, label } => {
+            let mut wrapped = vec!["-c".to_string(), ARTIFACTS_WRAPPER.to_string(), "sh".to_string(), dir.to_string(), command];
+            wrapped.extend(args.iter().map(|a| a.replace(ARTIFACTS_PLACEHOLDER, dir)));
+            Command::Run {
+                command: "sh".to_string(),
+                args: wrapped,
+                cwd,
+                floating,
+                background,
+                x,
+                y,
+                width,
+                height,
+                queue,
+                label,
+            }
+        }
+        other => other,
+    }
+}
 
-    #[serde(rename = "detach")]
-    Detach,
+/// One entry of the change feed served by `poll_events`
+#[derive(Clone, Default, Serialize)]
+struct BridgeEvent {
+    cursor: u64,
+    ts_ms: u64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pane_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tab: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+}
 
-    // === PROTECTION ===
-    #[serde(rename = "protect")]
-    Protect { pane_id: u32 },
+#[derive(Clone, Serialize, Deserialize)]
+enum QueuedWrite {
+    Chars(String),
+    Bytes(Vec<u8>),
+}
 
-    #[serde(rename = "get_protected")]
-    GetProtected,
+/// One command captured by the workflow recorder
+#[derive(Clone, Serialize, Deserialize, schemars::JsonSchema)]
+struct RecordedStep {
+    /// The command as it was parsed, `cmd` tag included
+    command: serde_json::Value,
+    /// Titles of the panes it targeted, used to find them again on replay
+    pane_names: BTreeMap<u32, String>,
 }
 
-#[derive(Serialize)]
-struct Response {
-    success: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    error: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    data: Option<serde_json::Value>,
+/// Bridge bookkeeping carried between sessions by `export_state` and `import_state`
+#[derive(Clone, Default, Serialize, Deserialize, schemars::JsonSchema)]
+struct ExportedState {
+    #[serde(default)]
+    protocol_version: u32,
+    #[serde(default)]
+    config_overrides: BTreeMap<String, String>,
+    #[serde(default)]
+    aliases: BTreeMap<String, String>,
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<u32>>,
+    #[serde(default)]
+    recordings: BTreeMap<String, Vec<RecordedStep>>,
+    #[serde(default)]
+    templates: BTreeMap<String, Template>,
+    #[serde(default)]
+    tab_templates: BTreeMap<String, TabTemplate>,
+    /// Titles of the grouped panes, since pane ids do not survive a move
+    #[serde(default)]
+    pane_names: BTreeMap<u32, String>,
 }
 
-#[derive(Serialize)]
-struct PaneSummary {
-    id: u32,
-    is_plugin: bool,
-    title: String,
-    is_focused: bool,
-    is_floating: bool,
-    is_fullscreen: bool,
-    is_suppressed: bool,
-    tab_index: usize,
-    command: Option<String>,
-    exit_status: Option<i32>,
-    exited: bool,
-    rows: usize,
-    cols: usize,
+/// Bridge state mirrored to STATE_PATH so a reloaded plugin picks up where it left off
+#[derive(Default, Serialize, Deserialize)]
+struct PersistedState {
+    /// Zellij server the pane ids below belong to
+    #[serde(default)]
+    zellij_pid: u32,
+    #[serde(default)]
+    config_overrides: BTreeMap<String, String>,
+    #[serde(default)]
+    recordings: BTreeMap<String, Vec<RecordedStep>>,
+    #[serde(default)]
+    templates: BTreeMap<String, Template>,
+    #[serde(default)]
+    tab_templates: BTreeMap<String, TabTemplate>,
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<u32>>,
+    #[serde(default)]
+    protected_pane_id: Option<u32>,
+    #[serde(default)]
+    owned_panes: BTreeSet<u32>,
+    #[serde(default)]
+    granted_panes: BTreeSet<u32>,
+    #[serde(default)]
+    pane_launches: BTreeMap<u32, Launch>,
+    #[serde(default)]
+    pane_handles: BTreeMap<u32, String>,
+    #[serde(default)]
+    timings: BTreeMap<String, CommandTiming>,
 }
 
-#[derive(Serialize)]
-struct TabSummary {
-    index: usize,
-    name: String,
-    active: bool,
-    is_fullscreen: bool,
-    is_sync: bool,
+/// Work in flight, written to CHECKPOINT_PATH every `checkpoint_secs` so a
+/// crash loses at most that much of it
+#[derive(Default, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Zellij server the pane ids below belong to
+    #[serde(default)]
+    zellij_pid: u32,
+    #[serde(default)]
+    ts_ms: u64,
+    #[serde(default)]
+    command_results: BTreeMap<u32, CommandResult>,
+    #[serde(default)]
+    result_counter: u64,
+    #[serde(default)]
+    captured_jobs: BTreeMap<u64, CapturedJob>,
+    #[serde(default)]
+    capture_counter: u64,
+    #[serde(default)]
+    jobs: BTreeMap<u64, Job>,
+    #[serde(default)]
+    job_counter: u64,
+    #[serde(default)]
+    write_queues: BTreeMap<u32, VecDeque<QueuedWrite>>,
+    /// Where `poll_events` cursors continue, so clients' cursors stay valid
+    #[serde(default)]
+    event_cursor: u64,
 }
 
-register_plugin!(State);
+/// Payload fields that carry a single pane id
+const PANE_ID_FIELDS: &[&str] = &["pane_id", "pane_a", "pane_b"];
 
-impl ZellijPlugin for State {
-    fn load(&mut self, _configuration: BTreeMap<String, String>) {
-        request_permission(&[
-            PermissionType::ReadApplicationState,
-            PermissionType::WriteToStdin,
-            PermissionType::ChangeApplicationState,
-            PermissionType::ReadCliPipes,
-            PermissionType::OpenTerminalsOrPlugins,
-            PermissionType::RunCommands,
-        ]);
+#[derive(Clone, Default, Serialize)]
+struct CommandStats {
+    count: u64,
+    failures: u64,
+    /// Requests that ran past `max_request_ms`
+    over_budget: u64,
+    #[serde(skip)]
+    total_micros: u128,
+}
 
-        subscribe(&[
-            EventType::PaneUpdate,
-            EventType::TabUpdate,
-            EventType::CommandPaneOpened,
-            EventType::CommandPaneExited,
-        ]);
+impl CommandStats {
+    fn to_json(&self) -> serde_json::Value {
+        let avg_ms = if self.count == 0 { 0.0 } else { self.total_micros as f64 / self.count as f64 / 1000.0 };
+        serde_json::json!({
+            "count": self.count,
+            "failures": self.failures,
+            "over_budget": self.over_budget,
+            "error_rate": if self.count == 0 { 0.0 } else { self.failures as f64 / self.count as f64 },
+            "avg_ms": avg_ms,
+        })
     }
+}
 
-    fn update(&mut self, event: Event) -> bool {
-        match event {
-            Event::PaneUpdate(pane_manifest) => {
-                self.panes = pane_manifest.panes;
-            }
-            Event::TabUpdate(tabs) => {
-                self.tabs = tabs;
-            }
-            Event::CommandPaneOpened(pane_id, _context) => {
-                self.command_results.insert(pane_id, CommandResult {
-                    pane_id,
-                    exit_code: None,
-                    exited: false,
-                });
-            }
-            Event::CommandPaneExited(pane_id, exit_code, _context) => {
-                self.command_results.insert(pane_id, CommandResult {
-                    pane_id,
-                    exit_code,
-                    exited: true,
-                });
-            }
-            _ => {}
-        }
-        false
+/// Command lines kept in `timings` before the least recently run are dropped
+const TIMINGS_CAPACITY: usize = 200;
+
+/// Run times of one command line in command panes
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct CommandTiming {
+    runs: u64,
+    /// Runs that exited with status 0
+    successes: u64,
+    total_ms: u64,
+    min_ms: u64,
+    max_ms: u64,
+    /// When the last run ended, for evicting the stalest lines first
+    last_ms: u64,
+}
+
+impl CommandTiming {
+    fn add(&mut self, duration_ms: u64, success: bool, ended_ms: u64) {
+        self.min_ms = if self.runs == 0 { duration_ms } else { self.min_ms.min(duration_ms) };
+        self.max_ms = self.max_ms.max(duration_ms);
+        self.runs += 1;
+        self.successes += u64::from(success);
+        self.total_ms += duration_ms;
+        self.last_ms = ended_ms;
     }
 
-    fn pipe(&mut self, pipe_message: PipeMessage) -> bool {
-        // On first command, detect and protect the focused pane (where Claude is running)
-        if self.protected_pane_id.is_none() {
-            self.detect_protected_pane();
-        }
+    fn to_json(&self, command: &str) -> serde_json::Value {
+        serde_json::json!({
+            "command": command,
+            "runs": self.runs,
+            "success_rate": if self.runs == 0 { 0.0 } else { self.successes as f64 / self.runs as f64 },
+            "min_ms": self.min_ms,
+            "avg_ms": self.total_ms.checked_div(self.runs).unwrap_or(0),
+            "max_ms": self.max_ms,
+        })
+    }
+}
 
-        let pipe_name = pipe_message.name.clone();
-        let payload = pipe_message.payload.clone().unwrap_or_default();
-        let response = self.handle_command(&pipe_name, &payload);
+/// Every `type` a logged event can have
+const EVENT_TYPES: &[&str] = &[
+    "pane_opened",
+    "pane_closed",
+    "pane_exited",
+    "pane_title_changed",
+    "tab_created",
+    "tab_closed",
+    "tab_activated",
+    "protection_violation",
+];
 
-        if let PipeSource::Cli(pipe_id) = pipe_message.source {
-            let response_json = serde_json::to_string(&response)
-                .unwrap_or_else(|e| format!(r#"{{"success":false,"error":"{}"}}"#, e));
-            cli_pipe_output(&pipe_id, &response_json);
-            unblock_cli_pipe_input(&pipe_id);
+/// Events kept for `poll_events` before the oldest are dropped
+const EVENT_LOG_CAPACITY: usize = 1000;
+
+/// Paginated results kept for `next_page` before the oldest are dropped
+const MAX_PENDING_PAGINATIONS: usize = 16;
+
+/// How long a mutation reply waits for a PaneUpdate before answering with what it has
+const STATE_WAIT_SECS: f64 = 0.5;
+
+/// Quiet time after a PaneUpdate before cached summaries are rebuilt, so a
+/// burst of updates (e.g. applying a layout) costs one rebuild
+const SUMMARY_DEBOUNCE_SECS: f64 = 0.05;
+
+/// How long `self_test` waits for its asynchronous probes
+const SELF_TEST_WAIT_SECS: f64 = 2.0;
+
+/// Context key marking host calls made by `self_test`
+const PROBE_CONTEXT_KEY: &str = "bridge_probe";
+
+/// What a deferred CLI reply is waiting on
+#[derive(Clone)]
+enum Waiting {
+    /// The refreshed state of a pane after a mutation
+    PaneState(u32),
+    /// The probes started by `self_test`
+    SelfTest,
+    /// Every bridge-owned command pane to exit; `panes` were running when it started
+    Quiesce { panes: Vec<u32>, timeout_secs: f64 },
+    /// The reply of another session's bridge to a forwarded command
+    Forward { id: u64, timeout_secs: f64 },
+    /// The response to a `fetch` web request
+    Fetch { id: u64, timeout_secs: f64 },
+    /// A terminal pane whose title or command contains `pattern` (lowercased)
+    PaneAppears { pattern: String, timeout_secs: f64 },
+    /// A pane's title moving away from `from`, to one containing `pattern`
+    /// (lowercased) when given, or the pane going away
+    TitleChange { pane_id: u32, from: String, pattern: Option<String>, timeout_secs: f64 },
+    /// The first logged event from `since_cursor` on that passes `filter`
+    Event { filter: EventFilter, since_cursor: u64, timeout_secs: f64 },
+    /// An interrupted pane to exit, closed by force once `timeout_secs` runs out
+    Shutdown { pane_id: u32, timeout_secs: f64 },
+    /// A pane whose `fingerprint` has held since `since` for `quiet_secs`
+    Quiet { pane_id: u32, quiet_secs: f64, fingerprint: serde_json::Value, since: Instant, timeout_secs: f64 },
+    /// A pane's title to match a prompt pattern, or the pane going away
+    Prompt { pane_id: u32, timeout_secs: f64 },
+    /// Every job with this label to finish
+    Jobs { label: String, timeout_secs: f64 },
+    /// Nothing: the reply is final but queued behind an older one on the same pipe
+    Answered,
+}
+
+impl Waiting {
+    fn timeout_secs(&self) -> f64 {
+        match *self {
+            Waiting::PaneState(_) => STATE_WAIT_SECS,
+            Waiting::SelfTest => SELF_TEST_WAIT_SECS,
+            Waiting::Answered => 0.0,
+            Waiting::Quiesce { timeout_secs, .. }
+            | Waiting::Forward { timeout_secs, .. }
+            | Waiting::Fetch { timeout_secs, .. }
+            | Waiting::PaneAppears { timeout_secs, .. }
+            | Waiting::TitleChange { timeout_secs, .. }
+            | Waiting::Event { timeout_secs, .. }
+            | Waiting::Shutdown { timeout_secs, .. }
+            | Waiting::Quiet { timeout_secs, .. }
+            | Waiting::Prompt { timeout_secs, .. }
+            | Waiting::Jobs { timeout_secs, .. } => timeout_secs,
         }
-        false
     }
 
-    fn render(&mut self, _rows: usize, _cols: usize) {
-        println!("Zellij Pane Bridge - Agentic Companion v0.3.0");
-        if let Some(pid) = self.protected_pane_id {
-            println!("Protected pane: {}", pid);
+    /// What the reply is held for, as shown in the plugin pane
+    fn describe(&self) -> String {
+        match self {
+            Waiting::PaneState(pane_id) => format!("state of pane {}", pane_id),
+            Waiting::SelfTest => "self-test probes".to_string(),
+            Waiting::Quiesce { .. } => "command panes to exit".to_string(),
+            Waiting::Forward { id, .. } => format!("remote bridge (forward {})", id),
+            Waiting::Fetch { id, .. } => format!("web response (fetch {})", id),
+            Waiting::PaneAppears { pattern, .. } => format!("a pane matching '{}'", pattern),
+            Waiting::TitleChange { pane_id, .. } => format!("title change of pane {}", pane_id),
+            Waiting::Event { filter, .. } if filter.types.is_empty() => "any event".to_string(),
+            Waiting::Event { filter, .. } => format!("event {}", filter.types.join("|")),
+            Waiting::Shutdown { pane_id, .. } => format!("pane {} to exit", pane_id),
+            Waiting::Quiet { pane_id, .. } => format!("pane {} to go quiet", pane_id),
+            Waiting::Prompt { pane_id, .. } => format!("pane {} to reach its prompt", pane_id),
+            Waiting::Jobs { label, .. } => format!("jobs labelled '{}'", label),
+            Waiting::Answered => "an earlier reply on its pipe".to_string(),
         }
     }
 }
 
-impl State {
-    fn handle_command(&mut self, name: &str, payload: &str) -> Response {
-        // Inject the pipe name as the "cmd" field for serde deserialization
-        let json_with_cmd = if payload.trim().is_empty() || payload == "{}" {
-            format!(r#"{{"cmd":"{}"}}"#, name)
-        } else {
-            // Parse payload, inject cmd field, re-serialize
-            match serde_json::from_str::<serde_json::Value>(payload) {
-                Ok(mut v) => {
-                    if let Some(obj) = v.as_object_mut() {
-                        obj.insert("cmd".to_string(), serde_json::Value::String(name.to_string()));
-                    }
-                    serde_json::to_string(&v).unwrap_or_else(|_| payload.to_string())
-                }
-                Err(_) => format!(r#"{{"cmd":"{}"}}"#, name),
-            }
-        };
+/// How long `wait_for_pane`, `wait_for_title_change`, `wait_for_quiet`, `wait_for_prompt`
+/// and `await_event` wait when the request does not say
+const WAIT_FOR_PANE_DEFAULT_TIMEOUT_SECS: f64 = 30.0;
 
-        let cmd_result: Result<Command, serde_json::Error> = serde_json::from_str(&json_with_cmd);
+/// Longest a held reply may wait or a highlight last, so their deadlines
+/// always fit in an `Instant`
+const MAX_WAIT_SECS: f64 = 86_400.0;
 
-        match cmd_result {
-            Ok(cmd) => self.execute_command(cmd),
-            Err(e) => Response {
-                success: false,
-                error: Some(format!("Invalid command '{}': {} (payload: {})", name, e, json_with_cmd)),
-                data: None,
-            },
-        }
+/// How long a pane must stay unchanged for `wait_for_quiet` when the request does not say
+const WAIT_FOR_QUIET_DEFAULT_SECS: f64 = 2.0;
+
+/// How long `shutdown` waits for an interrupted pane to exit when the request does not say
+const SHUTDOWN_DEFAULT_GRACE_SECS: f64 = 10.0;
+
+/// How long a forwarded command may take before the relay gives up
+const FORWARD_DEFAULT_TIMEOUT_SECS: f64 = 10.0;
+
+/// Context key carrying the id of a forwarded command
+const FORWARD_CONTEXT_KEY: &str = "bridge_forward";
+
+/// Context key marking the `zellij --version` run used when the plugin API gives no version
+const HOST_VERSION_CONTEXT_KEY: &str = "bridge_host_version";
+
+/// How long `fetch` waits for a response when the request does not say
+const FETCH_DEFAULT_TIMEOUT_SECS: f64 = 10.0;
+
+/// Context key carrying the id of a `fetch` request
+const FETCH_CONTEXT_KEY: &str = "bridge_fetch";
+
+/// Context key carrying the `Launch` of a command pane, as JSON
+const LAUNCH_CONTEXT_KEY: &str = "bridge_launch";
+
+/// Context key carrying the handle a command pane is to be known by
+const HANDLE_CONTEXT_KEY: &str = "bridge_handle";
+
+/// The command, arguments and folder a command pane was opened with
+#[derive(Clone, Serialize, Deserialize)]
+struct Launch {
+    command: String,
+    args: Vec<String>,
+    cwd: Option<String>,
+    /// Variables set for the command, from `rerun_with`
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+impl Launch {
+    fn context(command: &str, args: &[String], cwd: Option<&str>) -> (String, String) {
+        let launch = Launch { command: command.to_string(), args: args.to_vec(), cwd: cwd.map(String::from), env: BTreeMap::new() };
+        launch.context_entry()
     }
 
-    fn detect_protected_pane(&mut self) {
-        // Find the currently focused terminal pane - this is where Claude is running
-        for pane_list in self.panes.values() {
-            for p in pane_list {
-                if p.is_focused && !p.is_plugin {
-                    self.protected_pane_id = Some(p.id);
-                    return;
-                }
-            }
-        }
+    fn context_entry(&self) -> (String, String) {
+        (LAUNCH_CONTEXT_KEY.to_string(), serde_json::to_string(self).unwrap_or_default())
     }
 
-    fn is_protected_pane(&self, pane_id: u32) -> bool {
-        // Check if this is the protected pane (Claude's pane)
-        if self.protected_pane_id == Some(pane_id) {
-            return true;
-        }
+    /// The command and its arguments as one line
+    fn line(&self) -> String {
+        std::iter::once(&self.command).chain(&self.args).cloned().collect::<Vec<_>>().join(" ")
+    }
 
-        // Also check for panes with "claude" in name/command as fallback
-        for pane_list in self.panes.values() {
-            for p in pane_list {
-                if p.id == pane_id {
-                    let title_lower = p.title.to_lowercase();
-                    return title_lower.contains("claude") ||
-                           title_lower.contains("anthropic") ||
-                           p.terminal_command.as_ref()
-                               .map(|c| c.to_lowercase().contains("claude"))
-                               .unwrap_or(false);
-                }
-            }
+    /// Program and arguments to run, going through `env` when variables are set
+    fn command_line(&self) -> (String, Vec<String>) {
+        if self.env.is_empty() {
+            return (self.command.clone(), self.args.clone());
         }
-        false
+        let mut args: Vec<String> = self.env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        args.push(self.command.clone());
+        args.extend(self.args.iter().cloned());
+        ("env".to_string(), args)
     }
+}
 
-    fn execute_command(&mut self, cmd: Command) -> Response {
-        match cmd {
-            // === WRITE ===
-            Command::Write { pane_id, chars } => {
-                write_chars_to_pane_id(&chars, PaneId::Terminal(pane_id));
-                Response {
-                    success: true,
+/// How long `quiesce` waits when the request does not say
+const QUIESCE_DEFAULT_TIMEOUT_SECS: f64 = 300.0;
+
+#[derive(Clone)]
+struct DeferredReply {
+    pipe_id: String,
+    /// Pipe name the request came in on
+    command: String,
+    received: Instant,
+    envelope: Envelope,
+    response: Response,
+    warnings: Vec<String>,
+    waiting: Waiting,
+    deadline: Instant,
+    /// Whether progress lines go out ahead of the reply
+    progress: bool,
+    /// The last progress line sent, so an unchanged one is not repeated
+    last_progress: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ProbeStatus {
+    /// The host call was made and confirmed
+    Ok,
+    Failed,
+    /// Started but not confirmed yet
+    Pending,
+    /// Not confirmed before the self-test deadline
+    Timeout,
+    /// The permission was denied, so the call was not attempted
+    Denied,
+    /// No harmless call exists to exercise this capability
+    Untested,
+}
+
+/// Permissions the bridge asks for at load, for its core command set
+const REQUIRED_PERMISSIONS: &[PermissionType] = &[
+    PermissionType::ReadApplicationState,
+    PermissionType::WriteToStdin,
+    PermissionType::ChangeApplicationState,
+    PermissionType::ReadCliPipes,
+    PermissionType::OpenTerminalsOrPlugins,
+    PermissionType::RunCommands,
+];
+
+/// Permissions asked for the first time a command needs them (`fetch`,
+/// `change_host_dir`), so users who never use those are not prompted for them
+const ON_DEMAND_PERMISSIONS: &[PermissionType] = &[
+    PermissionType::WebAccess,
+    PermissionType::FullHdAccess,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PermissionState {
+    Pending,
+    Granted,
+    Denied,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    Quiet,
+    Normal,
+    Debug,
+}
+
+/// How much the plugin pane shows
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RenderMode {
+    /// Every status line, the busiest commands included
+    Full,
+    /// A single status line
+    Compact,
+}
+
+/// Settings read from the plugin's KDL configuration block
+#[derive(Clone, Serialize)]
+struct Config {
+    /// Title/command substrings that mark a pane as protected
+    protection_keywords: Vec<String>,
+    /// Working directory for `run` and `new_tab` when none is given
+    default_cwd: Option<String>,
+    /// Folder that gets a directory per job for its outputs, relative to the host folder unless absolute
+    artifacts_root: Option<String>,
+    /// How much the bridge logs to the Zellij log
+    verbosity: LogLevel,
+    /// Open command panes in the background unless `floating` is requested
+    background: bool,
+    /// Refuse new command panes once the session holds this many terminal panes
+    max_panes: Option<usize>,
+    /// Refuse or queue `run` while this many bridge-owned commands are running (0 disables)
+    max_running: usize,
+    /// Drop command results for panes that no longer exist
+    auto_prune: bool,
+    /// Terminal pane that receives a line whenever a command pane exits
+    notify_pane: Option<u32>,
+    /// Command panes launched once permissions are granted after load
+    autostart: Vec<AutostartEntry>,
+    /// Reject unknown payload fields unless a request sets `strict: false`
+    strict: bool,
+    /// Alternative command names mapped to the commands they stand for
+    aliases: BTreeMap<String, String>,
+    /// Reply detail for requests that do not set `verbosity` themselves
+    response_verbosity: Verbosity,
+    /// Replies whose data serializes larger than this are paginated (0 disables)
+    max_reply_bytes: usize,
+    /// Write a JSON-lines trace of events, commands and host calls to the data directory
+    trace: bool,
+    /// Size at which the trace file is rotated
+    trace_max_bytes: u64,
+    /// Reject every command that would change the session
+    read_only: bool,
+    /// Maintenance mode: keep tracking panes but refuse to start new work
+    drain: bool,
+    /// How long draining replies tell clients to wait before retrying
+    drain_retry_after_secs: u64,
+    /// Minimum time between two writes to the same pane (0 writes immediately)
+    write_gap_ms: u64,
+    /// Command results kept before the stalest are evicted (0 keeps all)
+    max_command_results: usize,
+    /// Commands that reach the host allowed under the current policy (empty allows all)
+    allowed_commands: Vec<String>,
+    /// Commands that reach the host accepted per minute (0 disables the limit)
+    max_commands_per_minute: usize,
+    /// Plugin URL other sessions' bridges are reached at by `forward`
+    plugin_location: String,
+    /// Policy profile whose settings sit between the plugin configuration and overrides
+    profile: Option<String>,
+    /// Honour `force: true` on commands that would otherwise refuse a protected pane
+    allow_force: bool,
+    /// Only let commands change panes the bridge opened or that were granted
+    enforce_ownership: bool,
+    /// Reject requests without a fresh `nonce` or a recent, unseen `ts_ms`
+    replay_protection: bool,
+    /// How far `ts_ms` may be from the bridge's clock
+    replay_window_secs: u64,
+    /// Profiles defined in the configuration, alongside the built-in ones
+    profiles: BTreeMap<String, BTreeMap<String, String>>,
+    /// File every logged event is appended to as a JSON line, relative to the host folder
+    event_sink: Option<String>,
+    /// Response bodies of `fetch` are cut off past this size
+    max_fetch_bytes: usize,
+    /// Host folder paths the file commands may touch (empty allows the whole folder)
+    file_allowlist: Vec<String>,
+    /// Largest file `read_file` returns in one reply and `write_file` accepts
+    max_file_bytes: usize,
+    /// Prefix the titles of command panes the bridge opened with their state
+    status_badges: bool,
+    /// Snapshot recreated when the plugin loads into a fresh session: a name, or "latest"
+    restore_on_load: Option<String>,
+    /// Regexes for the titles a shell's title hook sets while it waits at its prompt
+    prompt_title_patterns: Vec<String>,
+    /// Seconds between checkpoints of jobs, queued writes and command results (0 disables)
+    checkpoint_secs: u64,
+    /// What the plugin pane shows
+    render: RenderMode,
+    /// Start the full view with the plugin's name and key hints
+    show_banner: bool,
+    /// Time one pipe message may take before long-running work stops early (0 disables)
+    max_request_ms: u64,
+    /// Replies held at once before new waiting requests are refused as overloaded (0 disables)
+    max_held_replies: usize,
+    /// Write progress lines to a CLI pipe while its reply is held, unless the request says otherwise
+    progress_updates: bool,
+}
+
+/// Built-in policy profiles, as the configuration settings each one implies
+const BUILTIN_PROFILES: &[(&str, &[(&str, &str)])] = &[
+    ("trusted", &[
+        ("read_only", "false"),
+        ("strict", "false"),
+        ("allow_force", "true"),
+        ("enforce_ownership", "false"),
+        ("allowed_commands", ""),
+        ("max_commands_per_minute", "0"),
+    ]),
+    ("restricted", &[
+        ("read_only", "false"),
+        ("strict", "true"),
+        ("allow_force", "false"),
+        ("enforce_ownership", "true"),
+        ("background", "true"),
+        ("max_panes", "12"),
+        ("max_commands_per_minute", "120"),
+        ("allowed_commands", "list,query,list_tabs,tab_status,session_info,write,write_bytes,run,rerun,\
+            focus,show,clear,rename_pane,resize,new_tab,focus_tab,goto_tab"),
+    ]),
+    ("observe-only", &[
+        ("read_only", "true"),
+    ]),
+];
+
+/// A workspace stored with `define_template` and created by `instantiate_template`.
+/// Strings may contain `{{var}}` placeholders filled from the instantiation vars.
+#[derive(Clone, Serialize, Deserialize, schemars::JsonSchema)]
+struct Template {
+    #[serde(default)]
+    tabs: Vec<TemplateTab>,
+    #[serde(default)]
+    runs: Vec<AutostartEntry>,
+}
+
+/// A layout saved with `save_snapshot`
+#[derive(Clone, Serialize, Deserialize)]
+struct Snapshot {
+    #[serde(default)]
+    description: Option<String>,
+    created_ms: u64,
+    /// Tabs and panes as they were, in the form of a `full` verbosity reply
+    layout: serde_json::Value,
+}
+
+/// Saved snapshots, in the plugin's data directory so they outlive the plugin
+const SNAPSHOTS_PATH: &str = "/data/snapshots.json";
+
+/// Bridge bookkeeping, in the plugin's data directory so it survives a plugin reload
+const STATE_PATH: &str = "/data/state.json";
+
+/// Periodic checkpoint of work in flight, see `Checkpoint`
+const CHECKPOINT_PATH: &str = "/data/checkpoint.json";
+
+/// Pane fields compared by `diff_snapshot`
+const SNAPSHOT_PANE_FIELDS: &[&str] = &[
+    "title", "tab_index", "command", "is_floating", "is_suppressed", "is_fullscreen", "exited", "exit_status", "rows", "cols",
+];
+
+#[derive(Clone, Serialize, Deserialize, schemars::JsonSchema)]
+struct TemplateTab {
+    name: String,
+    #[serde(default)]
+    cwd: Option<String>,
+}
+
+impl Template {
+    fn instantiate(&self, vars: &BTreeMap<String, String>) -> Result<Template, Vec<String>> {
+        fill_placeholders(self, vars)
+    }
+}
+
+/// A tab stored with `define_tab_template` and opened by `new_tab_from_template`.
+/// Strings may contain `{{var}}` placeholders, the tab name included.
+#[derive(Clone, Serialize, Deserialize, schemars::JsonSchema)]
+struct TabTemplate {
+    tab_name: String,
+    #[serde(default)]
+    cwd: Option<String>,
+    /// Command panes opened in the new tab; their cwd defaults to the tab's
+    #[serde(default)]
+    panes: Vec<AutostartEntry>,
+}
+
+/// Fill `{{var}}` placeholders in every string of a template, failing with
+/// the names of any left unfilled
+fn fill_placeholders<T>(template: &T, vars: &BTreeMap<String, String>) -> Result<T, Vec<String>>
+where
+    T: Serialize + serde::de::DeserializeOwned,
+{
+    fn fill(value: &mut serde_json::Value, vars: &BTreeMap<String, String>, missing: &mut Vec<String>) {
+        match value {
+            serde_json::Value::String(text) => {
+                for (name, replacement) in vars {
+                    *text = text.replace(&format!("{{{{{}}}}}", name), replacement);
+                }
+                let mut rest = text.as_str();
+                while let Some((_, after)) = rest.split_once("{{") {
+                    let Some((name, tail)) = after.split_once("}}") else { break };
+                    if !missing.iter().any(|m| m == name) {
+                        missing.push(name.to_string());
+                    }
+                    rest = tail;
+                }
+            }
+            serde_json::Value::Array(items) => items.iter_mut().for_each(|v| fill(v, vars, missing)),
+            serde_json::Value::Object(map) => map.values_mut().for_each(|v| fill(v, vars, missing)),
+            _ => {}
+        }
+    }
+    let mut value = serde_json::to_value(template).unwrap_or_default();
+    let mut missing = Vec::new();
+    fill(&mut value, vars, &mut missing);
+    if !missing.is_empty() {
+        return Err(missing);
+    }
+    serde_json::from_value(value).map_err(|e| vec![e.to_string()])
+}
+
+/// A command pane the bridge opens on its own when it loads
+#[derive(Clone, Serialize, Deserialize, schemars::JsonSchema)]
+struct AutostartEntry {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    cwd: Option<String>,
+    #[serde(default)]
+    floating: bool,
+    #[serde(default)]
+    background: bool,
+    /// Tab to open the pane in, created if missing
+    #[serde(default)]
+    tab: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            protection_keywords: vec!["claude".to_string(), "anthropic".to_string()],
+            default_cwd: None,
+            artifacts_root: None,
+            verbosity: LogLevel::Normal,
+            background: false,
+            max_panes: None,
+            max_running: 0,
+            auto_prune: false,
+            notify_pane: None,
+            autostart: Vec::new(),
+            strict: false,
+            aliases: BTreeMap::new(),
+            response_verbosity: Verbosity::Normal,
+            max_reply_bytes: 64 * 1024,
+            trace: false,
+            trace_max_bytes: 1024 * 1024,
+            read_only: false,
+            drain: false,
+            drain_retry_after_secs: 30,
+            write_gap_ms: 0,
+            max_command_results: 1000,
+            allowed_commands: Vec::new(),
+            max_commands_per_minute: 0,
+            plugin_location: "file:~/.local/share/zellij-mcp/plugins/zellij-pane-bridge.wasm".to_string(),
+            profile: None,
+            allow_force: true,
+            enforce_ownership: false,
+            replay_protection: false,
+            replay_window_secs: 30,
+            profiles: BTreeMap::new(),
+            event_sink: None,
+            max_fetch_bytes: 1024 * 1024,
+            file_allowlist: Vec::new(),
+            max_file_bytes: 1024 * 1024,
+            status_badges: true,
+            restore_on_load: None,
+            prompt_title_patterns: Vec::new(),
+            checkpoint_secs: 60,
+            render: RenderMode::Full,
+            show_banner: true,
+            max_request_ms: 2000,
+            max_held_replies: 64,
+            progress_updates: true,
+        }
+    }
+}
+
+impl Config {
+    /// Build a configuration from plugin settings, returning a warning for every
+    /// key or value that was not understood (those fall back to defaults).
+    fn from_map(map: &BTreeMap<String, String>) -> (Config, Vec<String>) {
+        let mut config = Config::default();
+        let mut warnings = Vec::new();
+        for (key, value) in map {
+            let value = value.trim();
+            let parsed = match key.as_str() {
+                "protection_keywords" => {
+                    config.protection_keywords = value.split(',')
+                        .map(|k| k.trim().to_lowercase())
+                        .filter(|k| !k.is_empty())
+                        .collect();
+                    true
+                }
+                "default_cwd" => {
+                    config.default_cwd = Some(value.to_string()).filter(|v| !v.is_empty());
+                    true
+                }
+                "artifacts_root" => {
+                    config.artifacts_root = Some(value.to_string()).filter(|v| !v.is_empty());
+                    true
+                }
+                "verbosity" => match value {
+                    "quiet" => Some(LogLevel::Quiet),
+                    "normal" => Some(LogLevel::Normal),
+                    "debug" => Some(LogLevel::Debug),
+                    _ => None,
+                }.map(|v| config.verbosity = v).is_some(),
+                "background" => parse_bool(value).map(|v| config.background = v).is_some(),
+                "max_panes" => value.parse().map(|v| config.max_panes = Some(v)).is_ok(),
+                "max_running" => value.parse().map(|v| config.max_running = v).is_ok(),
+                "auto_prune" => parse_bool(value).map(|v| config.auto_prune = v).is_some(),
+                "notify_pane" => value.parse().map(|v| config.notify_pane = Some(v)).is_ok(),
+                // A JSON array, since plugin settings are flat strings
+                "autostart" => serde_json::from_str(value).map(|v| config.autostart = v).is_ok(),
+                "strict" => parse_bool(value).map(|v| config.strict = v).is_some(),
+                "response_verbosity" => serde_json::from_value(serde_json::json!(value))
+                    .map(|v| config.response_verbosity = v)
+                    .is_ok(),
+                "max_reply_bytes" => value.parse().map(|v| config.max_reply_bytes = v).is_ok(),
+                "trace" => parse_bool(value).map(|v| config.trace = v).is_some(),
+                "trace_max_bytes" => value.parse().map(|v| config.trace_max_bytes = v).is_ok(),
+                "read_only" => parse_bool(value).map(|v| config.read_only = v).is_some(),
+                "drain" => parse_bool(value).map(|v| config.drain = v).is_some(),
+                "drain_retry_after_secs" => value.parse().map(|v| config.drain_retry_after_secs = v).is_ok(),
+                "write_gap_ms" => value.parse().map(|v| config.write_gap_ms = v).is_ok(),
+                "max_command_results" => value.parse().map(|v| config.max_command_results = v).is_ok(),
+                "allowed_commands" => {
+                    config.allowed_commands = value.split(',')
+                        .map(|c| c.trim().to_string())
+                        .filter(|c| !c.is_empty())
+                        .collect();
+                    true
+                }
+                "max_commands_per_minute" => value.parse().map(|v| config.max_commands_per_minute = v).is_ok(),
+                "allow_force" => parse_bool(value).map(|v| config.allow_force = v).is_some(),
+                "enforce_ownership" => parse_bool(value).map(|v| config.enforce_ownership = v).is_some(),
+                "replay_protection" => parse_bool(value).map(|v| config.replay_protection = v).is_some(),
+                "replay_window_secs" => value.parse().map(|v| config.replay_window_secs = v).is_ok(),
+                "plugin_location" => {
+                    config.plugin_location = value.to_string();
+                    !value.is_empty()
+                }
+                "profile" => {
+                    config.profile = Some(value.to_string()).filter(|v| !v.is_empty());
+                    true
+                }
+                "max_fetch_bytes" => value.parse().map(|v| config.max_fetch_bytes = v).is_ok(),
+                "file_allowlist" => {
+                    config.file_allowlist = value.split(',')
+                        .map(|p| p.trim().trim_matches('/').to_string())
+                        .filter(|p| !p.is_empty())
+                        .collect();
+                    true
+                }
+                "max_file_bytes" => value.parse().map(|v| config.max_file_bytes = v).is_ok(),
+                "status_badges" => parse_bool(value).map(|v| config.status_badges = v).is_some(),
+                "checkpoint_secs" => value.parse().map(|v| config.checkpoint_secs = v).is_ok(),
+                "render" => match value {
+                    "full" => Some(RenderMode::Full),
+                    "compact" => Some(RenderMode::Compact),
+                    _ => None,
+                }.map(|v| config.render = v).is_some(),
+                "show_banner" => parse_bool(value).map(|v| config.show_banner = v).is_some(),
+                "max_request_ms" => value.parse().map(|v| config.max_request_ms = v).is_ok(),
+                "max_held_replies" => value.parse().map(|v| config.max_held_replies = v).is_ok(),
+                "progress_updates" => parse_bool(value).map(|v| config.progress_updates = v).is_some(),
+                "restore_on_load" => {
+                    config.restore_on_load = Some(value.to_string()).filter(|v| !v.is_empty());
+                    true
+                }
+                // A JSON array, since regexes may contain commas
+                "prompt_title_patterns" => serde_json::from_str::<Vec<String>>(value)
+                    .ok()
+                    .filter(|patterns| patterns.iter().all(|p| regex::Regex::new(p).is_ok()))
+                    .map(|patterns| config.prompt_title_patterns = patterns)
+                    .is_some(),
+                "event_sink" => {
+                    config.event_sink = Some(value.to_string()).filter(|v| !v.is_empty());
+                    true
+                }
+                // A JSON object of profile name to settings
+                "profiles" => serde_json::from_str(value).map(|v| config.profiles = v).is_ok(),
+                // "alias=command" pairs separated by commas, e.g. "kill=close,goto=goto_tab"
+                "aliases" => value.split(',')
+                    .filter(|pair| !pair.trim().is_empty())
+                    .map(|pair| pair.split_once('=').map(|(a, c)| (a.trim().to_string(), c.trim().to_string())))
+                    .collect::<Option<BTreeMap<_, _>>>()
+                    .map(|v| config.aliases = v)
+                    .is_some(),
+                _ => {
+                    warnings.push(format!("unknown configuration key '{}'", key));
+                    continue;
+                }
+            };
+            if !parsed {
+                warnings.push(format!("invalid value '{}' for configuration key '{}'", value, key));
+            }
+        }
+        (config, warnings)
+    }
+}
+
+/// Every command's JSON schema as `{command, schema}`, plus the shared `$defs`.
+/// Generated from the same definitions serde parses payloads with, so it cannot drift,
+/// and only once: invalid and KDL payloads look commands up here too.
+fn command_schemas() -> &'static (Vec<serde_json::Value>, Option<serde_json::Value>) {
+    static SCHEMAS: OnceLock<(Vec<serde_json::Value>, Option<serde_json::Value>)> = OnceLock::new();
+    SCHEMAS.get_or_init(build_command_schemas)
+}
+
+fn build_command_schemas() -> (Vec<serde_json::Value>, Option<serde_json::Value>) {
+    let mut schema = serde_json::to_value(schemars::schema_for!(Command)).unwrap_or_default();
+    let defs = schema.get_mut("$defs").map(serde_json::Value::take);
+    let variants = match schema.get_mut("oneOf").map(serde_json::Value::take) {
+        Some(serde_json::Value::Array(variants)) => variants,
+        _ => Vec::new(),
+    };
+    let commands = variants.into_iter()
+        .filter_map(|variant| {
+            let name = variant.pointer("/properties/cmd/const")?.as_str()?.to_string();
+            Some(serde_json::json!({"command": name, "schema": variant}))
+        })
+        .collect();
+    (commands, defs)
+}
+
+/// Parse JSON5-style text: trailing commas, single-quoted strings, unquoted
+/// keys and comments are rewritten into plain JSON first
+fn relax_json(text: &str) -> Option<serde_json::Value> {
+    let chars: Vec<char> = text.chars().collect();
+    // Where the comment starting at `j` ends, if one starts there
+    let comment_end = |j: usize| -> Option<usize> {
+        match (chars.get(j), chars.get(j + 1)) {
+            (Some('/'), Some('/')) => Some(chars[j..].iter().position(|&c| c == '\n').map_or(chars.len(), |n| j + n)),
+            (Some('/'), Some('*')) => Some(
+                (j + 2..chars.len().saturating_sub(1))
+                    .find(|&k| chars[k] == '*' && chars[k + 1] == '/')
+                    .map_or(chars.len(), |k| k + 2),
+            ),
+            _ => None,
+        }
+    };
+    // The next character that is neither whitespace nor part of a comment
+    let next_significant = |mut j: usize| -> Option<char> {
+        while j < chars.len() {
+            if let Some(end) = comment_end(j) {
+                j = end;
+            } else if chars[j].is_whitespace() {
+                j += 1;
+            } else {
+                return Some(chars[j]);
+            }
+        }
+        None
+    };
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '$' || c == '-';
+
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if let Some(end) = comment_end(i) {
+            i = end;
+            continue;
+        }
+        match c {
+            '"' | '\'' => {
+                out.push('"');
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    match chars[i] {
+                        '\\' if chars.get(i + 1) == Some(&'\'') => out.push('\''),
+                        '\\' => {
+                            out.push('\\');
+                            out.extend(chars.get(i + 1));
+                        }
+                        '"' => out.push_str("\\\""),
+                        other => out.push(other),
+                    }
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                out.push('"');
+                i += 1;
+            }
+            ',' if matches!(next_significant(i + 1), Some('}') | Some(']') | None) => i += 1,
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let start = i;
+                while i < chars.len() && is_word(chars[i]) {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                if next_significant(i) == Some(':') {
+                    out.push_str(&format!("\"{}\"", word));
+                } else {
+                    out.push_str(&word);
+                }
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    serde_json::from_str(&out).ok()
+}
+
+/// Levenshtein distance between two command names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Cut a line to `cols` characters, marking the cut with an ellipsis
+fn fit_width(line: &str, cols: usize) -> Cow<'_, str> {
+    if line.chars().count() <= cols {
+        return Cow::Borrowed(line);
+    }
+    let mut cut: String = line.chars().take(cols.saturating_sub(1)).collect();
+    if cols > 0 {
+        cut.push('…');
+    }
+    Cow::Owned(cut)
+}
+
+/// Write or append to `path`, creating it and its folder if needed. Plugin file
+/// I/O is not a host call that `host!` could hold back, so a dry run skips it here.
+fn write_file(path: &str, bytes: &[u8], append: bool) -> std::io::Result<()> {
+    if trace::capturing() {
+        return Ok(());
+    }
+    std::path::Path::new(path).parent().map_or(Ok(()), std::fs::create_dir_all)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    std::io::Write::write_all(&mut file, bytes)
+}
+
+/// Replace `path`, keeping its previous contents next to it as `<path>.bak`
+fn write_with_backup(path: &str, text: &str) -> Result<(), String> {
+    // A dry run leaves the file and its backup as they are
+    if trace::capturing() {
+        return Ok(());
+    }
+    let backup = format!("{}.bak", path);
+    if let Err(e) = std::fs::rename(path, &backup) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("zellij-pane-bridge: cannot keep a backup of {}: {}", path, e);
+        }
+    }
+    write_file(path, text.as_bytes(), false).map_err(|e| format!("Cannot write {}: {}", path, e))
+}
+
+/// Read what `write_with_backup` wrote, falling back to the backup when the
+/// file is missing or was left unreadable by a crash mid-write
+fn read_with_backup<T: serde::de::DeserializeOwned>(path: &str) -> Option<T> {
+    let backup = format!("{}.bak", path);
+    for candidate in [path, backup.as_str()] {
+        match std::fs::read_to_string(candidate) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(value) => return Some(value),
+                Err(e) => eprintln!("zellij-pane-bridge: ignoring unreadable {}: {}", candidate, e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("zellij-pane-bridge: cannot read {}: {}", candidate, e),
+        }
+    }
+    None
+}
+
+/// The `major.minor.patch` of a version string such as "0.43.1" or "zellij 0.41.2"
+fn parse_version(text: &str) -> Option<(u32, u32, u32)> {
+    let version = text.split_whitespace().last()?.trim_start_matches('v');
+    let mut parts = version.split(['.', '-']).map(|p| p.parse::<u32>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), patch) => Some((major, minor, patch.and_then(Result::ok).unwrap_or(0))),
+        _ => None,
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Maximum resize steps spent converging on an exact size
+const MAX_RESIZE_STEPS: u32 = 100;
+
+#[derive(Clone)]
+struct PendingResize {
+    target_rows: Option<usize>,
+    target_cols: Option<usize>,
+    steps_left: u32,
+    /// Geometry and signed distance to target seen when the last step was issued
+    last_size: (usize, usize),
+    last_diff: (i64, i64),
+    /// Updates seen since the last step without any change in geometry
+    stalls: u8,
+}
+
+/// A resize amount: a number of cells, or a percentage of the tab ("25%")
+#[derive(Clone, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(untagged)]
+enum ResizeAmount {
+    Cells(usize),
+    Percent(String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CommandResult {
+    pane_id: u32,
+    exit_code: Option<i32>,
+    exited: bool,
+    /// The command line the pane runs, once known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    /// When the command last started and exited, in Unix milliseconds
+    #[serde(default)]
+    started_ms: Option<u64>,
+    #[serde(default)]
+    exited_ms: Option<u64>,
+    /// Order of the last change, for evicting the stalest results first
+    #[serde(skip)]
+    updated: u64,
+}
+
+/// Version of the request/response protocol, bumped on incompatible changes or
+/// when clients need to detect newly added commands
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Request fields handled by the bridge itself rather than by a command
+const ENVELOPE_FIELDS: &[&str] = &["strict", "request_id", "min_version", "verbosity", "encoding", "nonce", "ts_ms", "dry_run", "progress"];
+
+/// Envelope fields read from any payload, independent of the command
+#[derive(Clone, Default, Deserialize)]
+struct Envelope {
+    /// Opaque client value echoed back so overlapping replies can be matched up
+    #[serde(default)]
+    request_id: Option<serde_json::Value>,
+    /// Oldest protocol version the client can work with
+    #[serde(default)]
+    min_version: Option<u32>,
+    #[serde(default)]
+    verbosity: Option<Verbosity>,
+    /// Encoding for the reply; defaults to the payload's encoding
+    #[serde(default)]
+    encoding: Option<Encoding>,
+    /// Increasing per request, for `replay_protection`
+    #[serde(default)]
+    nonce: Option<u64>,
+    /// When the client sent the request, for `replay_protection` without nonces
+    #[serde(default)]
+    ts_ms: Option<u64>,
+    /// Send progress lines ahead of a reply that takes a while (`progress_updates` when unset)
+    #[serde(default)]
+    progress: Option<bool>,
+}
+
+impl Envelope {
+    fn parse(payload: &str) -> Envelope {
+        serde_json::from_str(payload)
+            .ok()
+            .or_else(|| relax_json(payload).and_then(|v| serde_json::from_value(v).ok()))
+            .unwrap_or_default()
+    }
+}
+
+/// An intermediate line written to a CLI pipe before the reply to a long request.
+/// It has no `success` field, which is how clients tell it from the reply.
+#[derive(Serialize)]
+struct Progress<'a> {
+    progress: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a serde_json::Value>,
+}
+
+/// A response as written to the pipe, with the envelope fields added
+#[derive(Serialize)]
+struct Reply<'a> {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Cow<'a, serde_json::Value>>,
+    /// Set when `data` holds the first page of a larger result (see `next_page`)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    paginated: bool,
+    /// What the bridge went ahead despite, for the caller to react to
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    warnings: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a serde_json::Value>,
+    protocol_version: u32,
+    /// Every tab and pane after the command, at `full` verbosity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<serde_json::Value>,
+}
+
+/// How much a reply carries beyond success and error
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Verbosity {
+    Minimal,
+    Normal,
+    Full,
+}
+
+#[derive(Clone, Deserialize, Serialize, schemars::JsonSchema)]
+#[serde(tag = "cmd")]
+enum Command {
+    // === WRITE ===
+    #[serde(rename = "write")]
+    Write {
+        pane_id: u32,
+        chars: String,
+        #[serde(default)]
+        allow_exited: bool,
+    },
+
+    #[serde(rename = "write_bytes")]
+    WriteBytes {
+        pane_id: u32,
+        bytes: Vec<u8>,
+        #[serde(default)]
+        allow_exited: bool,
+    },
+
+    // === READ ===
+    #[serde(rename = "list")]
+    List,
+
+    #[serde(rename = "query")]
+    Query { name: String },
+
+    /// Everything about one pane, by id or by name: tab, geometry and neighbours
+    #[serde(rename = "find_pane")]
+    FindPane {
+        #[serde(default)]
+        pane_id: Option<u32>,
+        /// Exact title first, then the first pane whose title or command contains it
+        #[serde(default)]
+        name: Option<String>,
+    },
+
+    #[serde(rename = "list_tabs")]
+    ListTabs,
+
+    /// Wait until a terminal pane whose title or command contains `pattern` exists
+    #[serde(rename = "wait_for_pane")]
+    WaitForPane {
+        pattern: String,
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    /// Wait until a pane's title changes, optionally to one containing `pattern`
+    #[serde(rename = "wait_for_title_change")]
+    WaitForTitleChange {
+        pane_id: u32,
+        #[serde(default)]
+        pattern: Option<String>,
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    /// Wait until a pane's title, geometry and exit status have not changed for
+    /// `quiet_secs`, a rough sign that its command is waiting for input
+    #[serde(rename = "wait_for_quiet")]
+    WaitForQuiet {
+        pane_id: u32,
+        #[serde(default)]
+        quiet_secs: Option<f64>,
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    /// Wait until a pane's title says its shell is back at the prompt, as set
+    /// by a title hook matching `prompt_title_patterns`
+    #[serde(rename = "wait_for_prompt")]
+    WaitForPrompt {
+        pane_id: u32,
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    #[serde(rename = "tab_status")]
+    TabStatus { index: usize },
+
+    // === PANE CONTROL ===
+    #[serde(rename = "focus")]
+    Focus { pane_id: u32 },
+
+    #[serde(rename = "close")]
+    Close {
+        pane_id: u32,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "hide")]
+    Hide {
+        pane_id: u32,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "show")]
+    Show { pane_id: u32 },
+
+    #[serde(rename = "clear")]
+    Clear { pane_id: u32 },
+
+    #[serde(rename = "fullscreen")]
+    Fullscreen { pane_id: u32 },
+
+    /// Mark a pane's title for a while so a human can spot it
+    #[serde(rename = "highlight")]
+    Highlight {
+        pane_id: u32,
+        #[serde(default)]
+        duration_secs: Option<f64>,
+    },
+
+    #[serde(rename = "rename_pane")]
+    RenamePane { pane_id: u32, name: String },
+
+    #[serde(rename = "move")]
+    Move { pane_id: u32, direction: String },
+
+    #[serde(rename = "swap")]
+    Swap {
+        pane_a: u32,
+        pane_b: u32,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "resize")]
+    Resize {
+        pane_id: u32,
+        #[serde(default)]
+        direction: Option<String>,
+        #[serde(default)]
+        amount: Option<ResizeAmount>,
+        #[serde(default)]
+        shrink: bool,
+        #[serde(default)]
+        rows: Option<usize>,
+        #[serde(default)]
+        cols: Option<usize>,
+    },
+
+    #[serde(rename = "toggle_floating")]
+    TogglePaneFloating { pane_id: u32 },
+
+    /// Best-effort reversal of the latest renames, moves, resizes, hides,
+    /// shows, float toggles and tab focus changes
+    #[serde(rename = "undo")]
+    Undo {
+        #[serde(default)]
+        steps: Option<usize>,
+    },
+
+    #[serde(rename = "float_all")]
+    FloatAll {
+        index: usize,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "embed_all")]
+    EmbedAll {
+        index: usize,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "stack_panes")]
+    StackPanes { pane_ids: Vec<u32> },
+
+    #[serde(rename = "unstack_panes")]
+    UnstackPanes { pane_ids: Vec<u32> },
+
+    #[serde(rename = "move_to_tab")]
+    MoveToTab {
+        pane_id: u32,
+        tab_index: usize,
+        #[serde(default)]
+        focus: bool,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "break_to_new_tab")]
+    BreakToNewTab {
+        #[serde(default)]
+        pane_id: Option<u32>,
+        #[serde(default)]
+        group: Option<String>,
+        #[serde(default)]
+        tab_name: Option<String>,
+        #[serde(default)]
+        focus: bool,
+        #[serde(default)]
+        force: bool,
+    },
+
+    // === PANE GROUPS ===
+    #[serde(rename = "group_create")]
+    GroupCreate { name: String, pane_ids: Vec<u32> },
+
+    #[serde(rename = "group_list")]
+    GroupList,
+
+    #[serde(rename = "group_dissolve")]
+    GroupDissolve { name: String },
+
+    #[serde(rename = "group_close")]
+    GroupClose {
+        name: String,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "group_hide")]
+    GroupHide {
+        name: String,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "group_move_to_tab")]
+    GroupMoveToTab {
+        name: String,
+        tab_index: usize,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "group_write")]
+    GroupWrite { name: String, chars: String },
+
+    // === COMMAND EXECUTION ===
+    #[serde(rename = "run")]
+    Run {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        floating: bool,
+        #[serde(default)]
+        background: Option<bool>,
+        /// Placement of a floating pane, in cells or as a percentage like "50%"
+        #[serde(default)]
+        x: Option<Dimension>,
+        #[serde(default)]
+        y: Option<Dimension>,
+        #[serde(default)]
+        width: Option<Dimension>,
+        #[serde(default)]
+        height: Option<Dimension>,
+        /// Wait in the job queue instead of failing while `max_running` commands are running
+        #[serde(default)]
+        queue: bool,
+        /// Track the run as a job with this label
+        #[serde(default)]
+        label: Option<String>,
+    },
+
+    #[serde(rename = "rerun")]
+    Rerun { pane_id: u32 },
+
+    /// Interrupt (Ctrl-C) or close every running command pane whose command matches a regex
+    #[serde(rename = "kill_command")]
+    KillMatching {
+        pattern: String,
+        /// "interrupt" (the default) or "close"
+        #[serde(default)]
+        signal: Option<String>,
+        /// Leave protected panes alone (the default)
+        #[serde(default)]
+        exclude_protected: Option<bool>,
+    },
+
+    /// Replace a bridge-opened command pane with its command run again, with
+    /// new arguments and extra environment variables. The new pane takes the
+    /// old one's place and name.
+    #[serde(rename = "rerun_with")]
+    RerunWith {
+        pane_id: u32,
+        /// Replaces the original arguments; the original ones are kept when absent
+        #[serde(default)]
+        args: Option<Vec<String>>,
+        /// Added to the variables the pane was started with
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        force: bool,
+    },
+
+    /// Open another pane running what a bridge-opened command pane was started with
+    #[serde(rename = "duplicate")]
+    Duplicate {
+        pane_id: u32,
+        #[serde(default)]
+        floating: bool,
+        /// Tab to open the copy in, created if missing (the active tab otherwise)
+        #[serde(default)]
+        tab: Option<String>,
+    },
+
+    /// `run`, with the output also captured to a file for `read_output`
+    #[serde(rename = "run_captured")]
+    RunCaptured {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        floating: bool,
+        #[serde(default)]
+        background: Option<bool>,
+    },
+
+    /// Queue a command as a job, started once `max_running` leaves room
+    #[serde(rename = "submit")]
+    Submit {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        floating: bool,
+        #[serde(default)]
+        background: Option<bool>,
+        /// Queued jobs with a higher priority start first (default 0)
+        #[serde(default)]
+        priority: i32,
+        /// Stay queued while any job of higher priority is queued or running
+        #[serde(default)]
+        defer: bool,
+        #[serde(default)]
+        label: Option<String>,
+    },
+
+    /// Take a job back: drop it from the queue, or interrupt and close its
+    /// pane as `shutdown` does if it is running. With `label` instead of
+    /// `job_id`, every unfinished job with that label is taken back and
+    /// running ones are only interrupted, so `grace_secs` is refused.
+    #[serde(rename = "cancel")]
+    Cancel {
+        #[serde(default)]
+        job_id: Option<u64>,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        grace_secs: Option<f64>,
+    },
+
+    /// Jobs from `submit`, queued `run`s and labelled `run`s, oldest first
+    #[serde(rename = "jobs")]
+    Jobs {
+        #[serde(default)]
+        state: Option<JobState>,
+        #[serde(default)]
+        label: Option<String>,
+    },
+
+    /// Hold the reply until every job with `label` has finished
+    #[serde(rename = "wait_jobs")]
+    WaitJobs {
+        label: String,
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    /// Forget finished jobs, only those with `label` when given
+    #[serde(rename = "prune_jobs")]
+    PruneJobs {
+        #[serde(default)]
+        label: Option<String>,
+    },
+
+    /// Captured output of a `run_captured` command, from byte `offset` and up to `limit` bytes
+    #[serde(rename = "read_output")]
+    ReadOutput {
+        capture_id: u64,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+
+    #[serde(rename = "command_status")]
+    CommandStatus { pane_id: u32 },
+
+    /// Every tracked command result, with its pane's title and handle and how long it ran
+    #[serde(rename = "list_command_results")]
+    ListCommandResults {
+        /// Only results of commands that have (true) or have not (false) exited
+        #[serde(default)]
+        exited: Option<bool>,
+    },
+
+    /// Forget tracked command results: those of `pane_ids`, those older than
+    /// `older_than_secs`, or every exited one when neither is given
+    #[serde(rename = "purge_results")]
+    PurgeResults {
+        #[serde(default)]
+        pane_ids: Vec<u32>,
+        /// Age counted from the exit, or from the start of a command still running
+        #[serde(default)]
+        older_than_secs: Option<f64>,
+        /// Purge results of commands still running too, which then stop being tracked
+        #[serde(default)]
+        include_running: bool,
+    },
+
+    // === TAB OPERATIONS ===
+    #[serde(rename = "new_tab")]
+    NewTab {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        cwd: Option<String>,
+    },
+
+    #[serde(rename = "close_tab")]
+    CloseTab {
+        index: u32,
+        #[serde(default)]
+        force: bool,
+    },
+
+    /// Focus a tab by `index` (its position), by `name`, creating a named tab
+    /// that does not exist with `create`, or by `tab_offset` from the active tab
+    #[serde(rename = "focus_tab")]
+    FocusTab {
+        #[serde(default)]
+        index: Option<u32>,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        create: bool,
+        /// Tabs to move right (positive) or left (negative), wrapping around
+        #[serde(default)]
+        tab_offset: Option<i64>,
+    },
+
+    /// Go back to the tab that was active before the current one
+    #[serde(rename = "goto_previous_tab")]
+    GotoPreviousTab,
+
+    /// `focus_tab` by index, kept for older clients
+    #[serde(rename = "goto_tab")]
+    GotoTab { index: u32 },
+
+    #[serde(rename = "next_swap_layout")]
+    NextSwapLayout {
+        #[serde(default)]
+        index: Option<u32>,
+    },
+
+    #[serde(rename = "previous_swap_layout")]
+    PreviousSwapLayout {
+        #[serde(default)]
+        index: Option<u32>,
+    },
+
+    // === SESSION ===
+    #[serde(rename = "next_page")]
+    NextPage { token: String },
+
+    #[serde(rename = "stats")]
+    Stats,
+
+    /// How long command lines have taken in command panes: runs, success rate
+    /// and min/avg/max milliseconds, most run first
+    #[serde(rename = "timings")]
+    Timings {
+        /// Only command lines containing this text
+        #[serde(default)]
+        command: Option<String>,
+    },
+
+    #[serde(rename = "status")]
+    Status,
+
+    /// Interrupt a pane, wait for it to exit and close it, by force if it is
+    /// still running after `grace_secs`
+    #[serde(rename = "shutdown")]
+    Shutdown {
+        pane_id: u32,
+        #[serde(default)]
+        grace_secs: Option<f64>,
+        #[serde(default)]
+        force: bool,
+    },
+
+    #[serde(rename = "quiesce")]
+    Quiesce {
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    /// Relay a command to the bridge running in another session
+    #[serde(rename = "forward")]
+    Forward {
+        session: String,
+        name: String,
+        #[serde(default)]
+        payload: Option<serde_json::Value>,
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    /// Make an HTTP request from the host and reply with its response
+    #[serde(rename = "fetch")]
+    Fetch {
+        url: String,
+        /// GET, POST, PUT or DELETE
+        #[serde(default)]
+        method: Option<String>,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        #[serde(default)]
+        body: Option<String>,
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    // === HOST FILES ===
+    /// Read a file in the host folder, from byte `offset` and up to `limit` bytes
+    #[serde(rename = "read_file")]
+    ReadFile {
+        path: String,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+
+    /// Write (or append to) a file in the host folder, creating missing parent folders
+    #[serde(rename = "write_file")]
+    WriteFile {
+        path: String,
+        content: String,
+        #[serde(default)]
+        append: bool,
+    },
+
+    #[serde(rename = "list_dir")]
+    ListDir {
+        #[serde(default)]
+        path: Option<String>,
+    },
+
+    /// Point the host folder (and so the file commands and relative cwds) at another folder
+    #[serde(rename = "change_host_dir")]
+    ChangeHostDir { path: String },
+
+    /// Events logged since `since_cursor` (everything still kept when absent)
+    #[serde(rename = "poll_events")]
+    PollEvents {
+        #[serde(default)]
+        since_cursor: Option<u64>,
+    },
+
+    /// Wait for the next event of one of `types` (any when empty), on `pane_id`
+    /// and with a title containing `title_pattern`, whichever are given
+    #[serde(rename = "await_event")]
+    AwaitEvent {
+        #[serde(default)]
+        types: Vec<String>,
+        #[serde(default)]
+        pane_id: Option<u32>,
+        #[serde(default)]
+        title_pattern: Option<String>,
+        /// Also consider events already logged from this cursor on
+        #[serde(default)]
+        since_cursor: Option<u64>,
+        #[serde(default)]
+        timeout_secs: Option<f64>,
+    },
+
+    /// JSON Schema of one command's payload, or of every command
+    #[serde(rename = "describe")]
+    Describe {
+        #[serde(default)]
+        command: Option<String>,
+    },
+
+    #[serde(rename = "ping")]
+    Ping {
+        /// Returned unchanged, so clients can match replies to requests
+        #[serde(default)]
+        echo: Option<serde_json::Value>,
+    },
+
+    #[serde(rename = "self_test")]
+    SelfTest,
+
+    // === WORKFLOW RECORDING ===
+    #[serde(rename = "record_start")]
+    RecordStart,
+
+    #[serde(rename = "record_stop")]
+    RecordStop { name: String },
+
+    // === WORKSPACE TEMPLATES ===
+    #[serde(rename = "define_template")]
+    DefineTemplate {
+        name: String,
+        #[serde(default)]
+        tabs: Vec<TemplateTab>,
+        #[serde(default)]
+        runs: Vec<AutostartEntry>,
+    },
+
+    #[serde(rename = "instantiate_template")]
+    InstantiateTemplate {
+        name: String,
+        #[serde(default)]
+        vars: BTreeMap<String, String>,
+    },
+
+    #[serde(rename = "define_tab_template")]
+    DefineTabTemplate {
+        name: String,
+        /// Name of the opened tab, usually with placeholders like "task-{{ticket}}"
+        tab_name: String,
+        #[serde(default)]
+        cwd: Option<String>,
+        #[serde(default)]
+        panes: Vec<AutostartEntry>,
+    },
+
+    #[serde(rename = "new_tab_from_template")]
+    NewTabFromTemplate {
+        template: String,
+        #[serde(default)]
+        vars: BTreeMap<String, String>,
+    },
+
+    // === SNAPSHOTS ===
+    /// Save the current tabs and panes under a name, replacing any snapshot of that name
+    #[serde(rename = "save_snapshot")]
+    SaveSnapshot {
+        name: String,
+        #[serde(default)]
+        description: Option<String>,
+    },
+
+    #[serde(rename = "list_snapshots")]
+    ListSnapshots,
+
+    #[serde(rename = "delete_snapshot")]
+    DeleteSnapshot { name: String },
+
+    /// What changed in the tabs and panes since a snapshot was saved
+    #[serde(rename = "diff_snapshot")]
+    DiffSnapshot { name: String },
+
+    /// Recreate a snapshot's tabs and command panes in the current session
+    #[serde(rename = "restore_snapshot")]
+    RestoreSnapshot { name: String },
+
+    // === STATE TRANSFER ===
+    #[serde(rename = "export_state")]
+    ExportState,
+
+    #[serde(rename = "import_state")]
+    ImportState {
+        state: ExportedState,
+        /// Drop existing groups and recordings instead of merging into them
+        #[serde(default)]
+        replace: bool,
+    },
+
+    #[serde(rename = "replay")]
+    Replay {
+        name: String,
+        /// Stop at the first failing step instead of running the rest
+        #[serde(default)]
+        stop_on_error: bool,
+    },
+
+    #[serde(rename = "toggle_pane_frames")]
+    TogglePaneFrames {
+        #[serde(default)]
+        restore: bool,
+    },
+
+    #[serde(rename = "session_info")]
+    SessionInfo,
+
+    /// The session as a Zellij layout: tabs with the command panes the bridge
+    /// opened, or every terminal pane with `include_all`
+    #[serde(rename = "export_layout_kdl")]
+    ExportLayoutKdl {
+        #[serde(default)]
+        include_all: bool,
+    },
+
+    #[serde(rename = "detach")]
+    Detach,
+
+    // === CONFIGURATION ===
+    #[serde(rename = "get_config")]
+    GetConfig,
+
+    #[serde(rename = "set_config")]
+    SetConfig { values: serde_json::Map<String, serde_json::Value> },
+
+    #[serde(rename = "reload_config")]
+    ReloadConfig,
+
+    #[serde(rename = "set_read_only")]
+    SetReadOnly { enabled: bool },
+
+    #[serde(rename = "set_profile")]
+    SetProfile { name: String },
+
+    /// Tear down everything the bridge started and switch to read-only mode
+    #[serde(rename = "panic")]
+    Panic,
+
+    #[serde(rename = "drain")]
+    Drain {
+        enabled: bool,
+        #[serde(default)]
+        retry_after_secs: Option<u64>,
+    },
+
+    // === PERMISSIONS ===
+    #[serde(rename = "permissions")]
+    Permissions,
+
+    #[serde(rename = "request_permissions")]
+    RequestPermissions,
+
+    // === PROTECTION ===
+    #[serde(rename = "protect")]
+    Protect { pane_id: u32 },
+
+    /// Protect every pane whose title or command matches `regex`, now or later
+    #[serde(rename = "protect_pattern")]
+    ProtectPattern { regex: String },
+
+    #[serde(rename = "unprotect_pattern")]
+    UnprotectPattern { regex: String },
+
+    /// Let commands change a pane the bridge did not open, under `enforce_ownership`
+    #[serde(rename = "grant_pane")]
+    GrantPane { pane_id: u32 },
+
+    #[serde(rename = "revoke_pane")]
+    RevokePane { pane_id: u32 },
+
+    #[serde(rename = "get_protected")]
+    GetProtected,
+
+    /// Commands refused for targeting protected panes: totals and the latest ones
+    #[serde(rename = "protection_report")]
+    ProtectionReport,
+}
+
+impl Command {
+    /// The terminal pane a single-pane command acts on
+    fn target_pane(&self) -> Option<u32> {
+        match self {
+            Command::Write { pane_id, .. }
+            | Command::WriteBytes { pane_id, .. }
+            | Command::Focus { pane_id }
+            | Command::Close { pane_id, .. }
+            | Command::Hide { pane_id, .. }
+            | Command::Show { pane_id }
+            | Command::Clear { pane_id }
+            | Command::Fullscreen { pane_id }
+            | Command::RenamePane { pane_id, .. }
+            | Command::Duplicate { pane_id, .. }
+            | Command::RerunWith { pane_id, .. }
+            | Command::Shutdown { pane_id, .. }
+            | Command::Highlight { pane_id, .. }
+            | Command::Move { pane_id, .. }
+            | Command::Resize { pane_id, .. }
+            | Command::TogglePaneFloating { pane_id }
+            | Command::MoveToTab { pane_id, .. }
+            | Command::WaitForTitleChange { pane_id, .. }
+            | Command::WaitForQuiet { pane_id, .. }
+            | Command::WaitForPrompt { pane_id, .. }
+            | Command::Rerun { pane_id } => Some(*pane_id),
+            Command::BreakToNewTab { pane_id, .. } => *pane_id,
+            _ => None,
+        }
+    }
+
+    /// The pane whose refreshed state a successful reply should carry
+    fn observed_pane(&self) -> Option<u32> {
+        match self {
+            Command::Focus { pane_id }
+            | Command::Close { pane_id, .. }
+            | Command::Hide { pane_id, .. }
+            | Command::Show { pane_id }
+            | Command::Fullscreen { pane_id }
+            | Command::RenamePane { pane_id, .. }
+            | Command::Move { pane_id, .. }
+            | Command::Resize { pane_id, .. }
+            | Command::TogglePaneFloating { pane_id }
+            | Command::MoveToTab { pane_id, .. } => Some(*pane_id),
+            _ => None,
+        }
+    }
+
+    /// Whether the reply is held until something happens in the session
+    fn holds_reply(&self) -> bool {
+        self.observed_pane().is_some()
+            || matches!(
+                self,
+                Command::WaitForPane { .. }
+                    | Command::WaitForTitleChange { .. }
+                    | Command::WaitForQuiet { .. }
+                    | Command::WaitForPrompt { .. }
+                    | Command::Shutdown { .. }
+                    | Command::Cancel { .. }
+                    | Command::WaitJobs { .. }
+                    | Command::Forward { .. }
+                    | Command::Fetch { .. }
+                    | Command::AwaitEvent { .. }
+                    | Command::SelfTest
+                    | Command::Quiesce { .. }
+            )
+    }
+
+    /// Whether the command changes the session rather than only looking at it
+    fn is_mutating(&self) -> bool {
+        self.required_permission().is_some_and(|p| p != PermissionType::ReadApplicationState)
+    }
+
+    /// Whether the command changes what the bridge keeps for itself (results,
+    /// jobs, groups, snapshots, templates, configuration, protection) without
+    /// reaching the host
+    fn changes_bridge(&self) -> bool {
+        matches!(
+            self,
+            Command::PurgeResults { .. }
+                | Command::PruneJobs { .. }
+                | Command::GroupCreate { .. }
+                | Command::GroupDissolve { .. }
+                | Command::RecordStop { .. }
+                | Command::SaveSnapshot { .. }
+                | Command::DeleteSnapshot { .. }
+                | Command::DefineTemplate { .. }
+                | Command::DefineTabTemplate { .. }
+                | Command::ProtectPattern { .. }
+        ) || self.is_policy_change()
+    }
+
+    /// Whether the command changes the policy the bridge enforces on its callers,
+    /// which a caller held to a profile must not lift for itself
+    fn is_policy_change(&self) -> bool {
+        matches!(
+            self,
+            Command::SetConfig { .. }
+                | Command::ReloadConfig
+                | Command::SetProfile { .. }
+                | Command::SetReadOnly { .. }
+                | Command::ImportState { .. }
+                | Command::Drain { .. }
+                | Command::Protect { .. }
+                | Command::UnprotectPattern { .. }
+        )
+    }
+
+    /// Whether the command starts new work, which drain mode refuses
+    fn starts_work(&self) -> bool {
+        matches!(
+            self,
+            Command::Run { .. }
+                | Command::Rerun { .. }
+                | Command::Duplicate { .. }
+                | Command::RerunWith { .. }
+                | Command::RunCaptured { .. }
+                | Command::Submit { .. }
+                | Command::NewTab { .. }
+                | Command::Write { .. }
+                | Command::WriteBytes { .. }
+                | Command::GroupWrite { .. }
+                | Command::Replay { .. }
+                | Command::InstantiateTemplate { .. }
+                | Command::RestoreSnapshot { .. }
+                | Command::NewTabFromTemplate { .. }
+        )
+    }
+
+    /// The oldest Zellij release providing the host calls this command relies
+    /// on, for commands that need more than the bridge's baseline
+    fn min_host_version(&self) -> Option<(u32, u32, u32)> {
+        match self {
+            Command::FloatAll { .. }
+            | Command::EmbedAll { .. }
+            | Command::UnstackPanes { .. }
+            | Command::RerunWith { .. } => Some((0, 43, 0)),
+            Command::StackPanes { .. }
+            | Command::BreakToNewTab { .. }
+            | Command::MoveToTab { .. }
+            | Command::GroupMoveToTab { .. }
+            | Command::Swap { .. } => Some((0, 42, 0)),
+            _ => None,
+        }
+    }
+
+    /// The host permission this command depends on, if any
+    fn required_permission(&self) -> Option<PermissionType> {
+        match self {
+            Command::Write { .. } | Command::WriteBytes { .. } | Command::GroupWrite { .. } => {
+                Some(PermissionType::WriteToStdin)
+            }
+            Command::List
+            | Command::Query { .. }
+            | Command::FindPane { .. }
+            | Command::ListTabs
+            | Command::TabStatus { .. }
+            | Command::ReadFile { .. }
+            | Command::ReadOutput { .. }
+            | Command::ListDir { .. }
+            | Command::WaitForPane { .. }
+            | Command::WaitForTitleChange { .. }
+            | Command::WaitForQuiet { .. }
+            | Command::WaitForPrompt { .. }
+            | Command::SaveSnapshot { .. }
+            | Command::DiffSnapshot { .. }
+            | Command::ExportLayoutKdl { .. }
+            | Command::SessionInfo => {
+                Some(PermissionType::ReadApplicationState)
+            }
+            Command::Run { .. }
+            | Command::Rerun { .. }
+            | Command::Duplicate { .. }
+            | Command::RerunWith { .. }
+            | Command::RunCaptured { .. }
+            | Command::Submit { .. }
+            | Command::Forward { .. } => {
+                Some(PermissionType::RunCommands)
+            }
+            Command::Fetch { .. } => Some(PermissionType::WebAccess),
+            Command::ChangeHostDir { .. } => Some(PermissionType::FullHdAccess),
+            // Bridge bookkeeping that never reaches the host
+            Command::CommandStatus { .. }
+            | Command::ListCommandResults { .. }
+            | Command::PurgeResults { .. }
+            | Command::Jobs { .. }
+            | Command::WaitJobs { .. }
+            | Command::PruneJobs { .. }
+            | Command::GroupCreate { .. }
+            | Command::GroupList
+            | Command::GroupDissolve { .. }
+            | Command::GetConfig
+            | Command::SetConfig { .. }
+            | Command::ReloadConfig
+            | Command::Permissions
+            | Command::RequestPermissions
+            | Command::NextPage { .. }
+            | Command::Stats
+            | Command::Timings { .. }
+            | Command::Status
+            | Command::Ping { .. }
+            | Command::Describe { .. }
+            | Command::PollEvents { .. }
+            | Command::AwaitEvent { .. }
+            | Command::SelfTest
+            | Command::RecordStart
+            | Command::RecordStop { .. }
+            | Command::ExportState
+            | Command::ImportState { .. }
+            | Command::ListSnapshots
+            | Command::DeleteSnapshot { .. }
+            | Command::DefineTemplate { .. }
+            | Command::DefineTabTemplate { .. }
+            | Command::SetReadOnly { .. }
+            | Command::SetProfile { .. }
+            | Command::Panic
+            | Command::Drain { .. }
+            | Command::Quiesce { .. }
+            | Command::Protect { .. }
+            | Command::ProtectPattern { .. }
+            | Command::UnprotectPattern { .. }
+            | Command::GetProtected
+            | Command::ProtectionReport => None,
+            _ => Some(PermissionType::ChangeApplicationState),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct Response {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Stable identifier for the failure, so clients need not parse `error`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    InvalidCommand,
+    InvalidArgument,
+    PaneNotFound,
+    WrongPaneType,
+    PaneExited,
+    TabNotFound,
+    GroupNotFound,
+    Protected,
+    PermissionDenied,
+    VersionMismatch,
+    Unsupported,
+    InvalidConfig,
+    LimitReached,
+    PartialFailure,
+    RecordingNotFound,
+    TemplateNotFound,
+    SnapshotNotFound,
+    ReadOnly,
+    Draining,
+    Timeout,
+    NotAllowed,
+    RateLimited,
+    FileError,
+    NotOwner,
+    Replayed,
+    UnsupportedOnHost,
+    Overloaded,
+    JobNotFound,
+}
+
+#[derive(Serialize)]
+struct PaneSummary {
+    id: u32,
+    is_plugin: bool,
+    title: String,
+    is_focused: bool,
+    is_floating: bool,
+    is_fullscreen: bool,
+    is_suppressed: bool,
+    tab_index: usize,
+    command: Option<String>,
+    exit_status: Option<i32>,
+    exited: bool,
+    rows: usize,
+    cols: usize,
+    /// Whether the title says the shell is at its prompt, when `prompt_title_patterns` are set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ready_at_prompt: Option<bool>,
+    /// Stable handle of a terminal pane, accepted wherever a pane id is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handle: Option<String>,
+}
+
+impl PaneSummary {
+    fn new(tab_index: usize, p: &PaneInfo) -> Self {
+        PaneSummary {
+            id: p.id,
+            is_plugin: p.is_plugin,
+            title: p.title.clone(),
+            is_focused: p.is_focused,
+            is_floating: p.is_floating,
+            is_fullscreen: p.is_fullscreen,
+            is_suppressed: p.is_suppressed,
+            tab_index,
+            command: p.terminal_command.clone(),
+            exit_status: p.exit_status,
+            exited: p.exited,
+            rows: p.pane_content_rows,
+            cols: p.pane_content_columns,
+            ready_at_prompt: None,
+            handle: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TabSummary {
+    index: usize,
+    name: String,
+    active: bool,
+    is_fullscreen: bool,
+    is_sync: bool,
+}
+
+impl TabSummary {
+    fn new(t: &TabInfo) -> Self {
+        TabSummary {
+            index: t.position,
+            name: t.name.clone(),
+            active: t.active,
+            is_fullscreen: t.is_fullscreen_active,
+            is_sync: t.is_sync_panes_active,
+        }
+    }
+}
+
+/// A random (version 4) UUID. std seeds its hashers from the host's randomness,
+/// which saves a dependency for a few ids.
+fn new_handle() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let random = || std::collections::hash_map::RandomState::new().build_hasher().finish();
+    let hi = (random() & !0xf000) | 0x4000;
+    let lo = (random() & !(0xc << 60)) | (0x8 << 60);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32,
+        (hi >> 16) & 0xffff,
+        hi & 0xffff,
+        lo >> 48,
+        lo & 0xffff_ffff_ffff
+    )
+}
+
+/// Milliseconds since the Unix epoch, 0 if the clock is unavailable
+fn unix_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Bytes as text when they are UTF-8, base64-encoded (as the error) otherwise.
+/// A `cut` buffer may end inside a UTF-8 sequence, which is dropped instead.
+fn text_or_base64(bytes: Vec<u8>, cut: bool) -> Result<String, String> {
+    use base64::Engine;
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) => return Ok(text),
+        Err(e) if cut && e.utf8_error().error_len().is_none() => {
+            let valid = e.utf8_error().valid_up_to();
+            let mut bytes = e.into_bytes();
+            bytes.truncate(valid);
+            return Ok(String::from_utf8(bytes).unwrap_or_default());
+        }
+        Err(e) => e.into_bytes(),
+    };
+    Err(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+/// Differences between two layouts from `State::snapshot`. Tabs are matched
+/// by name and panes by id, which holds within a session.
+fn diff_layouts(before: &serde_json::Value, after: &serde_json::Value) -> serde_json::Value {
+    let tab_names = |layout: &serde_json::Value| -> BTreeSet<String> {
+        layout["tabs"].as_array()
+            .map(|tabs| tabs.iter().filter_map(|t| t["name"].as_str().map(String::from)).collect())
+            .unwrap_or_default()
+    };
+    let panes = |layout: &serde_json::Value| -> BTreeMap<u64, serde_json::Value> {
+        layout["panes"].as_array()
+            .map(|panes| panes.iter().filter_map(|p| Some((p["id"].as_u64()?, p.clone()))).collect())
+            .unwrap_or_default()
+    };
+    let (tabs_before, tabs_after) = (tab_names(before), tab_names(after));
+    let (panes_before, panes_after) = (panes(before), panes(after));
+
+    let brief = |p: &serde_json::Value| serde_json::json!({"id": p["id"], "title": p["title"], "tab_index": p["tab_index"]});
+    let changed: Vec<serde_json::Value> = panes_before.iter()
+        .filter_map(|(id, was)| {
+            let now = panes_after.get(id)?;
+            let changes: serde_json::Map<String, serde_json::Value> = SNAPSHOT_PANE_FIELDS.iter()
+                .filter(|f| was[**f] != now[**f])
+                .map(|f| (f.to_string(), serde_json::json!({"from": was[*f], "to": now[*f]})))
+                .collect();
+            (!changes.is_empty()).then(|| serde_json::json!({"id": id, "changes": changes}))
+        })
+        .collect();
+    serde_json::json!({
+        "tabs_added": tabs_after.difference(&tabs_before).collect::<Vec<_>>(),
+        "tabs_removed": tabs_before.difference(&tabs_after).collect::<Vec<_>>(),
+        "panes_added": panes_after.iter().filter(|(id, _)| !panes_before.contains_key(id)).map(|(_, p)| brief(p)).collect::<Vec<_>>(),
+        "panes_removed": panes_before.iter().filter(|(id, _)| !panes_after.contains_key(id)).map(|(_, p)| brief(p)).collect::<Vec<_>>(),
+        "panes_changed": changed,
+    })
+}
+
+/// A KDL string literal
+fn kdl_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A pane title without the highlight marker and status badge the bridge puts in front
+fn strip_markers(title: &str) -> &str {
+    let title = title.strip_prefix(HIGHLIGHT_MARKER).unwrap_or(title);
+    if let Some(rest) = title.strip_prefix("⏳ ").or_else(|| title.strip_prefix("✓ ")) {
+        return rest;
+    }
+    let Some(rest) = title.strip_prefix("✗ ") else {
+        return title;
+    };
+    rest.strip_prefix("exit ")
+        .and_then(|after| after.split_once(' '))
+        .filter(|(code, _)| code.parse::<i32>().is_ok())
+        .map_or(rest, |(_, rest)| rest)
+}
+
+/// Whether a pane's title or command contains `needle_lower`
+fn pane_matches(p: &PaneInfo, needle_lower: &str) -> bool {
+    p.title.to_lowercase().contains(needle_lower)
+        || p.terminal_command.as_ref().is_some_and(|c| c.to_lowercase().contains(needle_lower))
+}
+
+/// Trace fields for an event, summarizing the manifests instead of copying them
+fn trace_event(event: &Event) -> serde_json::Value {
+    match event {
+        Event::PaneUpdate(manifest) => serde_json::json!({
+            "event": "PaneUpdate",
+            "panes": manifest.panes.values().map(|p| p.len()).sum::<usize>(),
+        }),
+        Event::TabUpdate(tabs) => serde_json::json!({"event": "TabUpdate", "tabs": tabs.len()}),
+        other => serde_json::json!({"event": format!("{:?}", other)}),
+    }
+}
+
+register_plugin!(State);
+
+impl ZellijPlugin for State {
+    fn load(&mut self, configuration: BTreeMap<String, String>) {
+        self.loaded_at = Some(Instant::now());
+        self.base_configuration = configuration;
+        self.load_state();
+        for warning in self.apply_config() {
+            eprintln!("zellij-pane-bridge: {}", warning);
+        }
+
+        self.load_snapshots();
+        self.restore_pending = self.config.restore_on_load.is_some();
+        // Older hosts answer with an empty string; `zellij --version` fills in once permitted
+        self.zellij_version = Some(get_zellij_version()).filter(|v| parse_version(v).is_some());
+        self.request_permissions(REQUIRED_PERMISSIONS);
+
+        subscribe(&[
+            EventType::PaneUpdate,
+            EventType::TabUpdate,
+            EventType::CommandPaneOpened,
+            EventType::CommandPaneExited,
+            EventType::CommandPaneReRun,
+            EventType::PermissionRequestResult,
+            EventType::RunCommandResult,
+            EventType::WebRequestResult,
+            EventType::Timer,
+            EventType::Key,
+        ]);
+    }
+
+    fn update(&mut self, event: Event) -> bool {
+        if trace::enabled() {
+            trace::record("event", trace_event(&event));
+        }
+        match event {
+            Event::PaneUpdate(pane_manifest) => {
+                self.apply_manifest(pane_manifest.panes);
+                self.advance_pending_resizes();
+                self.flush_deferred_replies(true);
+                let live: Vec<u32> = self.panes.values()
+                    .flatten()
+                    .filter(|p| !p.is_plugin)
+                    .map(|p| p.id)
+                    .collect();
+                if self.reconcile_pending {
+                    self.reconcile_recovered(&live);
+                }
+                self.track_handles(&live);
+                // A job whose pane is closed before it exits has no exit code to report
+                let closed: Vec<u32> = self.jobs.values()
+                    .filter(|j| matches!(j.state, JobState::Running | JobState::Cancelled) && j.finished_ms.is_none())
+                    .filter_map(|j| j.pane_id)
+                    .filter(|id| !live.contains(id))
+                    .collect();
+                for pane_id in closed {
+                    self.finish_job(pane_id, None);
+                }
+                self.start_queued_jobs();
+                // Write timestamps are only useful while the pane exists
+                self.last_write.retain(|id, _| live.contains(id));
+                self.badge_titles.retain(|id, _| live.contains(id));
+                // Ids are reused, so ownership ends with the pane
+                self.owned_panes.retain(|id| live.contains(id));
+                self.granted_panes.retain(|id| live.contains(id));
+                self.pane_launches.retain(|id, _| live.contains(id));
+                self.refresh_badges();
+                if self.config.auto_prune {
+                    self.command_results.retain(|id, _| live.contains(id));
+                }
+                self.persist_state();
+                self.restore_on_load();
+            }
+            Event::TabUpdate(tabs) => {
+                self.log_tab_changes(&tabs);
+                let was_active = self.tabs.iter().find(|t| t.active).map(|t| &t.name);
+                if tabs.iter().find(|t| t.active).is_some_and(|t| Some(&t.name) != was_active) {
+                    self.previous_tab = was_active.cloned();
+                }
+                self.tabs = tabs;
+                self.flush_deferred_replies(false);
+                self.restore_on_load();
+            }
+            Event::CommandPaneOpened(pane_id, context) => {
+                // Self-test panes only need to prove they can open
+                if context.contains_key(PROBE_CONTEXT_KEY) {
+                    self.finish_probe(&context, true);
+                    host!(close_terminal_pane(pane_id));
+                    return false;
+                }
+                if let Some(launch) = context.get(LAUNCH_CONTEXT_KEY).and_then(|l| serde_json::from_str(l).ok()) {
+                    self.pane_launches.insert(pane_id, launch);
+                }
+                self.record_result(pane_id, None, false);
+                self.owned_panes.insert(pane_id);
+                // A pane the bridge opens is new even when Zellij hands out an id it used before
+                let handle = context.get(HANDLE_CONTEXT_KEY).cloned().unwrap_or_else(new_handle);
+                if let Some(started) = self.opening.remove(&handle) {
+                    let job = self.jobs.values_mut().find(|j| j.handle.as_ref() == Some(&handle));
+                    let cancelled = job.as_ref().is_some_and(|j| j.state == JobState::Cancelled);
+                    if let Some(job) = job {
+                        job.pane_id = Some(pane_id);
+                    }
+                    if cancelled || self.panicked_at.is_some_and(|t| started <= t) {
+                        host!(close_terminal_pane(pane_id));
+                    }
+                }
+                self.adopt_handle(pane_id, handle);
+                self.persist_state();
+                let name = context.get("pane_name").cloned();
+                if self.config.status_badges {
+                    self.badge_titles.insert(pane_id, name);
+                    self.refresh_badges();
+                } else if let Some(name) = name {
+                    host!(rename_terminal_pane(pane_id, &name));
+                }
+            }
+            Event::RunCommandResult(_, stdout, _, context) if context.contains_key(HOST_VERSION_CONTEXT_KEY) => {
+                let version = String::from_utf8_lossy(&stdout).trim().to_string();
+                match parse_version(&version) {
+                    Some(_) => self.zellij_version = Some(version),
+                    None => eprintln!("zellij-pane-bridge: could not tell the Zellij version from {:?}", version),
+                }
+            }
+            Event::RunCommandResult(exit_code, stdout, stderr, context) => {
+                match context.get(FORWARD_CONTEXT_KEY).and_then(|id| id.parse::<u64>().ok()) {
+                    Some(id) => self.finish_forward(id, exit_code, &stdout, &stderr),
+                    None => self.finish_probe(&context, exit_code == Some(0)),
+                }
+            }
+            Event::WebRequestResult(status, headers, body, context) => {
+                if let Some(id) = context.get(FETCH_CONTEXT_KEY).and_then(|id| id.parse::<u64>().ok()) {
+                    self.finish_fetch(id, status, headers, body);
+                }
+            }
+            Event::Timer(_) => {
+                self.checkpoint_if_due();
+                self.end_highlights();
+                self.refresh_settled_summaries();
+                self.drain_write_queues();
+                self.start_queued_jobs();
+                self.flush_deferred_replies(false);
+                // Ages and countdowns of pending work in the plugin pane move on
+                return true;
+            }
+            Event::CommandPaneReRun(pane_id, _context) => {
+                self.record_result(pane_id, None, false);
+                self.refresh_badges();
+            }
+            Event::PermissionRequestResult(status) => {
+                // Zellij answers a whole prompt at once, so every pending permission shares the outcome
+                let state = match status {
+                    PermissionStatus::Granted => PermissionState::Granted,
+                    PermissionStatus::Denied => PermissionState::Denied,
+                };
+                for permission_state in self.permissions.values_mut() {
+                    if *permission_state == PermissionState::Pending {
+                        *permission_state = state;
+                    }
+                }
+                if state == PermissionState::Denied {
+                    eprintln!("zellij-pane-bridge: permissions denied, host calls will be ignored");
+                } else if !self.autostarted {
+                    if self.zellij_version.is_none() {
+                        let context = BTreeMap::from([(HOST_VERSION_CONTEXT_KEY.to_string(), String::new())]);
+                        host!(run_command(&["zellij", "--version"], context));
+                    }
+                    self.autostarted = true;
+                    self.launch_autostart();
+                    self.restore_on_load();
+                }
+            }
+            Event::CommandPaneExited(_, _, context) if context.contains_key(PROBE_CONTEXT_KEY) => {}
+            Event::Key(key) if key.is_key_without_modifier(BareKey::Char('r')) => {
+                self.set_read_only(!self.config.read_only);
+                return true;
+            }
+            Event::Key(key) if key.is_key_without_modifier(BareKey::Char('!')) => {
+                let report = self.panic();
+                eprintln!("zellij-pane-bridge: panic button pressed: {}", report);
+                return true;
+            }
+            Event::CommandPaneExited(pane_id, exit_code, _context) => {
+                self.record_result(pane_id, exit_code, true);
+                self.finish_job(pane_id, exit_code);
+                self.start_queued_jobs();
+                self.refresh_badges();
+                self.log_event(BridgeEvent { kind: "pane_exited", pane_id: Some(pane_id), exit_code, ..Default::default() });
+                if let Some(notify_pane) = self.config.notify_pane {
+                    let code = exit_code.map(|c| c.to_string()).unwrap_or_else(|| "unknown".to_string());
+                    host!(write_chars_to_pane_id(
+                        &format!("[pane-bridge] pane {} exited with code {}\n", pane_id, code),
+                        PaneId::Terminal(notify_pane),
+                    ));
+                }
+                self.flush_deferred_replies(false);
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn pipe(&mut self, pipe_message: PipeMessage) -> bool {
+        // On first command, detect and protect the focused pane (where Claude is running)
+        if self.protected_pane_id.is_none() {
+            self.detect_protected_pane();
+        }
+
+        let pipe_name = pipe_message.name.clone();
+        let raw_payload = pipe_message.payload.clone().unwrap_or_default();
+        // Binary encodings are chosen with a pipe argument: `zellij pipe --args encoding=msgpack`
+        let payload_encoding = pipe_message.args.get("encoding").map(|e| Encoding::parse(e).ok_or(e));
+        let sniffed_kdl = payload_encoding.is_none() && wire::looks_like_kdl(&raw_payload);
+        let decoded = match payload_encoding {
+            Some(Ok(encoding)) => wire::decode(encoding, &raw_payload),
+            Some(Err(name)) => Err(format!("unknown encoding '{}' (expected json, msgpack, cbor or kdl)", name)),
+            // Unflagged KDL is recognised too; what does not parse as KDL is left to the JSON parser
+            None if sniffed_kdl => Ok(wire::decode(Encoding::Kdl, &raw_payload).unwrap_or(raw_payload)),
+            None => Ok(raw_payload),
+        };
+        let decoded = match decoded {
+            Ok(payload) if sniffed_kdl || matches!(payload_encoding, Some(Ok(Encoding::Kdl))) => {
+                Ok(self.kdl_lists(&pipe_name, payload))
+            }
+            decoded => decoded,
+        };
+        let (payload, decode_error) = match decoded {
+            Ok(payload) => (payload, None),
+            Err(e) => (String::new(), Some(e)),
+        };
+        let mut envelope = Envelope::parse(&payload);
+        if envelope.encoding.is_none() {
+            envelope.encoding = payload_encoding.and_then(Result::ok);
+        }
+        let log_id = envelope.request_id.as_ref().map(|id| format!(" [{}]", id)).unwrap_or_default();
+        if self.config.verbosity >= LogLevel::Debug {
+            eprintln!("zellij-pane-bridge:{} {} {}", log_id, pipe_name, payload);
+        }
+        let replay_error = decode_error.is_none()
+            .then(|| self.check_replay(&envelope, &pipe_name, &payload))
+            .flatten();
+        let response = match (envelope.min_version, replay_error) {
+            _ if decode_error.is_some() => Response {
+                success: false,
+                error: decode_error,
+                error_code: Some(ErrorCode::InvalidArgument),
+                data: None,
+            },
+            (Some(min_version), _) if min_version > PROTOCOL_VERSION => Response {
+                success: false,
+                error: Some(format!(
+                    "Client requires protocol version {} but this pane-bridge plugin speaks version {}; update the plugin",
+                    min_version, PROTOCOL_VERSION
+                )),
+                error_code: Some(ErrorCode::VersionMismatch),
+                data: Some(serde_json::json!({"protocol_version": PROTOCOL_VERSION, "min_version": min_version})),
+            },
+            (_, Some(response)) => response,
+            _ => {
+                let started = Instant::now();
+                let budget = Duration::from_millis(self.config.max_request_ms);
+                self.request_deadline = (!budget.is_zero()).then(|| started + budget);
+                // Steps can only be reported while no older reply is held on the pipe
+                self.progress_target = match &pipe_message.source {
+                    PipeSource::Cli(pipe_id) if envelope.progress.unwrap_or(self.config.progress_updates)
+                        && !self.blocked_pipes.contains(pipe_id) => Some((pipe_id.clone(), envelope.clone())),
+                    _ => None,
+                };
+                let response = self.handle_command(&pipe_name, &payload);
+                self.request_deadline = None;
+                self.progress_target = None;
+                self.persist_state();
+                let command = self.config.aliases.get(&pipe_name).unwrap_or(&pipe_name).clone();
+                let elapsed = started.elapsed();
+                let over_budget = !budget.is_zero() && elapsed > budget;
+                if over_budget && self.config.verbosity >= LogLevel::Normal {
+                    eprintln!(
+                        "zellij-pane-bridge:{} {} took {}ms, over the {}ms budget",
+                        log_id, pipe_name, elapsed.as_millis(), budget.as_millis()
+                    );
+                }
+                let stats = self.stats.entry(command).or_default();
+                stats.count += 1;
+                stats.failures += u64::from(!response.success);
+                stats.over_budget += u64::from(over_budget);
+                stats.total_micros += elapsed.as_micros();
+                response
+            }
+        };
+        trace::record("command", serde_json::json!({
+            "command": pipe_name,
+            "request_id": envelope.request_id,
+            "success": response.success,
+            "error_code": response.error_code,
+        }));
+        if self.config.verbosity >= LogLevel::Normal && !response.success {
+            eprintln!("zellij-pane-bridge:{} {} failed: {}", log_id, pipe_name, response.error.as_deref().unwrap_or_default());
+        }
+
+        let defer_reply = self.defer_reply.take();
+        let warnings = std::mem::take(&mut self.warnings);
+        if let PipeSource::Cli(pipe_id) = pipe_message.source {
+            // A reply may not overtake one still held for the same pipe
+            let defer_reply = defer_reply.or_else(|| self.blocked_pipes.contains(&pipe_id).then_some(Waiting::Answered));
+            match defer_reply {
+                // Hold the reply until what it waits for arrives or times out
+                Some(waiting) => {
+                    let timeout = waiting.timeout_secs();
+                    if self.blocked_pipes.insert(pipe_id.clone()) {
+                        block_cli_pipe_input(&pipe_id);
+                    }
+                    let progress = envelope.progress.unwrap_or(self.config.progress_updates);
+                    self.deferred_replies.push(DeferredReply {
+                        pipe_id,
+                        command: pipe_name,
+                        received: Instant::now(),
+                        envelope,
+                        response,
+                        warnings,
+                        waiting,
+                        deadline: Instant::now() + Duration::from_secs_f64(timeout.min(MAX_WAIT_SECS)),
+                        progress,
+                        last_progress: None,
+                    });
+                    if timeout > 0.0 {
+                        host!(set_timeout(timeout));
+                    }
+                    self.report_progress();
+                }
+                None => {
+                    self.send_reply(&pipe_id, &envelope, &response, &warnings);
+                    unblock_cli_pipe_input(&pipe_id);
+                }
+            }
+        }
+        // Re-render so the counters in the plugin pane stay current
+        true
+    }
+
+    fn render(&mut self, rows: usize, cols: usize) {
+        // A pane too short for the dashboard gets the one-line view instead
+        let lines = if self.config.render == RenderMode::Compact || rows < 3 {
+            vec![self.status_line()]
+        } else {
+            self.dashboard_lines(rows)
+        };
+        for line in lines.iter().take(rows) {
+            println!("{}", fit_width(line, cols));
+        }
+    }
+}
+
+impl State {
+    /// The plugin pane's state in one line
+    fn status_line(&self) -> String {
+        let mut parts = vec!["pane-bridge".to_string()];
+        if self.config.read_only {
+            parts.push("READ-ONLY".to_string());
+        }
+        if self.config.drain {
+            parts.push("DRAINING".to_string());
+        }
+        if let Some(pid) = self.protected_pane_id {
+            parts.push(format!("protected {}", pid));
+        }
+        let total: u64 = self.stats.values().map(|s| s.count).sum();
+        let failures: u64 = self.stats.values().map(|s| s.failures).sum();
+        parts.push(format!("{} cmds ({} failed)", total, failures));
+        if let Some(oldest) = self.deferred_replies.iter().map(|r| r.received).min() {
+            parts.push(format!("{} waiting (oldest {}s)", self.deferred_replies.len(), oldest.elapsed().as_secs()));
+        }
+        let queued: usize = self.write_queues.values().map(VecDeque::len).sum();
+        if queued > 0 {
+            parts.push(format!("{} queued writes", queued));
+        }
+        let violations: u64 = self.protection_violations.values().sum();
+        if violations > 0 {
+            parts.push(format!("{} violations", violations));
+        }
+        parts.join(" · ")
+    }
+
+    /// The full dashboard, with as many of the busiest commands as `rows` leaves room for
+    fn dashboard_lines(&self, rows: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.config.show_banner {
+            lines.push("Zellij Pane Bridge - Agentic Companion v0.3.0".to_string());
+        }
+        if let Some(pid) = self.protected_pane_id {
+            lines.push(format!("Protected pane: {}", pid));
+        }
+        if self.config.read_only {
+            lines.push("READ-ONLY: commands that change the session are rejected".to_string());
+        }
+        if self.config.drain {
+            lines.push("DRAINING: new runs, tabs and writes are rejected".to_string());
+        }
+        if self.config.show_banner {
+            lines.push("r: toggle read-only   !: panic (close bridge panes, go read-only)".to_string());
+        }
+        if let Some(last) = self.protection_log.back() {
+            let pane = last.pane_id.map(|id| format!(" on pane {}", id)).unwrap_or_default();
+            lines.push(format!(
+                "Protection violations: {} (last: {}{})",
+                self.protection_violations.values().sum::<u64>(), last.command, pane
+            ));
+        }
+        lines.extend(self.pending_work_lines());
+        let total: u64 = self.stats.values().map(|s| s.count).sum();
+        if total > 0 {
+            let failures: u64 = self.stats.values().map(|s| s.failures).sum();
+            lines.push(format!("Commands: {} ({} failed), waiting replies: {}", total, failures, self.deferred_replies.len()));
+            let mut busiest: Vec<_> = self.stats.iter().collect();
+            busiest.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.count));
+            let room = rows.saturating_sub(lines.len()).min(5);
+            for (name, stats) in busiest.iter().take(room) {
+                lines.push(format!("  {:<20} {:>6} {:>4} failed", name, stats.count, stats.failures));
+            }
+        }
+        lines
+    }
+
+    /// Deferred work a client may be waiting on: held replies, queued writes,
+    /// resizes still converging and pending timers, each with its age or due time
+    fn pending_work_lines(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut lines = Vec::new();
+        for reply in &self.deferred_replies {
+            lines.push(format!(
+                "  waiting  {:<14} {:>4}s  for {} (gives up in {}s)",
+                reply.command,
+                now.duration_since(reply.received).as_secs(),
+                reply.waiting.describe(),
+                reply.deadline.saturating_duration_since(now).as_secs(),
+            ));
+        }
+        let mut queued: Vec<_> = self.write_queues.iter().filter(|(_, q)| !q.is_empty()).collect();
+        queued.sort_by_key(|(id, _)| **id);
+        for (pane_id, queue) in queued {
+            lines.push(format!("  queued   {} write(s) to pane {}", queue.len(), pane_id));
+        }
+        let now_ms = unix_ms();
+        for (job_id, job) in self.jobs.iter().filter(|(_, j)| j.state == JobState::Queued) {
+            lines.push(format!(
+                "  queued   job {} {:>4}s  {} (priority {}, max_running {})",
+                job_id,
+                now_ms.saturating_sub(job.submitted_ms) / 1000,
+                job.command().0,
+                job.priority,
+                self.config.max_running,
+            ));
+        }
+        let mut resizing: Vec<_> = self.pending_resizes.keys().collect();
+        resizing.sort_unstable();
+        for pane_id in resizing {
+            lines.push(format!("  resizing pane {}", pane_id));
+        }
+        for (pane_id, (_, ends)) in &self.highlights {
+            lines.push(format!("  timer    highlight of pane {} ends in {}s", pane_id, ends.saturating_duration_since(now).as_secs()));
+        }
+        if lines.is_empty() {
+            return lines;
+        }
+        lines.insert(0, "Pending:".to_string());
+        lines
+    }
+
+    fn send_reply(&mut self, pipe_id: &str, envelope: &Envelope, response: &Response, warnings: &[String]) {
+        let verbosity = envelope.verbosity.unwrap_or(self.config.response_verbosity);
+        let data = response.data.as_ref().filter(|_| verbosity != Verbosity::Minimal);
+        let first_page = data.and_then(|d| self.paginate(d));
+        if verbosity == Verbosity::Full {
+            self.refresh_summaries();
+        }
+        let reply = Reply {
+            success: response.success,
+            error: response.error.as_ref(),
+            error_code: response.error_code,
+            paginated: first_page.is_some(),
+            data: first_page.map(Cow::Owned).or(data.map(Cow::Borrowed)),
+            warnings,
+            request_id: envelope.request_id.as_ref(),
+            protocol_version: PROTOCOL_VERSION,
+            snapshot: (verbosity == Verbosity::Full).then(|| self.snapshot()),
+        };
+        let response_json = wire::encode(envelope.encoding.unwrap_or_default(), &reply)
+            .unwrap_or_else(|e| format!(r#"{{"success":false,"error":"{}"}}"#, e));
+        // One reply per line, so clients streaming requests over one pipe can tell them apart
+        cli_pipe_output(pipe_id, &format!("{}\n", response_json));
+    }
+
+    /// Unblock a CLI pipe once no reply is held for it any more
+    fn release_pipe(&mut self, pipe_id: &str) {
+        if self.deferred_replies.iter().any(|r| r.pipe_id == pipe_id) || !self.blocked_pipes.remove(pipe_id) {
+            return;
+        }
+        unblock_cli_pipe_input(pipe_id);
+    }
+
+    /// Split data that serializes larger than `max_reply_bytes` into pages, keep all
+    /// but the first for `next_page`, and return the first. Arrays are split by
+    /// items so every page parses on its own; anything else is split as JSON text.
+    fn paginate(&mut self, data: &serde_json::Value) -> Option<serde_json::Value> {
+        let limit = self.config.max_reply_bytes;
+        let text = serde_json::to_string(data).ok()?;
+        if limit == 0 || text.len() <= limit {
+            return None;
+        }
+
+        let mut pages: Vec<serde_json::Value> = Vec::new();
+        if let Some(items) = data.as_array() {
+            let mut page = Vec::new();
+            let mut page_len = 0;
+            for item in items {
+                let item_len = serde_json::to_string(item).map(|t| t.len()).unwrap_or(0) + 1;
+                if !page.is_empty() && page_len + item_len > limit {
+                    pages.push(serde_json::json!({"items": std::mem::take(&mut page)}));
+                    page_len = 0;
+                }
+                page.push(item.clone());
+                page_len += item_len;
+            }
+            pages.push(serde_json::json!({"items": page}));
+        } else {
+            let mut rest = text.as_str();
+            while !rest.is_empty() {
+                let mut cut = limit.min(rest.len());
+                while !rest.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                let (chunk, tail) = rest.split_at(cut);
+                pages.push(serde_json::json!({"chunk": chunk}));
+                rest = tail;
+            }
+        }
+
+        self.page_counter += 1;
+        let token = format!("page-{}", self.page_counter);
+        let total = pages.len();
+        let mut pages: VecDeque<serde_json::Value> = pages.into_iter()
+            .enumerate()
+            .map(|(i, mut page)| {
+                page["page"] = serde_json::json!(i + 1);
+                page["pages"] = serde_json::json!(total);
+                page["next"] = if i + 1 < total { serde_json::json!(token) } else { serde_json::Value::Null };
+                page
+            })
+            .collect();
+        let first = pages.pop_front();
+        self.pending_pages.insert(token.clone(), pages);
+        self.pending_page_order.push_back(token);
+        // Clients that never finish reading should not pin results forever
+        while self.pending_page_order.len() > MAX_PENDING_PAGINATIONS {
+            if let Some(oldest) = self.pending_page_order.pop_front() {
+                self.pending_pages.remove(&oldest);
+            }
+        }
+        first
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        let panes = self.all_summaries();
+        let tabs: Vec<TabSummary> = self.tabs.iter().map(TabSummary::new).collect();
+        serde_json::json!({"tabs": tabs, "panes": panes})
+    }
+
+    /// Write the known tabs and panes as a KDL layout with Zellij's default
+    /// tab and status bars. Tabs without a pane worth keeping get a plain one.
+    fn layout_kdl(&self, include_all: bool) -> (String, usize) {
+        let mut kdl = String::from("layout {\n");
+        kdl.push_str("    default_tab_template {\n");
+        kdl.push_str("        pane size=1 borderless=true {\n            plugin location=\"zellij:tab-bar\"\n        }\n");
+        kdl.push_str("        children\n");
+        kdl.push_str("        pane size=2 borderless=true {\n            plugin location=\"zellij:status-bar\"\n        }\n");
+        kdl.push_str("    }\n");
+        let mut exported = 0;
+        let mut tabs: Vec<&TabInfo> = self.tabs.iter().collect();
+        tabs.sort_by_key(|t| t.position);
+        for tab in tabs {
+            let focus = if tab.active { " focus=true" } else { "" };
+            kdl.push_str(&format!("    tab name={}{} {{\n", kdl_string(&tab.name), focus));
+            let panes: Vec<&PaneInfo> = self.panes.get(&tab.position)
+                .map(|panes| {
+                    panes.iter()
+                        .filter(|p| !p.is_plugin && !p.is_suppressed)
+                        .filter(|p| include_all || self.owned_panes.contains(&p.id))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let (floating, tiled): (Vec<&PaneInfo>, Vec<&PaneInfo>) = panes.into_iter().partition(|p| p.is_floating);
+            if tiled.is_empty() {
+                kdl.push_str("        pane\n");
+            }
+            for p in &tiled {
+                kdl.push_str(&self.pane_kdl(p, "        "));
+            }
+            if !floating.is_empty() {
+                kdl.push_str("        floating_panes {\n");
+                for p in &floating {
+                    kdl.push_str(&self.pane_kdl(p, "            "));
+                }
+                kdl.push_str("        }\n");
+            }
+            exported += tiled.len() + floating.len();
+            kdl.push_str("    }\n");
+        }
+        kdl.push_str("}\n");
+        (kdl, exported)
+    }
+
+    fn pane_kdl(&self, p: &PaneInfo, indent: &str) -> String {
+        let mut line = format!("{}pane", indent);
+        let launch = self.pane_launches.get(&p.id).cloned().or_else(|| {
+            // The command line is all zellij reports for other panes, so split it the simple way
+            let mut words = p.terminal_command.as_deref()?.split_whitespace().map(String::from);
+            Some(Launch { command: words.next()?, args: words.collect(), cwd: None, env: BTreeMap::new() })
+        });
+        if let Some(launch) = &launch {
+            line.push_str(&format!(" command={}", kdl_string(&launch.command)));
+        }
+        let name = strip_markers(&p.title);
+        if !name.is_empty() {
+            line.push_str(&format!(" name={}", kdl_string(name)));
+        }
+        if let Some(cwd) = launch.as_ref().and_then(|l| l.cwd.as_deref()) {
+            line.push_str(&format!(" cwd={}", kdl_string(cwd)));
+        }
+        let args: Vec<String> = launch.iter().flat_map(|l| &l.args).map(|a| kdl_string(a)).collect();
+        if args.is_empty() {
+            line.push('\n');
+        } else {
+            line.push_str(&format!(" {{\n{}    args {}\n{}}}\n", indent, args.join(" "), indent));
+        }
+        line
+    }
+
+    fn load_snapshots(&mut self) {
+        if let Some(snapshots) = read_with_backup(SNAPSHOTS_PATH) {
+            self.snapshots = snapshots;
+        }
+    }
+
+    /// Pick up the state a previous instance of the plugin left behind. Pane
+    /// entries are only trusted from the same Zellij server, and even then are
+    /// checked against the first manifest before they count.
+    fn load_state(&mut self) {
+        let state: Option<PersistedState> = read_with_backup(STATE_PATH);
+        let checkpoint: Option<Checkpoint> = read_with_backup(CHECKPOINT_PATH);
+        if state.is_none() && checkpoint.is_none() {
+            return;
+        }
+        let zellij_pid = get_plugin_ids().zellij_pid;
+        let same_server = state.as_ref().is_some_and(|s| s.zellij_pid == zellij_pid);
+        if let Some(state) = state {
+            self.config_overrides = state.config_overrides;
+            self.recordings = state.recordings;
+            self.templates = state.templates;
+            self.tab_templates = state.tab_templates;
+            self.timings = state.timings;
+            if same_server {
+                self.groups = state.groups;
+                self.protected_pane_id = state.protected_pane_id;
+                self.owned_panes = state.owned_panes;
+                self.granted_panes = state.granted_panes;
+                self.pane_launches = state.pane_launches.into_iter().collect();
+                self.pane_handles = state.pane_handles.into_iter().collect();
+                self.reconcile_pending = true;
+            }
+        }
+        let checkpoint_ms = checkpoint.as_ref().map(|c| c.ts_ms);
+        if let Some(checkpoint) = checkpoint {
+            // Captured output lives in the host folder, so jobs outlast the server
+            self.event_cursor = checkpoint.event_cursor;
+            self.job_counter = checkpoint.job_counter;
+            self.captured_jobs = checkpoint.captured_jobs;
+            // Older checkpoints numbered captures with the job counter
+            let last_capture = self.captured_jobs.keys().next_back().copied().unwrap_or_default();
+            self.capture_counter = checkpoint.capture_counter.max(last_capture);
+            // Queued jobs start on any server; running ones went down with theirs
+            self.jobs = checkpoint.jobs;
+            if checkpoint.zellij_pid != zellij_pid {
+                for job in self.jobs.values_mut().filter(|j| j.state == JobState::Running) {
+                    job.state = JobState::Failed;
+                    job.finished_ms = Some(checkpoint.ts_ms);
+                    job.error = Some("Zellij server restarted while the job ran".to_string());
+                }
+            }
+            if checkpoint.zellij_pid == zellij_pid {
+                self.command_results = checkpoint.command_results.into_iter().collect();
+                self.result_counter = checkpoint.result_counter;
+                self.write_queues = checkpoint.write_queues.into_iter().collect();
+                self.reconcile_pending = true;
+            }
+        }
+        self.recovery = Some(serde_json::json!({
+            "same_server": same_server,
+            "recordings": self.recordings.len(),
+            "templates": self.templates.len() + self.tab_templates.len(),
+            "config_overrides": self.config_overrides.len(),
+            "checkpoint_ms": checkpoint_ms,
+            "captured_jobs": self.captured_jobs.len(),
+            "queued_jobs": self.jobs.values().filter(|j| j.state == JobState::Queued).count(),
+            "queued_writes": self.write_queues.values().map(VecDeque::len).sum::<usize>(),
+        }));
+    }
+
+    /// Drop restored entries for panes that did not survive the reload
+    fn reconcile_recovered(&mut self, live: &[u32]) {
+        self.reconcile_pending = false;
+        let mut dropped: BTreeSet<u32> = BTreeSet::new();
+        dropped.extend(self.owned_panes.iter().chain(&self.granted_panes).filter(|id| !live.contains(id)));
+        dropped.extend(self.pane_launches.keys().filter(|id| !live.contains(id)));
+        dropped.extend(self.protected_pane_id.filter(|id| !live.contains(id)));
+        if self.protected_pane_id.is_some_and(|id| !live.contains(&id)) {
+            self.protected_pane_id = None;
+        }
+        for members in self.groups.values_mut() {
+            dropped.extend(members.iter().filter(|id| !live.contains(id)));
+            members.retain(|id| live.contains(id));
+        }
+        let emptied: Vec<String> = self.groups.iter().filter(|(_, m)| m.is_empty()).map(|(n, _)| n.clone()).collect();
+        self.groups.retain(|_, members| !members.is_empty());
+        self.owned_panes.retain(|id| live.contains(id));
+        self.granted_panes.retain(|id| live.contains(id));
+        self.pane_launches.retain(|id, _| live.contains(id));
+        self.write_queues.retain(|id, _| live.contains(id));
+        if let Some(obj) = self.recovery.as_mut().and_then(|r| r.as_object_mut()) {
+            obj.insert("owned_panes".to_string(), serde_json::json!(self.owned_panes));
+            obj.insert("dropped_panes".to_string(), serde_json::json!(dropped));
+            obj.insert("dropped_groups".to_string(), serde_json::json!(emptied));
+        }
+    }
+
+    /// Write the bridge state to STATE_PATH when it changed since the last write
+    fn persist_state(&mut self) {
+        let state = PersistedState {
+            zellij_pid: get_plugin_ids().zellij_pid,
+            config_overrides: self.config_overrides.clone(),
+            recordings: self.recordings.clone(),
+            templates: self.templates.clone(),
+            tab_templates: self.tab_templates.clone(),
+            groups: self.groups.clone(),
+            protected_pane_id: self.protected_pane_id,
+            owned_panes: self.owned_panes.clone(),
+            granted_panes: self.granted_panes.clone(),
+            pane_launches: self.pane_launches.iter().map(|(id, l)| (*id, l.clone())).collect(),
+            pane_handles: self.pane_handles.iter().map(|(id, h)| (*id, h.clone())).collect(),
+            timings: self.timings.clone(),
+        };
+        let Ok(text) = serde_json::to_string(&state) else { return };
+        if text == self.persisted {
+            return;
+        }
+        match write_with_backup(STATE_PATH, &text) {
+            Ok(()) => self.persisted = text,
+            Err(e) => eprintln!("zellij-pane-bridge: {}", e),
+        }
+    }
+
+    /// Start the checkpoint timer if `checkpoint_secs` is set and none is running
+    fn schedule_checkpoint(&mut self) {
+        if self.config.checkpoint_secs > 0 && self.next_checkpoint.is_none() {
+            let secs = self.config.checkpoint_secs;
+            self.next_checkpoint = Some(Instant::now() + Duration::from_secs(secs));
+            host!(set_timeout(secs as f64));
+        }
+    }
+
+    /// Write a checkpoint when its timer is up, then start the next one. Other
+    /// timers share the event, so a slightly early one still counts.
+    fn checkpoint_if_due(&mut self) {
+        let Some(due) = self.next_checkpoint else { return };
+        if Instant::now() + Duration::from_millis(100) < due {
+            return;
+        }
+        self.next_checkpoint = None;
+        if self.config.checkpoint_secs == 0 {
+            return;
+        }
+        let checkpoint = Checkpoint {
+            zellij_pid: get_plugin_ids().zellij_pid,
+            ts_ms: unix_ms(),
+            command_results: self.command_results.iter().map(|(id, r)| (*id, r.clone())).collect(),
+            result_counter: self.result_counter,
+            captured_jobs: self.captured_jobs.clone(),
+            capture_counter: self.capture_counter,
+            jobs: self.jobs.clone(),
+            job_counter: self.job_counter,
+            write_queues: self.write_queues.iter().map(|(id, q)| (*id, q.clone())).collect(),
+            event_cursor: self.event_cursor,
+        };
+        let written = serde_json::to_string(&checkpoint)
+            .map_err(|e| e.to_string())
+            .and_then(|text| write_with_backup(CHECKPOINT_PATH, &text));
+        if let Err(e) = written {
+            eprintln!("zellij-pane-bridge: {}", e);
+        }
+        self.schedule_checkpoint();
+    }
+
+    fn save_snapshots(&self) -> Result<(), String> {
+        let text = serde_json::to_string(&self.snapshots).map_err(|e| e.to_string())?;
+        write_with_backup(SNAPSHOTS_PATH, &text)
+    }
+
+    fn snapshot_not_found(&self, name: &str) -> Response {
+        Response {
+            success: false,
+            error: Some(format!("No snapshot named '{}'", name)),
+            error_code: Some(ErrorCode::SnapshotNotFound),
+            data: Some(serde_json::json!({"snapshots": self.snapshots.keys().collect::<Vec<_>>()})),
+        }
+    }
+
+    /// Answer held replies whose wait is over: pane-state replies once a fresh
+    /// manifest arrives, the others when their condition holds, and any reply
+    /// past its deadline.
+    fn flush_deferred_replies(&mut self, manifest_updated: bool) {
+        let now = Instant::now();
+        let probes_done = !self.probes.values().any(|s| *s == ProbeStatus::Pending);
+        let quiet = self.running_commands().is_empty();
+        let mut pending = std::mem::take(&mut self.deferred_replies);
+        // A pane that changed starts its quiet period over, with a timer for its new end
+        for reply in &mut pending {
+            if let Waiting::Quiet { pane_id, quiet_secs, fingerprint, since, .. } = &mut reply.waiting {
+                let current = self.pane_fingerprint(*pane_id);
+                if current != *fingerprint {
+                    *fingerprint = current;
+                    *since = now;
+                    host!(set_timeout(*quiet_secs));
+                }
+            }
+        }
+        let forwards = &self.forwards;
+        let fetches = &self.fetches;
+        let (ready, waiting): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .enumerate()
+            .partition(|(_, r)| r.deadline <= now || match &r.waiting {
+                Waiting::Answered => true,
+                Waiting::PaneState(_) => manifest_updated,
+                Waiting::SelfTest => probes_done,
+                Waiting::Quiesce { .. } => quiet,
+                Waiting::Forward { id, .. } => forwards.get(id).is_some_and(Option::is_some),
+                Waiting::Fetch { id, .. } => fetches.get(id).is_some_and(Option::is_some),
+                Waiting::PaneAppears { pattern, .. } => self.matching_pane(pattern).is_some(),
+                Waiting::TitleChange { pane_id, from, pattern, .. } => {
+                    self.title_changed(*pane_id, from, pattern.as_deref())
+                }
+                Waiting::Event { filter, since_cursor, .. } => self.next_event(filter, *since_cursor).is_some(),
+                Waiting::Shutdown { pane_id, .. } => self.pane_finished(*pane_id),
+                Waiting::Quiet { quiet_secs, since, .. } => now.duration_since(*since).as_secs_f64() >= *quiet_secs,
+                Waiting::Prompt { pane_id, .. } => self.pane_at_prompt(*pane_id) != Some(false),
+                Waiting::Jobs { label, .. } => self.labelled_jobs(label).all(|(_, j)| j.finished()),
+            });
+        // Replies go out in request order per pipe, so one whose pipe has an
+        // older reply still waiting is answered now but sent after it
+        let mut first_waiting: HashMap<String, usize> = HashMap::new();
+        for (index, r) in &waiting {
+            first_waiting.entry(r.pipe_id.clone()).or_insert(*index);
+        }
+        let mut held = waiting;
+        let mut answered = Vec::new();
+        for (index, mut reply) in ready {
+            self.answer_reply(&mut reply, now, quiet);
+            if first_waiting.get(&reply.pipe_id).is_some_and(|&first| first < index) {
+                reply.waiting = Waiting::Answered;
+                held.push((index, reply));
+            } else {
+                answered.push(reply);
+            }
+        }
+        held.sort_by_key(|(index, _)| *index);
+        let mut held: Vec<DeferredReply> = held.into_iter().map(|(_, r)| r).collect();
+        // Replies held while answering come after the ones already waiting
+        held.append(&mut self.deferred_replies);
+        self.deferred_replies = held;
+        let mut released = BTreeSet::new();
+        for reply in answered {
+            self.send_reply(&reply.pipe_id, &reply.envelope, &reply.response, &reply.warnings);
+            released.insert(reply.pipe_id);
+        }
+        for pipe_id in released {
+            self.release_pipe(&pipe_id);
+        }
+        self.report_progress();
+    }
+
+    /// Send a progress line for each held reply that has news and is next on its pipe
+    fn report_progress(&mut self) {
+        let mut seen_pipes = BTreeSet::new();
+        for index in 0..self.deferred_replies.len() {
+            let reply = &self.deferred_replies[index];
+            if !seen_pipes.insert(reply.pipe_id.clone()) || !reply.progress {
+                continue;
+            }
+            let Some(text) = self.progress_of(&reply.waiting).filter(|t| reply.last_progress.as_ref() != Some(t)) else {
+                continue;
+            };
+            self.send_progress(&reply.pipe_id, &reply.envelope, &text);
+            self.deferred_replies[index].last_progress = Some(text);
+        }
+    }
+
+    /// How far along a held reply's wait is, for the waits that move in steps
+    fn progress_of(&self, waiting: &Waiting) -> Option<String> {
+        match waiting {
+            Waiting::Quiesce { panes, .. } => {
+                let running = self.running_commands();
+                let total = panes.iter().chain(&running).collect::<BTreeSet<_>>().len();
+                Some(format!("{}/{} command panes finished", total - running.len(), total))
+            }
+            Waiting::SelfTest => {
+                let done = self.probes.values().filter(|s| **s != ProbeStatus::Pending).count();
+                Some(format!("{}/{} probes finished", done, self.probes.len()))
+            }
+            Waiting::Shutdown { pane_id, .. } => Some(format!("pane {} interrupted, waiting for it to exit", pane_id)),
+            Waiting::Jobs { label, .. } => {
+                let (done, total) = self.labelled_jobs(label).fold((0, 0), |(d, t), (_, j)| (d + j.finished() as usize, t + 1));
+                Some(format!("{}/{} jobs labelled '{}' finished", done, total, label))
+            }
+            _ => None,
+        }
+    }
+
+    fn send_progress(&self, pipe_id: &str, envelope: &Envelope, text: &str) {
+        let line = Progress { progress: text, request_id: envelope.request_id.as_ref() };
+        if let Ok(encoded) = wire::encode(envelope.encoding.unwrap_or_default(), &line) {
+            cli_pipe_output(pipe_id, &format!("{}\n", encoded));
+        }
+    }
+
+    /// Fill in the final outcome of a held reply whose wait is over
+    fn answer_reply(&mut self, reply: &mut DeferredReply, now: Instant, quiet: bool) {
+        let (key, value) = match &reply.waiting {
+            Waiting::Answered => return,
+            &Waiting::PaneState(pane_id) => {
+                let state = match self.find_pane(pane_id) {
+                    Some((tab_idx, p)) => serde_json::to_value(PaneSummary::new(tab_idx, p)).unwrap_or_default(),
+                    None => serde_json::json!({"id": pane_id, "closed": true}),
+                };
+                ("state", state)
+            }
+            Waiting::SelfTest => {
+                for status in self.probes.values_mut() {
+                    if *status == ProbeStatus::Pending {
+                        *status = ProbeStatus::Timeout;
+                    }
+                }
+                ("probes", serde_json::to_value(&self.probes).unwrap_or_default())
+            }
+            Waiting::Jobs { label, timeout_secs } => {
+                let jobs: Vec<serde_json::Value> = self.labelled_jobs(label).map(|(id, j)| j.to_json(*id)).collect();
+                if !self.labelled_jobs(label).all(|(_, j)| j.finished()) {
+                    reply.response.success = false;
+                    reply.response.error = Some(format!("Jobs labelled '{}' still unfinished after {}s", label, timeout_secs));
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                }
+                ("jobs", jobs.into())
+            }
+            &Waiting::Quiesce { timeout_secs, .. } => {
+                if !quiet {
+                    reply.response.success = false;
+                    reply.response.error = Some(format!(
+                        "Command panes still running after {}s", timeout_secs
+                    ));
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                }
+                ("summary", self.quiesce_summary())
+            }
+            &Waiting::Forward { id, timeout_secs } => match self.forwards.remove(&id).flatten() {
+                Some(remote) => {
+                    // The remote reply decides the outcome, the relay only carries it
+                    reply.response.success = remote.get("success").and_then(|v| v.as_bool()).unwrap_or(false);
+                    reply.response.error = remote.get("error").and_then(|v| v.as_str()).map(str::to_string);
+                    ("response", remote)
+                }
+                None => {
+                    reply.response.success = false;
+                    reply.response.error = Some(format!("No reply from the remote bridge within {}s", timeout_secs));
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                    ("response", serde_json::Value::Null)
+                }
+            },
+            &Waiting::Fetch { id, timeout_secs } => match self.fetches.remove(&id).flatten() {
+                Some(fetched) => ("response", fetched),
+                None => {
+                    reply.response.success = false;
+                    reply.response.error = Some(format!("No response within {}s", timeout_secs));
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                    ("response", serde_json::Value::Null)
+                }
+            },
+            Waiting::PaneAppears { pattern, timeout_secs } => match self.matching_pane(pattern) {
+                Some((tab_idx, p)) => ("pane", serde_json::to_value(PaneSummary::new(tab_idx, p)).unwrap_or_default()),
+                None => {
+                    reply.response.success = false;
+                    reply.response.error = Some(format!("No pane matching '{}' appeared within {}s", pattern, timeout_secs));
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                    ("pane", serde_json::Value::Null)
+                }
+            },
+            Waiting::TitleChange { pane_id, from, pattern, timeout_secs } => {
+                let pane_id = *pane_id;
+                if !self.title_changed(pane_id, from, pattern.as_deref()) {
+                    reply.response.success = false;
+                    reply.response.error = Some(match pattern {
+                        Some(pattern) => format!(
+                            "Title of pane {} did not change to match '{}' within {}s", pane_id, pattern, timeout_secs
+                        ),
+                        None => format!("Title of pane {} did not change within {}s", pane_id, timeout_secs),
+                    });
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                }
+                let state = match self.find_pane(pane_id) {
+                    Some((tab_idx, p)) => serde_json::to_value(PaneSummary::new(tab_idx, p)).unwrap_or_default(),
+                    None => serde_json::json!({"id": pane_id, "closed": true}),
+                };
+                ("pane", state)
+            }
+            &Waiting::Shutdown { pane_id, timeout_secs } => {
+                let path = if self.pane_finished(pane_id) {
+                    "interrupted"
+                } else {
+                    eprintln!("zellij-pane-bridge: pane {} still running after {}s, closing it", pane_id, timeout_secs);
+                    "forced"
+                };
+                if self.find_pane(pane_id).is_some() {
+                    host!(close_terminal_pane(pane_id));
+                }
+                ("path", path.into())
+            }
+            &Waiting::Quiet { pane_id, quiet_secs, since, timeout_secs, .. } => {
+                if now.duration_since(since).as_secs_f64() < quiet_secs {
+                    reply.response.success = false;
+                    reply.response.error = Some(format!(
+                        "Pane {} did not stay unchanged for {}s within {}s", pane_id, quiet_secs, timeout_secs
+                    ));
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                }
+                let state = match self.find_pane(pane_id) {
+                    Some((tab_idx, p)) => serde_json::to_value(PaneSummary::new(tab_idx, p)).unwrap_or_default(),
+                    None => serde_json::json!({"id": pane_id, "closed": true}),
+                };
+                ("pane", state)
+            }
+            &Waiting::Prompt { pane_id, timeout_secs } => {
+                if self.pane_at_prompt(pane_id) == Some(false) {
+                    reply.response.success = false;
+                    reply.response.error = Some(format!("Pane {} did not reach its prompt within {}s", pane_id, timeout_secs));
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                }
+                let state = match self.find_pane(pane_id) {
+                    Some((tab_idx, p)) => serde_json::to_value(PaneSummary::new(tab_idx, p)).unwrap_or_default(),
+                    None => serde_json::json!({"id": pane_id, "closed": true}),
+                };
+                ("pane", state)
+            }
+            Waiting::Event { filter, since_cursor, timeout_secs } => match self.next_event(filter, *since_cursor) {
+                Some(event) => ("event", serde_json::to_value(event).unwrap_or_default()),
+                None => {
+                    reply.response.success = false;
+                    reply.response.error = Some(format!("No matching event within {}s", timeout_secs));
+                    reply.response.error_code = Some(ErrorCode::Timeout);
+                    ("event", serde_json::Value::Null)
+                }
+            },
+        };
+        if let Some(data) = reply.response.data.as_mut().and_then(|d| d.as_object_mut()) {
+            data.insert(key.to_string(), value);
+        }
+    }
+
+    /// Whether a pane is at its prompt, `None` once it is gone
+    fn pane_at_prompt(&self, pane_id: u32) -> Option<bool> {
+        self.find_pane(pane_id).map(|(_, p)| self.at_prompt(p).unwrap_or(false))
+    }
+
+    /// What `wait_for_quiet` watches of a pane: its title, geometry and exit status
+    fn pane_fingerprint(&self, pane_id: u32) -> serde_json::Value {
+        let Some((tab_idx, p)) = self.find_pane(pane_id) else {
+            return serde_json::Value::Null;
+        };
+        let result = self.command_results.get(&pane_id);
+        serde_json::json!([
+            tab_idx,
+            p.title,
+            [p.pane_x, p.pane_y, p.pane_columns, p.pane_rows],
+            p.is_floating,
+            p.is_fullscreen,
+            result.map(|r| (r.exited, r.exit_code)),
+        ])
+    }
+
+    /// A command result with what else the bridge knows about its pane
+    fn result_json(&self, result: &CommandResult) -> serde_json::Value {
+        let mut json = serde_json::to_value(result).unwrap_or_default();
+        let pane = self.find_pane(result.pane_id).map(|(_, p)| p);
+        json["pane_open"] = pane.is_some().into();
+        json["title"] = pane.map(|p| strip_markers(&p.title).to_string()).into();
+        json["handle"] = self.pane_handles.get(&result.pane_id).cloned().into();
+        if let (Some(started), Some(exited)) = (result.started_ms, result.exited_ms) {
+            json["duration_ms"] = exited.saturating_sub(started).into();
+        }
+        json
+    }
+
+    /// Store a command pane's state, evicting the stalest result (exited ones
+    /// first) once `max_command_results` is exceeded
+    fn record_result(&mut self, pane_id: u32, exit_code: Option<i32>, exited: bool) {
+        self.result_counter += 1;
+        let now = unix_ms();
+        let previous = self.command_results.get(&pane_id);
+        let command = self.find_pane(pane_id)
+            .and_then(|(_, p)| p.terminal_command.clone())
+            .or_else(|| self.pane_launches.get(&pane_id).map(Launch::line))
+            .or_else(|| previous.and_then(|r| r.command.clone()));
+        // An exit ends the run that started last; anything else starts a new one
+        let started_ms = if exited { previous.and_then(|r| r.started_ms) } else { Some(now) };
+        // A repeated exit event must not count the same run twice
+        let finished_run = exited && !previous.is_some_and(|r| r.exited);
+        if let (true, Some(command), Some(started)) = (finished_run, &command, started_ms) {
+            self.timings.entry(command.clone()).or_default().add(now.saturating_sub(started), exit_code == Some(0), now);
+            if self.timings.len() > TIMINGS_CAPACITY {
+                let stalest = self.timings.iter().min_by_key(|(_, t)| t.last_ms).map(|(c, _)| c.clone());
+                if let Some(stalest) = stalest {
+                    self.timings.remove(&stalest);
+                }
+            }
+        }
+        self.command_results.insert(pane_id, CommandResult {
+            pane_id,
+            exit_code,
+            exited,
+            command,
+            started_ms,
+            exited_ms: exited.then_some(now),
+            updated: self.result_counter,
+        });
+        let max = self.config.max_command_results;
+        while max > 0 && self.command_results.len() > max {
+            let stalest = self.command_results.values()
+                .min_by_key(|r| (!r.exited, r.updated))
+                .map(|r| r.pane_id);
+            match stalest {
+                Some(id) => self.command_results.remove(&id),
+                None => break,
+            };
+        }
+    }
+
+    /// Whether a pane's command has exited or the pane is gone
+    fn pane_finished(&self, pane_id: u32) -> bool {
+        self.command_results.get(&pane_id).is_some_and(|r| r.exited)
+            || (!self.panes.is_empty() && self.find_pane(pane_id).is_none())
+    }
+
+    /// Bridge-owned command panes that have not exited. Panes gone from the
+    /// manifest without an exit event count as finished.
+    fn running_commands(&self) -> Vec<u32> {
+        let mut running: Vec<u32> = self.command_results.values()
+            .filter(|r| !r.exited && (self.panes.is_empty() || self.find_pane(r.pane_id).is_some()))
+            .map(|r| r.pane_id)
+            .collect();
+        running.sort_unstable();
+        running
+    }
+
+    /// Bridge-owned commands counted against `max_running`: running command
+    /// panes plus runs started recently whose pane has not opened yet
+    fn running_load(&self) -> usize {
+        let now = Instant::now();
+        let opening = self.opening.values().filter(|t| now.duration_since(**t) < OPENING_GRACE).count();
+        self.running_commands().len() + opening
+    }
+
+    fn saturated(&self) -> bool {
+        self.config.max_running > 0 && self.running_load() >= self.config.max_running
+    }
+
+    fn enqueue_job(&mut self, run: Command, priority: i32, defer: bool, label: Option<String>) -> u64 {
+        self.job_counter += 1;
+        let submitted_ms = unix_ms();
+        // Job ids restart when the checkpoint is lost, the timestamp keeps older directories apart
+        let artifacts = self.config.artifacts_root.as_deref().map(|root| {
+            let root = if root.starts_with('/') {
+                root.to_string()
+            } else {
+                format!("{}/{}", self.host_folder().trim_end_matches('/'), root)
+            };
+            format!("{}/{}-{}", root.trim_end_matches('/'), submitted_ms, self.job_counter)
+        });
+        self.jobs.insert(self.job_counter, Job {
+            run,
+            state: JobState::Queued,
+            priority,
+            defer,
+            label,
+            artifacts,
+            submitted_ms,
+            started_ms: None,
+            finished_ms: None,
+            handle: None,
+            pane_id: None,
+            exit_code: None,
+            error: None,
+            error_code: None,
+        });
+        self.job_counter
+    }
+
+    fn labelled_jobs<'a>(&'a self, label: &'a str) -> impl Iterator<Item = (&'a u64, &'a Job)> {
+        self.jobs.iter().filter(move |(_, j)| j.label.as_deref() == Some(label))
+    }
+
+    /// Cancel a job that has not reached its pane yet, returning what it was
+    fn cancel_unstarted(&mut self, job_id: u64) -> Option<&'static str> {
+        let job = self.jobs.get_mut(&job_id)?;
+        match (job.state, job.pane_id) {
+            (JobState::Queued, _) => {
+                job.state = JobState::Cancelled;
+                job.finished_ms = Some(unix_ms());
+                Some("queued")
+            }
+            // Its pane is closed as soon as it opens
+            (JobState::Running, None) => {
+                job.state = JobState::Cancelled;
+                Some("starting")
+            }
+            _ => None,
+        }
+    }
+
+    /// How many queued jobs start before `job_id`, plus one
+    fn queue_position(&self, job_id: u64) -> usize {
+        let priority = self.jobs.get(&job_id).map(|j| j.priority).unwrap_or_default();
+        let ahead = self.jobs.iter()
+            .filter(|(_, j)| j.state == JobState::Queued)
+            .filter(|(id, j)| j.priority > priority || (j.priority == priority && **id < job_id))
+            .count();
+        ahead + 1
+    }
+
+    /// The queued job to start next: the highest priority, oldest first,
+    /// skipping deferring jobs while higher-priority work is pending
+    fn next_job(&self) -> Option<u64> {
+        let pending = |j: &&Job| matches!(j.state, JobState::Queued | JobState::Running);
+        let top = self.jobs.values().filter(pending).map(|j| j.priority).max()?;
+        self.jobs.iter()
+            .filter(|(_, j)| j.state == JobState::Queued)
+            .filter(|(_, j)| !j.defer || j.priority >= top)
+            .max_by_key(|(id, j)| (j.priority, std::cmp::Reverse(**id)))
+            .map(|(id, _)| *id)
+    }
+
+    /// Start queued jobs by priority while `max_running` leaves room
+    fn start_queued_jobs(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self.opening.iter()
+            .filter(|(_, t)| now.duration_since(**t) >= OPENING_GRACE)
+            .map(|(h, _)| h.clone())
+            .collect();
+        for handle in expired {
+            self.opening.remove(&handle);
+            let unopened = self.jobs.values_mut()
+                .find(|j| j.finished_ms.is_none() && j.pane_id.is_none() && j.handle.as_ref() == Some(&handle));
+            if let Some(job) = unopened {
+                if job.state == JobState::Running {
+                    job.state = JobState::Failed;
+                    job.error = Some("Its command pane never opened".to_string());
+                }
+                job.finished_ms = Some(unix_ms());
+            }
+        }
+        // Draining holds queued jobs back until it is lifted
+        while !self.saturated() && !self.config.drain {
+            let Some(job_id) = self.next_job() else {
+                break;
+            };
+            self.start_job(job_id);
+        }
+        self.trim_jobs();
+    }
+
+    /// Open the pane of a queued job, in its artifacts directory when it has one.
+    /// The job may have waited since it was accepted, so read-only mode, draining
+    /// and denied permissions are checked again first.
+    fn start_job(&mut self, job_id: u64) -> Response {
+        let Some(job) = self.jobs.get(&job_id) else {
+            return Response {
+                success: false,
+                error: Some(format!("No job {}", job_id)),
+                error_code: Some(ErrorCode::JobNotFound),
+                data: Some(serde_json::json!({"job_id": job_id})),
+            };
+        };
+        if self.config.drain {
+            let retry_after = self.config.drain_retry_after_secs;
+            return Response {
+                success: false,
+                error: Some(format!("The bridge is draining; job {} stays queued", job_id)),
+                error_code: Some(ErrorCode::Draining),
+                data: Some(serde_json::json!({"job_id": job_id, "draining": true, "retry_after_secs": retry_after})),
+            };
+        }
+        let refused = if self.config.read_only {
+            Some((ErrorCode::ReadOnly, "The bridge went read-only before the job could start".to_string()))
+        } else {
+            self.denied_permission(&job.run).map(|permission| {
+                (ErrorCode::PermissionDenied, format!("Permission {} was denied before the job could start", permission))
+            })
+        };
+        if let Some((code, error)) = refused {
+            if let Some(job) = self.jobs.get_mut(&job_id) {
+                job.state = JobState::Failed;
+                job.finished_ms = Some(unix_ms());
+                job.error = Some(error.clone());
+                job.error_code = Some(code);
+            }
+            return Response {
+                success: false,
+                error: Some(error),
+                error_code: Some(code),
+                data: Some(serde_json::json!({"job_id": job_id})),
+            };
+        }
+        let run = match &job.artifacts {
+            Some(dir) => with_artifacts(job.run.clone(), dir),
+            None => job.run.clone(),
+        };
+        let mut response = self.execute_command(run);
+        let Some(job) = self.jobs.get_mut(&job_id) else {
+            return response;
+        };
+        job.started_ms = Some(unix_ms());
+        if response.success {
+            job.state = JobState::Running;
+            job.handle = response.data.as_ref().and_then(|d| d["handle"].as_str()).map(String::from);
+        } else {
+            job.state = JobState::Failed;
+            job.finished_ms = job.started_ms;
+            job.error = response.error.clone();
+            job.error_code = response.error_code;
+        }
+        if let Some(data) = response.data.as_mut().filter(|_| response.success) {
+            let (command, args) = job.command();
+            data["command"] = command.into();
+            data["args"] = args.into();
+            data["job_id"] = job_id.into();
+            data["artifacts"] = job.artifacts.clone().into();
+        }
+        response
+    }
+
+    /// Mark the running job in `pane_id` done or failed by its exit code. A
+    /// cancelled job stays cancelled and only records how it ended.
+    fn finish_job(&mut self, pane_id: u32, exit_code: Option<i32>) {
+        let running = self.jobs.values_mut().find(|j| {
+            j.pane_id == Some(pane_id)
+                && (j.state == JobState::Running || (j.state == JobState::Cancelled && j.finished_ms.is_none()))
+        });
+        if let Some(job) = running {
+            if job.state == JobState::Running {
+                job.state = if exit_code == Some(0) { JobState::Done } else { JobState::Failed };
+            }
+            job.exit_code = exit_code;
+            job.finished_ms = Some(unix_ms());
+        }
+    }
+
+    /// Drop the oldest finished jobs beyond FINISHED_JOBS_CAPACITY
+    fn trim_jobs(&mut self) {
+        let finished: Vec<u64> = self.jobs.iter()
+            .filter(|(_, j)| j.finished())
+            .map(|(id, _)| *id)
+            .collect();
+        for job_id in finished.iter().take(finished.len().saturating_sub(FINISHED_JOBS_CAPACITY)) {
+            self.jobs.remove(job_id);
+        }
+    }
+
+    fn quiesce_summary(&self) -> serde_json::Value {
+        let exit_codes: BTreeMap<u32, Option<i32>> = self.command_results.values()
+            .filter(|r| r.exited)
+            .map(|r| (r.pane_id, r.exit_code))
+            .collect();
+        let failed = exit_codes.values().filter(|c| **c != Some(0)).count();
+        serde_json::json!({
+            "running": self.running_commands(),
+            "exit_codes": exit_codes,
+            "failed": failed,
+        })
+    }
+
+    /// Exercise one harmless host call per permission and record which ones work
+    fn start_self_test(&mut self) {
+        let denied = |permission: PermissionType| {
+            self.permissions.get(&format!("{:?}", permission)) == Some(&PermissionState::Denied)
+        };
+        let status = |permission: PermissionType, attempt: bool| {
+            if denied(permission) {
+                ProbeStatus::Denied
+            } else if attempt {
+                ProbeStatus::Pending
+            } else {
+                ProbeStatus::Untested
+            }
+        };
+        let mut probes = BTreeMap::new();
+        probes.insert("read_application_state", match status(PermissionType::ReadApplicationState, true) {
+            ProbeStatus::Pending if self.panes.is_empty() && self.tabs.is_empty() => ProbeStatus::Failed,
+            ProbeStatus::Pending => ProbeStatus::Ok,
+            other => other,
+        });
+        // This command itself arrived over a pipe
+        probes.insert("read_cli_pipes", ProbeStatus::Ok);
+        probes.insert("run_commands", status(PermissionType::RunCommands, true));
+        probes.insert("command_pane", status(PermissionType::RunCommands, true));
+        probes.insert("write_to_stdin", status(PermissionType::WriteToStdin, false));
+        probes.insert("change_application_state", status(PermissionType::ChangeApplicationState, false));
+        probes.insert("open_terminals_or_plugins", status(PermissionType::OpenTerminalsOrPlugins, false));
+
+        if probes["run_commands"] == ProbeStatus::Pending {
+            let context = BTreeMap::from([(PROBE_CONTEXT_KEY.to_string(), "run_commands".to_string())]);
+            host!(run_command(&["true"], context));
+        }
+        if probes["command_pane"] == ProbeStatus::Pending {
+            let context = BTreeMap::from([(PROBE_CONTEXT_KEY.to_string(), "command_pane".to_string())]);
+            let cmd = CommandToRun { path: "true".into(), args: Vec::new(), cwd: None };
+            host!(open_command_pane_background(cmd, context));
+        }
+        self.probes = probes;
+    }
+
+    /// Record the outcome of a probe reported back through an event context
+    fn finish_probe(&mut self, context: &BTreeMap<String, String>, ok: bool) {
+        let probe = context.get(PROBE_CONTEXT_KEY).and_then(|name| self.probes.get_mut(name.as_str()));
+        if let Some(status) = probe.filter(|s| **s == ProbeStatus::Pending) {
+            *status = if ok { ProbeStatus::Ok } else { ProbeStatus::Failed };
+            self.flush_deferred_replies(false);
+        }
+    }
+
+    /// Take the remote bridge's reply from the output of `zellij pipe`
+    fn finish_forward(&mut self, id: u64, exit_code: Option<i32>, stdout: &[u8], stderr: &[u8]) {
+        let Some(slot) = self.forwards.get_mut(&id) else {
+            return;
+        };
+        let stdout = String::from_utf8_lossy(stdout);
+        let remote = stdout.lines()
+            .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .find(|reply| reply.get("progress").is_none())
+            .unwrap_or_else(|| {
+                let stderr = String::from_utf8_lossy(stderr);
+                serde_json::json!({
+                    "success": false,
+                    "error": format!("zellij pipe exited with {:?}: {}", exit_code, stderr.trim()),
+                })
+            });
+        *slot = Some(remote);
+        self.flush_deferred_replies(false);
+    }
+
+    /// Store the response to a `fetch`, body as text when it is UTF-8 and as
+    /// base64 otherwise, cut off at `max_fetch_bytes`
+    fn finish_fetch(&mut self, id: u64, status: u16, headers: BTreeMap<String, String>, mut body: Vec<u8>) {
+        let Some(slot) = self.fetches.get_mut(&id) else {
+            return;
+        };
+        let size = body.len();
+        let truncated = size > self.config.max_fetch_bytes;
+        body.truncate(self.config.max_fetch_bytes);
+        let mut fetched = serde_json::json!({
+            "status": status,
+            "headers": headers,
+            "size": size,
+            "truncated": truncated,
+        });
+        match text_or_base64(body, truncated) {
+            Ok(text) => fetched["body"] = text.into(),
+            Err(encoded) => fetched["body_base64"] = encoded.into(),
+        }
+        *slot = Some(fetched);
+        self.flush_deferred_replies(false);
+    }
+
+    /// Up to `limit` bytes (capped by `max_file_bytes`) of a file from `offset`,
+    /// with where the next read should start
+    fn read_chunk(&self, full_path: &str, offset: u64, limit: Option<usize>) -> std::io::Result<serde_json::Value> {
+        use std::io::{Read, Seek, SeekFrom};
+        let limit = limit.unwrap_or(self.config.max_file_bytes).min(self.config.max_file_bytes);
+        let mut file = std::fs::File::open(full_path)?;
+        let size = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = Vec::new();
+        file.take(limit as u64).read_to_end(&mut bytes)?;
+        let end = offset + bytes.len() as u64;
+        let truncated = end < size;
+        let mut data = serde_json::json!({
+            "size": size,
+            "offset": offset,
+            "next_offset": end,
+            "truncated": truncated,
+        });
+        match text_or_base64(bytes, truncated) {
+            Ok(text) => data["content"] = text.into(),
+            Err(encoded) => data["content_base64"] = encoded.into(),
+        }
+        Ok(data)
+    }
+
+    /// Resolve a path given to the file commands inside the host folder
+    /// (mounted at /host), refusing `..` escapes and paths outside `file_allowlist`
+    fn host_path(&self, path: &str) -> Result<String, Response> {
+        let relative = path.strip_prefix("/host").filter(|rest| rest.is_empty() || rest.starts_with('/'));
+        let relative = match relative {
+            Some(rest) => rest,
+            None if path.starts_with('/') => {
+                return Err(Response {
+                    success: false,
+                    error: Some(format!("Path '{}' is outside the host folder; use a relative path", path)),
+                    error_code: Some(ErrorCode::InvalidArgument),
+                    data: None,
+                });
+            }
+            None => path,
+        };
+        let mut parts: Vec<&str> = Vec::new();
+        for part in relative.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    if parts.pop().is_none() {
+                        return Err(Response {
+                            success: false,
+                            error: Some(format!("Path '{}' escapes the host folder", path)),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: None,
+                        });
+                    }
+                }
+                part => parts.push(part),
+            }
+        }
+        let normalized = parts.join("/");
+        let allowed = self.config.file_allowlist.is_empty()
+            || self.config.file_allowlist.iter().any(|prefix| {
+                normalized == *prefix || normalized.starts_with(&format!("{}/", prefix))
+            });
+        if !allowed {
+            return Err(Response {
+                success: false,
+                error: Some(format!(
+                    "Path '{}' is not under an allowed folder ({})",
+                    path, self.config.file_allowlist.join(", ")
+                )),
+                error_code: Some(ErrorCode::NotAllowed),
+                data: Some(serde_json::json!({"file_allowlist": self.config.file_allowlist})),
+            });
+        }
+        Ok(format!("/host/{}", normalized))
+    }
+
+    /// Under `replay_protection`, refuse a request that repeats an earlier one:
+    /// its `nonce` must exceed every nonce seen, or without one its `ts_ms` must
+    /// be within the window and the same request not seen with it before
+    fn check_replay(&mut self, envelope: &Envelope, name: &str, payload: &str) -> Option<Response> {
+        use std::hash::{Hash, Hasher};
+        if !self.config.replay_protection {
+            return None;
+        }
+        let refuse = |error: String| Some(Response {
+            success: false,
+            error: Some(error),
+            error_code: Some(ErrorCode::Replayed),
+            data: None,
+        });
+        if let Some(nonce) = envelope.nonce {
+            if self.last_nonce.is_some_and(|last| nonce <= last) {
+                return refuse(format!("Nonce {} is not above the last accepted nonce; request refused as a replay", nonce));
+            }
+            self.last_nonce = Some(nonce);
+            return None;
+        }
+        let Some(ts_ms) = envelope.ts_ms else {
+            return refuse("Replay protection is on; send an increasing nonce or a ts_ms timestamp".to_string());
+        };
+        let now = unix_ms();
+        let window_ms = self.config.replay_window_secs * 1000;
+        if ts_ms.abs_diff(now) > window_ms {
+            return refuse(format!(
+                "ts_ms {} is more than {}s from the bridge clock; request refused as a replay",
+                ts_ms, self.config.replay_window_secs
+            ));
+        }
+        self.seen_requests.retain(|(seen_ms, _)| seen_ms.abs_diff(now) <= window_ms);
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (name, payload).hash(&mut hasher);
+        let stamp = (ts_ms, hasher.finish());
+        if self.seen_requests.contains(&stamp) {
+            return refuse("The same request with the same ts_ms was already accepted; refused as a replay".to_string());
+        }
+        self.seen_requests.push_back(stamp);
+        None
+    }
+
+    fn handle_command(&mut self, name: &str, payload: &str) -> Response {
+        // Aliases resolve a single level, so they cannot loop
+        let aliases = &self.config.aliases;
+        let name = aliases.get(name).cloned().unwrap_or_else(|| name.to_string());
+        let name = name.as_str();
+        let parsed = if payload.trim().is_empty() {
+            Ok(serde_json::json!({}))
+        } else {
+            serde_json::from_str::<serde_json::Value>(payload)
+        };
+        // Model-written payloads often carry trailing commas, single quotes or bare keys
+        let (parsed, relaxed) = match parsed {
+            Err(e) => match relax_json(payload) {
+                Some(v) => (Ok(v), Some(e)),
+                None => (Err(e), None),
+            },
+            parsed => (parsed, None),
+        };
+        let strict = parsed.as_ref().ok()
+            .and_then(|v| v.get("strict"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(self.config.strict);
+        if let Some(e) = relaxed.as_ref().filter(|_| strict) {
+            return Response {
+                success: false,
+                error: Some(format!("Invalid JSON payload for '{}': {} (strict mode accepts plain JSON only)", name, e)),
+                error_code: Some(ErrorCode::InvalidArgument),
+                data: None,
+            };
+        }
+        if relaxed.is_some() {
+            self.warnings.push("Payload was not plain JSON and was read leniently".to_string());
+        }
+        let dry_run = parsed.as_ref().ok()
+            .and_then(|v| v.get("dry_run"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        // Inject the pipe name as the "cmd" field for serde deserialization
+        let mut json_with_cmd = match parsed {
+            Ok(mut v) => {
+                if let Some(obj) = v.as_object_mut() {
+                    obj.insert("cmd".to_string(), serde_json::Value::String(name.to_string()));
+                }
+                v
+            }
+            Err(e) if strict => {
+                return Response {
+                    success: false,
+                    error: Some(format!("Invalid JSON payload for '{}': {}", name, e)),
+                    error_code: Some(ErrorCode::InvalidArgument),
+                    data: None,
+                };
+            }
+            Err(_) => serde_json::json!({"cmd": name}),
+        };
+        if let Some(response) = self.resolve_handles(&mut json_with_cmd) {
+            return response;
+        }
+
+        // With overrides disabled the command runs as if `force` had not been set
+        let force_ignored = !self.config.allow_force && json_with_cmd.get("force") == Some(&serde_json::Value::Bool(true));
+        let mut effective = json_with_cmd.clone();
+        if force_ignored {
+            effective["force"] = serde_json::Value::Bool(false);
+            self.warnings.push("force:true was ignored because force overrides are disabled by policy".to_string());
+        }
+        let cmd_result: Result<Command, serde_json::Error> = serde_json::from_value(effective);
+
+        match cmd_result {
+            Ok(cmd) => {
+                if strict {
+                    if let Some(response) = Self::check_unknown_fields(name, &cmd, &json_with_cmd) {
+                        return response;
+                    }
+                }
+                if let Some(response) = cmd.target_pane().and_then(|id| self.check_pane_target(id)) {
+                    return response;
+                }
+                if let Some(pane_id) = cmd.target_pane().filter(|id| cmd.is_mutating() && !self.may_change(*id)) {
+                    return Response {
+                        success: false,
+                        error: Some(format!(
+                            "Pane {} was not opened by the bridge; '{}' may only change its own panes (use grant_pane to allow)",
+                            pane_id, name
+                        )),
+                        error_code: Some(ErrorCode::NotOwner),
+                        data: Some(serde_json::json!({"pane_id": pane_id})),
+                    };
+                }
+                if self.config.read_only && (cmd.is_mutating() || cmd.changes_bridge()) && !self.read_only_exempt(&cmd) {
+                    let changes = if cmd.is_mutating() { "the session" } else { "the bridge's state" };
+                    return Response {
+                        success: false,
+                        error: Some(format!("The bridge is in read-only mode; '{}' would change {}", name, changes)),
+                        error_code: Some(ErrorCode::ReadOnly),
+                        data: Some(serde_json::json!({"read_only": true})),
+                    };
+                }
+                if let Some(response) = self.check_policy(name, &cmd) {
+                    return response;
+                }
+                if let Some(response) = self.check_host_support(name, &cmd) {
+                    return response;
+                }
+                if self.config.drain && cmd.starts_work() {
+                    let retry_after = self.config.drain_retry_after_secs;
+                    return Response {
+                        success: false,
+                        error: Some(format!("The bridge is draining and not accepting '{}'; retry in {}s", name, retry_after)),
+                        error_code: Some(ErrorCode::Draining),
+                        data: Some(serde_json::json!({"draining": true, "retry_after_secs": retry_after})),
+                    };
+                }
+                if let Some(response) = self.check_overload(name, &cmd) {
+                    return response;
+                }
+                match self.denied_permission(&cmd) {
+                    Some(permission) if self.permissions.get(&permission) != Some(&PermissionState::Denied) => {
+                        // Asked for the first time a command needs it, then pending until answered
+                        if let Some(required) = cmd.required_permission().filter(|_| !self.permissions.contains_key(&permission)) {
+                            self.request_permissions(&[required]);
+                        }
+                        Response {
+                            success: false,
+                            error: Some(format!(
+                                "Permission {} has not been granted yet; '{}' can run once the Zellij prompt is accepted",
+                                permission, name
+                            )),
+                            error_code: Some(ErrorCode::PermissionDenied),
+                            data: Some(serde_json::json!({"permission_missing": permission})),
+                        }
+                    }
+                    Some(permission) => Response {
+                        success: false,
+                        error: Some(format!(
+                            "Permission {} was denied; '{}' cannot run (use request_permissions to ask again)",
+                            permission, name
+                        )),
+                        error_code: Some(ErrorCode::PermissionDenied),
+                        data: Some(serde_json::json!({"permission_denied": permission})),
+                    },
+                    None if dry_run && (cmd.is_mutating() || cmd.changes_bridge()) => self.dry_run(cmd),
+                    None => {
+                        let observed_pane = cmd.observed_pane();
+                        let target_pane = cmd.target_pane();
+                        let step = self.recording.as_ref()
+                            // A replay is captured as the steps it runs, not as itself
+                            .filter(|_| cmd.required_permission().is_some() && !matches!(cmd, Command::Replay { .. }))
+                            .map(|_| self.recorded_step(&cmd));
+                        let inverse = self.inverse_of(&cmd);
+                        let warnings = self.command_warnings(&cmd);
+                        let mut response = self.execute_command(cmd);
+                        if response.success {
+                            self.warnings.extend(warnings);
+                        }
+                        if let (true, Some(inverse)) = (response.success, inverse) {
+                            self.undo_stack.push_back(UndoStep { undoes: name.to_string(), inverse });
+                            while self.undo_stack.len() > UNDO_CAPACITY {
+                                self.undo_stack.pop_front();
+                            }
+                        }
+                        if force_ignored && response.error_code == Some(ErrorCode::Protected) {
+                            response.error = response.error.map(|e| {
+                                format!("{} (force overrides are disabled by policy)", e.replace(" (use force:true to override)", ""))
+                            });
+                        }
+                        if response.error_code == Some(ErrorCode::Protected) {
+                            *self.protection_violations.entry(name.to_string()).or_default() += 1;
+                            let mut params = json_with_cmd.clone();
+                            if let Some(obj) = params.as_object_mut() {
+                                obj.remove("cmd");
+                            }
+                            self.protection_log.push_back(ProtectionViolation {
+                                ts_ms: unix_ms(),
+                                command: name.to_string(),
+                                pane_id: target_pane.or(self.protected_pane_id),
+                                params,
+                                error: response.error.clone(),
+                            });
+                            while self.protection_log.len() > PROTECTION_LOG_CAPACITY {
+                                self.protection_log.pop_front();
+                            }
+                            self.log_event(BridgeEvent {
+                                kind: "protection_violation",
+                                pane_id: target_pane.or(self.protected_pane_id),
+                                title: Some(name.to_string()),
+                                ..Default::default()
+                            });
+                            self.flush_deferred_replies(false);
+                        }
+                        if response.success && self.defer_reply.is_none() {
+                            self.defer_reply = observed_pane.map(Waiting::PaneState);
+                        }
+                        if let (true, Some(step), Some(recording)) = (response.success, step, self.recording.as_mut()) {
+                            recording.push(step);
+                        }
+                        response
+                    }
+                }
+            }
+            Err(e) => {
+                let (commands, _) = command_schemas();
+                if commands.iter().any(|c| c["command"] == name) {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Invalid command '{}': {} (payload: {})", name, e, json_with_cmd)),
+                        error_code: Some(ErrorCode::InvalidCommand),
+                        data: None,
+                    };
+                }
+                // An unknown name: offer the nearest known ones and what the closest takes
+                let mut ranked: Vec<(usize, &str)> = commands.iter()
+                    .filter_map(|c| c["command"].as_str())
+                    .chain(self.config.aliases.keys().map(String::as_str))
+                    .map(|known| (edit_distance(name, known), known))
+                    .filter(|(distance, known)| {
+                        *distance <= (name.len().max(known.len()) / 3).max(2) || (name.len() >= 3 && known.contains(name))
+                    })
+                    .collect();
+                ranked.sort();
+                ranked.dedup_by_key(|(_, known)| *known);
+                let suggestions: Vec<&str> = ranked.iter().take(3).map(|(_, known)| *known).collect();
+                let closest = suggestions.first().map(|known| {
+                    let command = self.config.aliases.get(*known).map(String::as_str).unwrap_or(known);
+                    let schema = commands.iter().find(|c| c["command"] == command).map(|c| &c["schema"]);
+                    let parameters: Vec<&String> = schema
+                        .and_then(|s| s["properties"].as_object())
+                        .map(|props| props.keys().filter(|k| k.as_str() != "cmd").collect())
+                        .unwrap_or_default();
+                    let required: Vec<&str> = schema
+                        .and_then(|s| s["required"].as_array())
+                        .map(|r| r.iter().filter_map(|k| k.as_str()).filter(|k| *k != "cmd").collect())
+                        .unwrap_or_default();
+                    serde_json::json!({"command": command, "parameters": parameters, "required": required})
+                });
+                let hint = match suggestions.as_slice() {
+                    [] => " (use describe to list commands)".to_string(),
+                    [one] => format!("; did you mean '{}'?", one),
+                    many => format!("; did you mean one of {}?", many.iter().map(|s| format!("'{}'", s)).collect::<Vec<_>>().join(", ")),
+                };
+                Response {
+                    success: false,
+                    error: Some(format!("Unknown command '{}'{}", name, hint)),
+                    error_code: Some(ErrorCode::InvalidCommand),
+                    data: Some(serde_json::json!({"suggestions": suggestions, "closest": closest})),
+                }
+            }
+        }
+    }
+
+    /// KDL cannot tell a one-item list from a single value, so wrap single
+    /// values in a list wherever the command's schema expects one
+    fn kdl_lists(&self, name: &str, payload: String) -> String {
+        let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_str(&payload) else {
+            return payload;
+        };
+        let name = self.config.aliases.get(name).map(String::as_str).unwrap_or(name);
+        let (commands, _) = command_schemas();
+        let Some(command) = commands.iter().find(|c| c["command"] == name) else {
+            return payload;
+        };
+        for (key, value) in fields.iter_mut() {
+            let ty = &command["schema"]["properties"][key.as_str()]["type"];
+            let is_list = ty == "array" || ty.as_array().is_some_and(|t| t.iter().any(|t| t == "array"));
+            if is_list && !value.is_array() && !value.is_null() {
+                *value = serde_json::Value::Array(vec![value.take()]);
+            }
+        }
+        serde_json::Value::Object(fields).to_string()
+    }
+
+    /// The command that puts back what `cmd` is about to change, for the
+    /// commands `undo` can reverse
+    fn inverse_of(&self, cmd: &Command) -> Option<Command> {
+        match cmd {
+            Command::RenamePane { pane_id, .. } => {
+                let (_, pane) = self.find_pane(*pane_id)?;
+                let name = match self.highlights.get(pane_id) {
+                    Some((title, _)) => title.clone(),
+                    None => strip_markers(&pane.title).to_string(),
+                };
+                Some(Command::RenamePane { pane_id: *pane_id, name })
+            }
+            Command::Move { pane_id, direction } => {
+                let opposite = match direction.to_lowercase().as_str() {
+                    "up" => "down",
+                    "down" => "up",
+                    "left" => "right",
+                    // Unknown directions move right, like `move` itself
+                    _ => "left",
+                };
+                Some(Command::Move { pane_id: *pane_id, direction: opposite.to_string() })
+            }
+            Command::Resize { pane_id, .. } => {
+                let (_, pane) = self.find_pane(*pane_id)?;
+                Some(Command::Resize {
+                    pane_id: *pane_id,
+                    direction: None,
+                    amount: None,
+                    shrink: false,
+                    rows: Some(pane.pane_content_rows),
+                    cols: Some(pane.pane_content_columns),
+                })
+            }
+            Command::Hide { pane_id, .. } => Some(Command::Show { pane_id: *pane_id }),
+            Command::Show { pane_id } => Some(Command::Hide { pane_id: *pane_id, force: false }),
+            Command::TogglePaneFloating { pane_id } => Some(Command::TogglePaneFloating { pane_id: *pane_id }),
+            Command::FocusTab { .. } | Command::GotoTab { .. } | Command::GotoPreviousTab => {
+                let index = self.active_tab_index()?;
+                Some(Command::GotoTab { index: index as u32 })
+            }
+            _ => None,
+        }
+    }
+
+    /// Run a command that passed every check without letting it reach the
+    /// host: host calls are recorded rather than made and the bridge's own
+    /// state is put back afterwards, so the reply shows the planned effect.
+    fn dry_run(&mut self, cmd: Command) -> Response {
+        let target_pane = cmd.target_pane();
+        let saved = self.clone();
+        let started = trace::start_capture();
+        let mut response = self.execute_command(cmd);
+        // A dry run nested in another (a replay step) leaves the outer one collecting
+        let host_calls = if started { trace::finish_capture() } else { Vec::new() };
+        *self = saved;
+        let result = response.data.take();
+        response.data = Some(serde_json::json!({
+            "dry_run": true,
+            "target_pane": target_pane,
+            "host_calls": host_calls,
+            "result": result,
+        }));
+        response
+    }
+
+    /// Refuse a command the running Zellij is too old for. An unknown host
+    /// version lets everything through.
+    fn check_host_support(&self, name: &str, cmd: &Command) -> Option<Response> {
+        let required = cmd.min_host_version()?;
+        let host = self.zellij_version.as_deref().and_then(parse_version)?;
+        if host >= required {
+            return None;
+        }
+        let required = format!("{}.{}.{}", required.0, required.1, required.2);
+        Some(Response {
+            success: false,
+            error: Some(format!(
+                "'{}' needs Zellij {} or newer; this host runs {}.{}.{}", name, required, host.0, host.1, host.2
+            )),
+            error_code: Some(ErrorCode::UnsupportedOnHost),
+            data: Some(serde_json::json!({"requires": required, "zellij_version": self.zellij_version})),
+        })
+    }
+
+    /// Enforce the allowlist and rate limit of the active policy. Bookkeeping
+    /// commands that never reach the host are exempt, unless they change the
+    /// policy itself.
+    fn check_policy(&mut self, name: &str, cmd: &Command) -> Option<Response> {
+        let allow_listed = self.config.allowed_commands.iter().any(|c| c == name);
+        // Policy changes pass only under the trusted profile (or none) or when listed by name
+        if cmd.is_policy_change() {
+            let trusted = self.config.profile.as_deref().is_none_or(|p| p == "trusted");
+            if allow_listed || (trusted && self.config.allowed_commands.is_empty()) {
+                return None;
+            }
+            return Some(Response {
+                success: false,
+                error: Some(format!("'{}' changes the bridge's policy, which the current policy does not allow", name)),
+                error_code: Some(ErrorCode::NotAllowed),
+                data: Some(serde_json::json!({
+                    "profile": self.config.profile,
+                    "allowed_commands": self.config.allowed_commands,
+                })),
+            });
+        }
+        cmd.required_permission()?;
+        if !self.config.allowed_commands.is_empty() && !allow_listed {
+            return Some(Response {
+                success: false,
+                error: Some(format!("'{}' is not allowed by the current policy", name)),
+                error_code: Some(ErrorCode::NotAllowed),
+                data: Some(serde_json::json!({
+                    "profile": self.config.profile,
+                    "allowed_commands": self.config.allowed_commands,
+                })),
+            });
+        }
+        let limit = self.config.max_commands_per_minute;
+        if limit > 0 {
+            let window = Duration::from_secs(60);
+            let now = Instant::now();
+            while self.recent_commands.front().is_some_and(|t| now.duration_since(*t) >= window) {
+                self.recent_commands.pop_front();
+            }
+            if self.recent_commands.len() >= limit {
+                let retry_after = self.recent_commands.front()
+                    .map(|t| window.saturating_sub(now.duration_since(*t)).as_secs() + 1)
+                    .unwrap_or(1);
+                return Some(Response {
+                    success: false,
+                    error: Some(format!("Rate limit of {} commands per minute reached; retry in {}s", limit, retry_after)),
+                    error_code: Some(ErrorCode::RateLimited),
+                    data: Some(serde_json::json!({"limit_per_minute": limit, "retry_after_secs": retry_after})),
+                });
+            }
+            self.recent_commands.push_back(now);
+        }
+        None
+    }
+
+    /// Capture `cmd` along with the titles of the panes it targets
+    fn recorded_step(&self, cmd: &Command) -> RecordedStep {
+        let command = serde_json::to_value(cmd).unwrap_or_default();
+        let mut ids: Vec<u32> = PANE_ID_FIELDS.iter()
+            .filter_map(|field| command.get(*field).and_then(|v| v.as_u64()))
+            .map(|id| id as u32)
+            .collect();
+        if let Some(pane_ids) = command.get("pane_ids").and_then(|v| v.as_array()) {
+            ids.extend(pane_ids.iter().filter_map(|v| v.as_u64()).map(|id| id as u32));
+        }
+        let pane_names = ids.into_iter()
+            .filter_map(|id| self.find_pane(id).map(|(_, p)| (id, strip_markers(&p.title).to_string())))
+            .collect();
+        RecordedStep { command, pane_names }
+    }
+
+    /// Point a recorded command at the panes that now carry the recorded titles.
+    /// Returns the ids that changed, old to new.
+    fn remap_step(&self, step: &RecordedStep) -> (serde_json::Value, BTreeMap<u32, u32>) {
+        let mut remapped = BTreeMap::new();
+        for (&old_id, title) in &step.pane_names {
+            let current = self.find_pane_by_title(title).map(|(_, p)| p.id);
+            if let Some(new_id) = current.filter(|new_id| *new_id != old_id) {
+                remapped.insert(old_id, new_id);
+            }
+        }
+        let remap = |v: &mut serde_json::Value| {
+            if let Some(new_id) = v.as_u64().and_then(|id| remapped.get(&(id as u32))) {
+                *v = serde_json::json!(new_id);
+            }
+        };
+        let mut command = step.command.clone();
+        for field in PANE_ID_FIELDS {
+            if let Some(v) = command.get_mut(*field) {
+                remap(v);
+            }
+        }
+        if let Some(pane_ids) = command.get_mut("pane_ids").and_then(|v| v.as_array_mut()) {
+            pane_ids.iter_mut().for_each(remap);
+        }
+        (command, remapped)
+    }
+
+    /// Give every live terminal pane a handle and retire those of panes that closed
+    fn track_handles(&mut self, live: &[u32]) {
+        let closed: Vec<u32> = self.pane_handles.keys().filter(|id| !live.contains(id)).copied().collect();
+        for pane_id in closed {
+            if let Some(handle) = self.pane_handles.remove(&pane_id) {
+                self.retire_handle(handle);
+            }
+        }
+        for &pane_id in live {
+            self.pane_handles.entry(pane_id).or_insert_with(new_handle);
+        }
+    }
+
+    fn retire_handle(&mut self, handle: String) {
+        self.closed_handles.push_back(handle);
+        while self.closed_handles.len() > CLOSED_HANDLES_CAPACITY {
+            self.closed_handles.pop_front();
+        }
+    }
+
+    /// Hand `handle` to a newly opened pane, taking it from the pane it replaces.
+    /// Whatever handle the id carried before belonged to an earlier pane.
+    fn adopt_handle(&mut self, pane_id: u32, handle: String) {
+        if let Some(previous) = self.handle_pane(&handle) {
+            self.pane_handles.remove(&previous);
+        }
+        if let Some(old) = self.pane_handles.insert(pane_id, handle.clone()).filter(|old| *old != handle) {
+            self.retire_handle(old);
+        }
+        if let Some(tab_idx) = self.find_pane(pane_id).map(|(tab_idx, _)| tab_idx) {
+            self.stale_summaries.insert(tab_idx);
+        }
+    }
+
+    /// The id of the pane carrying `handle` now
+    fn handle_pane(&self, handle: &str) -> Option<u32> {
+        self.pane_handles.iter().find(|(_, h)| *h == handle).map(|(id, _)| *id)
+    }
+
+    /// Swap pane handles in a payload for the ids of the panes carrying them now
+    fn resolve_handles(&self, payload: &mut serde_json::Value) -> Option<Response> {
+        let mut fields: Vec<&mut serde_json::Value> = Vec::new();
+        for (key, value) in payload.as_object_mut()?.iter_mut() {
+            if PANE_ID_FIELDS.contains(&key.as_str()) {
+                fields.push(value);
+            } else if let ("pane_ids", Some(ids)) = (key.as_str(), value.as_array_mut()) {
+                fields.extend(ids.iter_mut());
+            }
+        }
+        for value in fields {
+            let Some(handle) = value.as_str() else {
+                continue;
+            };
+            match self.handle_pane(handle) {
+                Some(pane_id) => *value = serde_json::json!(pane_id),
+                None => {
+                    let closed = self.closed_handles.iter().any(|h| h == handle);
+                    return Some(Response {
+                        success: false,
+                        error: Some(if closed {
+                            format!("Pane handle {} belongs to a pane that has closed", handle)
+                        } else {
+                            format!("Unknown pane handle {}", handle)
+                        }),
+                        error_code: Some(ErrorCode::PaneNotFound),
+                        data: Some(serde_json::json!({"handle": handle, "closed": closed})),
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Make sure a pane id names a live terminal pane before handing it to the host,
+    /// which would otherwise ignore the call silently. Skipped until the first
+    /// manifest arrives, since there is nothing to check against yet.
+    fn check_pane_target(&self, pane_id: u32) -> Option<Response> {
+        if self.panes.is_empty() || self.find_pane(pane_id).is_some() {
+            return None;
+        }
+        let is_plugin = self.panes.values().flatten().any(|p| p.id == pane_id && p.is_plugin);
+        Some(if is_plugin {
+            Response {
+                success: false,
+                error: Some(format!("Pane {} is a plugin pane, not a terminal", pane_id)),
+                error_code: Some(ErrorCode::WrongPaneType),
+                data: Some(serde_json::json!({"pane_id": pane_id, "is_plugin": true})),
+            }
+        } else {
+            Response {
+                success: false,
+                error: Some(format!("Pane {} not found", pane_id)),
+                error_code: Some(ErrorCode::PaneNotFound),
+                data: Some(serde_json::json!({"pane_id": pane_id})),
+            }
+        })
+    }
+
+    /// Exit code of a command pane that has finished, from the manifest or from
+    /// the exit events we tracked. `Some(None)` means exited without a code.
+    fn exited_status(&self, pane_id: u32) -> Option<Option<i32>> {
+        if let Some(result) = self.command_results.get(&pane_id).filter(|r| r.exited) {
+            return Some(result.exit_code);
+        }
+        self.find_pane(pane_id)
+            .filter(|(_, p)| p.exited)
+            .map(|(_, p)| p.exit_status)
+    }
+
+    /// Typing into a finished command pane goes nowhere useful (Enter re-runs it),
+    /// so refuse unless the caller opts in.
+    fn check_exited_write(&self, pane_id: u32, allow_exited: bool) -> Option<Response> {
+        let exit_code = self.exited_status(pane_id)?;
+        if allow_exited {
+            return None;
+        }
+        Some(Response {
+            success: false,
+            error: Some(format!(
+                "Pane {} has exited (exit code {}); its command is no longer reading input (use allow_exited:true to write anyway)",
+                pane_id,
+                exit_code.map(|c| c.to_string()).unwrap_or_else(|| "unknown".to_string())
+            )),
+            error_code: Some(ErrorCode::PaneExited),
+            data: Some(serde_json::json!({"pane_id": pane_id, "exit_code": exit_code})),
+        })
+    }
+
+    /// Refuse another held reply once `max_held_replies` are waiting, since each
+    /// one keeps a CLI pipe blocked and is re-checked on every session update
+    fn check_overload(&self, name: &str, cmd: &Command) -> Option<Response> {
+        let limit = self.config.max_held_replies;
+        if limit == 0 || self.deferred_replies.len() < limit || !cmd.holds_reply() {
+            return None;
+        }
+        // The earliest held reply is answered by its deadline at the latest
+        let retry_after = self.deferred_replies.iter()
+            .map(|r| r.deadline.saturating_duration_since(Instant::now()))
+            .min()
+            .map_or(1, |d| d.as_secs_f64().ceil().max(1.0) as u64);
+        Some(Response {
+            success: false,
+            error: Some(format!(
+                "The bridge is holding {} replies already; '{}' would wait too (retry in {}s)",
+                self.deferred_replies.len(), name, retry_after
+            )),
+            error_code: Some(ErrorCode::Overloaded),
+            data: Some(serde_json::json!({
+                "held_replies": self.deferred_replies.len(),
+                "max_held_replies": limit,
+                "retry_after_secs": retry_after,
+            })),
+        })
+    }
+
+    /// Whether the pipe message being handled has used up its `max_request_ms`
+    fn over_budget(&self) -> bool {
+        self.request_deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// What looks wrong about running `cmd` now without being reason to refuse it
+    fn command_warnings(&self, cmd: &Command) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let Some(pane_id) = cmd.target_pane() else {
+            return warnings;
+        };
+        let Some((tab_idx, pane)) = self.find_pane(pane_id) else {
+            return warnings;
+        };
+        match cmd {
+            Command::Write { .. } | Command::WriteBytes { .. } => {
+                if let Some(exit_code) = self.exited_status(pane_id) {
+                    warnings.push(format!(
+                        "Pane {} has exited (exit code {}); the input may re-run its command or go unread",
+                        pane_id,
+                        exit_code.map(|c| c.to_string()).unwrap_or_else(|| "unknown".to_string())
+                    ));
+                }
+            }
+            Command::Resize { .. } if pane.is_fullscreen => {
+                warnings.push(format!("Pane {} is fullscreen; the new size shows once it leaves fullscreen", pane_id));
+            }
+            _ => {}
+        }
+        // Visual changes in a tab nobody is looking at go unnoticed
+        let visual = matches!(
+            cmd,
+            Command::Resize { .. } | Command::Move { .. } | Command::Highlight { .. } | Command::Fullscreen { .. }
+        );
+        if visual && self.active_tab_index().is_some_and(|active| active != tab_idx) {
+            warnings.push(format!("Pane {} is in tab {}, which is not focused", pane_id, tab_idx));
+        }
+        warnings
+    }
+
+    fn write_data(&self, pane_id: u32, written: usize, queue_position: Option<usize>) -> serde_json::Value {
+        let mut data = serde_json::json!({"written": written, "pane_id": pane_id});
+        if let Some(position) = queue_position {
+            data["queued"] = serde_json::json!(true);
+            data["queue_position"] = serde_json::json!(position);
+        }
+        if let Some(exit_code) = self.exited_status(pane_id) {
+            data["warning"] = serde_json::json!(format!("pane {} has exited", pane_id));
+            data["exit_code"] = serde_json::json!(exit_code);
+        }
+        data
+    }
+
+    /// Write to a pane now, or queue the write if the pane was written to less
+    /// than `write_gap_ms` ago. Returns the queue position of a deferred write.
+    fn queue_write(&mut self, pane_id: u32, write: QueuedWrite) -> Option<usize> {
+        let gap = Duration::from_millis(self.config.write_gap_ms);
+        let queue = self.write_queues.entry(pane_id).or_default();
+        let since_last = self.last_write.get(&pane_id).map(Instant::elapsed);
+        if queue.is_empty() && since_last.is_none_or(|elapsed| elapsed >= gap) {
+            Self::write_now(pane_id, &write);
+            self.last_write.insert(pane_id, Instant::now());
+            return None;
+        }
+        if queue.is_empty() {
+            host!(set_timeout(gap.saturating_sub(since_last.unwrap_or_default()).as_secs_f64()));
+        }
+        queue.push_back(write);
+        Some(queue.len())
+    }
+
+    fn write_now(pane_id: u32, write: &QueuedWrite) {
+        match write {
+            QueuedWrite::Chars(chars) => host!(write_chars_to_pane_id(chars, PaneId::Terminal(pane_id))),
+            QueuedWrite::Bytes(bytes) => host!(write_to_pane_id(bytes.clone(), PaneId::Terminal(pane_id))),
+        }
+    }
+
+    /// Send the next queued write of every pane whose gap has passed
+    fn drain_write_queues(&mut self) {
+        let gap = Duration::from_millis(self.config.write_gap_ms);
+        let now = Instant::now();
+        for (pane_id, queue) in &mut self.write_queues {
+            if queue.is_empty() {
+                continue;
+            }
+            let since_last = self.last_write.get(pane_id).map(|t| now.duration_since(*t)).unwrap_or(gap);
+            if since_last < gap {
+                host!(set_timeout((gap - since_last).as_secs_f64()));
+                continue;
+            }
+            if let Some(write) = queue.pop_front() {
+                Self::write_now(*pane_id, &write);
+                self.last_write.insert(*pane_id, now);
+            }
+            if !queue.is_empty() {
+                host!(set_timeout(gap.as_secs_f64()));
+            }
+        }
+        self.write_queues.retain(|_, queue| !queue.is_empty());
+    }
+
+    /// Reject payload fields the command does not accept. The accepted set comes
+    /// from serializing the parsed command back, so it always matches the enum.
+    fn check_unknown_fields(name: &str, cmd: &Command, payload: &serde_json::Value) -> Option<Response> {
+        let accepted: Vec<String> = serde_json::to_value(cmd).ok()?
+            .as_object()?
+            .keys()
+            .filter(|k| k.as_str() != "cmd")
+            .cloned()
+            .collect();
+        let unknown: Vec<&String> = payload.as_object()?
+            .keys()
+            .filter(|k| k.as_str() != "cmd" && !ENVELOPE_FIELDS.contains(&k.as_str()) && !accepted.contains(k))
+            .collect();
+        if unknown.is_empty() {
+            return None;
+        }
+        Some(Response {
+            success: false,
+            error: Some(format!(
+                "Unknown parameter(s) {} for '{}' (accepted: {})",
+                unknown.iter().map(|k| format!("'{}'", k)).collect::<Vec<_>>().join(", "),
+                name,
+                if accepted.is_empty() { "none".to_string() } else { accepted.join(", ") }
+            )),
+            error_code: Some(ErrorCode::InvalidArgument),
+            data: Some(serde_json::json!({"unknown_fields": unknown, "accepted": accepted})),
+        })
+    }
+
+    /// Rebuild the effective configuration from layout settings plus runtime overrides
+    fn apply_config(&mut self) -> Vec<String> {
+        let mut merged = self.base_configuration.clone();
+        merged.extend(self.config_overrides.clone());
+        // The selected profile's settings slot in under the overrides
+        let mut profile_warning = None;
+        if let Some(name) = merged.get("profile").filter(|n| !n.is_empty()) {
+            let custom: BTreeMap<String, BTreeMap<String, String>> = merged.get("profiles")
+                .and_then(|p| serde_json::from_str(p).ok())
+                .unwrap_or_default();
+            let settings = custom.get(name).cloned().or_else(|| {
+                BUILTIN_PROFILES.iter()
+                    .find(|(builtin, _)| builtin == name)
+                    .map(|(_, settings)| settings.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+            });
+            match settings {
+                Some(settings) => {
+                    merged = self.base_configuration.clone();
+                    merged.extend(settings);
+                    merged.extend(self.config_overrides.clone());
+                }
+                None => profile_warning = Some(format!("unknown profile '{}'", name)),
+            }
+        }
+        let (config, mut warnings) = Config::from_map(&merged);
+        warnings.extend(profile_warning);
+        trace::configure(config.trace.then_some(config.trace_max_bytes));
+        if config.prompt_title_patterns != self.config.prompt_title_patterns {
+            self.prompt_patterns = config.prompt_title_patterns.iter()
+                .filter_map(|p| regex::Regex::new(p).ok())
+                .collect();
+            // Cached summaries carry `ready_at_prompt`
+            self.stale_summaries.extend(self.panes.keys().copied());
+        }
+        self.config = config;
+        self.schedule_checkpoint();
+        warnings
+    }
+
+    /// The emergency stop: close every pane the bridge started, drop all queued
+    /// work and timers, answer waiting clients and go read-only. Returns what
+    /// was torn down.
+    fn panic(&mut self) -> serde_json::Value {
+        // Exited command panes held open are closed too, not only running ones
+        let mut closed: Vec<u32> = self.owned_panes.iter().copied().chain(self.running_commands()).collect();
+        let unfinished: Vec<u64> = self.jobs.iter()
+            .filter(|(_, j)| !j.finished() && j.state != JobState::Cancelled)
+            .map(|(id, _)| *id)
+            .collect();
+        for &job_id in &unfinished {
+            if self.cancel_unstarted(job_id).is_some() {
+                continue;
+            }
+            if let Some(job) = self.jobs.get_mut(&job_id) {
+                job.state = JobState::Cancelled;
+                closed.extend(job.pane_id);
+            }
+        }
+        // Runs whose pane is still opening are closed when it does
+        let opening = self.opening.len();
+        self.panicked_at = Some(Instant::now());
+        closed.sort_unstable();
+        closed.dedup();
+        closed.retain(|id| !self.is_protected_pane(*id));
+        for &pane_id in &closed {
+            host!(close_terminal_pane(pane_id));
+        }
+        let resizes: Vec<u32> = self.pending_resizes.drain().map(|(id, _)| id).collect();
+        let pages = self.pending_pages.len();
+        self.pending_pages.clear();
+        self.pending_page_order.clear();
+        let recording_steps = self.recording.take().map(|steps| steps.len());
+        let writes: usize = self.write_queues.drain().map(|(_, queue)| queue.len()).sum();
+        let now = Instant::now();
+        let mut highlights: Vec<u32> = self.highlights.keys().copied().collect();
+        highlights.sort_unstable();
+        for (_, until) in self.highlights.values_mut() {
+            *until = now;
+        }
+        self.end_highlights();
+
+        // Waiting clients get their replies now rather than after the teardown
+        let replies = self.deferred_replies.len();
+        for reply in &mut self.deferred_replies {
+            reply.deadline = now;
+        }
+        self.flush_deferred_replies(false);
+
+        self.set_read_only(true);
+        // Checkpoints start again once read-only mode is lifted
+        let checkpoint = self.next_checkpoint.take().is_some();
+        serde_json::json!({
+            "closed_panes": closed,
+            "cancelled_jobs": unfinished,
+            "closing_on_open": opening,
+            "cancelled_resizes": resizes,
+            "dropped_pages": pages,
+            "dropped_writes": writes,
+            "ended_highlights": highlights,
+            "cancelled_checkpoint": checkpoint,
+            "discarded_recording_steps": recording_steps,
+            "answered_replies": replies,
+            "read_only": self.config.read_only,
+        })
+    }
+
+    /// Whether `cmd` runs despite read-only mode: turning it on again, or off
+    /// under the trusted profile. Otherwise only the dashboard's `r` key lifts it.
+    fn read_only_exempt(&self, cmd: &Command) -> bool {
+        match cmd {
+            Command::SetReadOnly { enabled: true } => true,
+            Command::SetReadOnly { enabled: false } => self.config.profile.as_deref() == Some("trusted"),
+            _ => false,
+        }
+    }
+
+    /// Read-only mode is a config override, so `reload_config` lifts it
+    /// unless the plugin configuration sets it too
+    fn set_read_only(&mut self, enabled: bool) {
+        self.config_overrides.insert("read_only".to_string(), enabled.to_string());
+        self.apply_config();
+    }
+
+    fn config_response(&self, warnings: Vec<String>) -> Response {
+        Response {
+            success: warnings.is_empty(),
+            error: if warnings.is_empty() { None } else { Some(warnings.join("; ")) },
+            error_code: if warnings.is_empty() { None } else { Some(ErrorCode::InvalidConfig) },
+            data: Some(serde_json::json!({
+                "config": self.config,
+                "overrides": self.config_overrides,
+            })),
+        }
+    }
+
+    fn request_permissions(&mut self, permissions: &[PermissionType]) {
+        for permission in permissions {
+            self.permissions.insert(format!("{:?}", permission), PermissionState::Pending);
+        }
+        host!(request_permission(permissions));
+    }
+
+    /// Name of the permission `cmd` needs if the user explicitly denied it, or
+    /// if it is asked for on demand and not granted yet. Other pending
+    /// permissions are let through, the host drops the call if they never arrive.
+    fn denied_permission(&self, cmd: &Command) -> Option<String> {
+        let required = cmd.required_permission()?;
+        let permission = format!("{:?}", required);
+        let state = self.permissions.get(&permission);
+        let missing = ON_DEMAND_PERMISSIONS.contains(&required) && state != Some(&PermissionState::Granted);
+        (missing || state == Some(&PermissionState::Denied)).then_some(permission)
+    }
+
+    fn permissions_data(&self) -> serde_json::Value {
+        let all_granted = self.permissions.values().all(|s| *s == PermissionState::Granted);
+        serde_json::json!({
+            "permissions": self.permissions,
+            "all_granted": all_granted,
+        })
+    }
+
+    /// Recreate the `restore_on_load` snapshot once permissions, tabs and panes
+    /// are all known, if the session is fresh: a single tab whose terminals
+    /// run nothing the bridge did not start.
+    fn restore_on_load(&mut self) {
+        let granted = REQUIRED_PERMISSIONS.iter()
+            .all(|p| self.permissions.get(&format!("{:?}", p)) == Some(&PermissionState::Granted));
+        if !self.restore_pending || !granted || self.tabs.is_empty() || self.panes.is_empty() {
+            return;
+        }
+        self.restore_pending = false;
+        let fresh = self.tabs.len() == 1
+            && self.panes.values()
+                .flatten()
+                .all(|p| p.is_plugin || p.terminal_command.is_none() || self.owned_panes.contains(&p.id));
+        if !fresh {
+            return;
+        }
+        let Some(wanted) = self.config.restore_on_load.clone() else {
+            return;
+        };
+        let name = if wanted == "latest" {
+            self.snapshots.iter().max_by_key(|(_, s)| s.created_ms).map(|(name, _)| name.clone())
+        } else {
+            Some(wanted).filter(|name| self.snapshots.contains_key(name))
+        };
+        match name {
+            Some(name) => {
+                let response = self.restore_snapshot(&name);
+                eprintln!("zellij-pane-bridge: restored snapshot '{}': {}", name, response.data.unwrap_or_default());
+            }
+            None => eprintln!("zellij-pane-bridge: no snapshot to restore on load"),
+        }
+    }
+
+    /// Recreate the tabs and command panes of a snapshot. The active tab takes
+    /// the name of the snapshot's first tab; the others are opened anew.
+    fn restore_snapshot(&mut self, name: &str) -> Response {
+        let Some(snapshot) = self.snapshots.get(name) else {
+            return self.snapshot_not_found(name);
+        };
+        let tab_names: Vec<String> = snapshot.layout["tabs"].as_array()
+            .map(|tabs| tabs.iter().filter_map(|t| t["name"].as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        let runs: Vec<AutostartEntry> = snapshot.layout["panes"].as_array()
+            .map(|panes| {
+                panes.iter()
+                    .filter(|p| p["is_plugin"] != true)
+                    .filter_map(|p| {
+                        // The command line is all zellij reports, so split it the simple way
+                        let mut words = p["command"].as_str()?.split_whitespace().map(String::from);
+                        Some(AutostartEntry {
+                            command: words.next()?,
+                            args: words.collect(),
+                            name: p["title"].as_str().map(|t| strip_markers(t).to_string()).filter(|t| !t.is_empty()),
+                            cwd: None,
+                            floating: p["is_floating"] == true,
+                            background: false,
+                            tab: p["tab_index"].as_u64().and_then(|i| tab_names.get(i as usize)).cloned(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut tabs = tab_names.iter();
+        if let (Some(first), Some(active)) = (tabs.next(), self.active_tab_index()) {
+            host!(rename_tab(active as u32, first));
+        }
+        for tab in tabs {
+            let cwd = self.resolve_cwd(None);
+            host!(new_tab(Some(tab.as_str()), cwd.as_deref()));
+        }
+        for entry in &runs {
+            self.launch_entry(entry);
+        }
+        Response {
+            success: true,
+            error: None,
+            error_code: None,
+            data: Some(serde_json::json!({
+                "snapshot": name,
+                "tabs": tab_names,
+                "runs": runs.iter().map(|r| r.name.as_deref().unwrap_or(&r.command)).collect::<Vec<_>>(),
+            })),
+        }
+    }
+
+    fn launch_autostart(&self) {
+        for entry in &self.config.autostart {
+            self.launch_entry(entry);
+        }
+    }
+
+    /// The host folder as commands see it, the one `/host` maps to
+    fn host_folder(&self) -> String {
+        self.host_dir.clone().unwrap_or_else(|| get_plugin_ids().initial_cwd.to_string_lossy().into_owned())
+    }
+
+    /// The cwd a pane or tab opens in: the requested one or `default_cwd`, with
+    /// relative paths taken from the folder set by `change_host_dir`
+    fn resolve_cwd(&self, cwd: Option<String>) -> Option<String> {
+        let cwd = cwd.or_else(|| self.config.default_cwd.clone());
+        match (cwd, &self.host_dir) {
+            (Some(cwd), Some(host_dir)) if !cwd.starts_with('/') => {
+                Some(format!("{}/{}", host_dir.trim_end_matches('/'), cwd))
+            }
+            (cwd, _) => cwd,
+        }
+    }
+
+    /// Open the command pane an autostart or template entry describes
+    fn launch_entry(&self, entry: &AutostartEntry) {
+        if let Some(tab) = &entry.tab {
+            host!(focus_or_create_tab(tab));
+        }
+        let cmd = CommandToRun {
+            path: entry.command.clone().into(),
+            args: entry.args.clone(),
+            cwd: self.resolve_cwd(entry.cwd.clone()).map(|s| s.into()),
+        };
+        // The pane id is only known once it opens, so the name travels in the context
+        let mut context = BTreeMap::new();
+        if let Some(name) = &entry.name {
+            context.insert("pane_name".to_string(), name.clone());
+        }
+        let cwd = cmd.cwd.as_ref().map(|c| c.to_string_lossy().into_owned());
+        let (key, launch) = Launch::context(&entry.command, &entry.args, cwd.as_deref());
+        context.insert(key, launch);
+        if entry.floating {
+            host!(open_command_pane_floating(cmd, None, context));
+        } else if entry.background {
+            host!(open_command_pane_background(cmd, context));
+        } else {
+            host!(open_command_pane(cmd, context));
+        }
+    }
+
+    fn detect_protected_pane(&mut self) {
+        // Find the currently focused terminal pane - this is where Claude is running
+        for pane_list in self.panes.values() {
+            for p in pane_list {
+                if p.is_focused && !p.is_plugin {
+                    self.protected_pane_id = Some(p.id);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Whether commands may change a pane under the ownership policy
+    fn may_change(&self, pane_id: u32) -> bool {
+        !self.config.enforce_ownership || self.owned_panes.contains(&pane_id) || self.granted_panes.contains(&pane_id)
+    }
+
+    fn is_protected_pane(&self, pane_id: u32) -> bool {
+        // Check if this is the protected pane (Claude's pane)
+        if self.protected_pane_id == Some(pane_id) {
+            return true;
+        }
+
+        // Also check for panes with a protection keyword in name/command as fallback
+        if let Some((_, p)) = self.find_pane(pane_id) {
+            let title_lower = p.title.to_lowercase();
+            let command_lower = p.terminal_command.as_ref().map(|c| c.to_lowercase()).unwrap_or_default();
+            let command = p.terminal_command.as_deref().unwrap_or_default();
+            return self.config.protection_keywords.iter()
+                .any(|k| title_lower.contains(k.as_str()) || command_lower.contains(k.as_str()))
+                || self.protect_patterns.iter().any(|re| re.is_match(&p.title) || re.is_match(command));
+        }
+        false
+    }
+
+    fn focus_tab(&mut self, index: Option<u32>, name: Option<String>, create: bool) -> Response {
+        let tab_not_found = |what: String, tabs: &[TabInfo]| Response {
+            success: false,
+            error: Some(format!("Tab {} does not exist", what)),
+            error_code: Some(ErrorCode::TabNotFound),
+            data: Some(serde_json::json!({"tabs": tabs.iter().map(|t| &t.name).collect::<Vec<_>>()})),
+        };
+        match (index, name) {
+            (Some(index), None) if !create => {
+                if !self.tabs.iter().any(|t| t.position == index as usize) {
+                    return tab_not_found(index.to_string(), &self.tabs);
+                }
+                host!(go_to_tab(index));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"focused_tab": index})),
+                }
+            }
+            (None, Some(name)) => {
+                let exists = self.tabs.iter().any(|t| t.name == name);
+                if create {
+                    host!(focus_or_create_tab(&name));
+                } else if exists {
+                    host!(go_to_tab_name(&name));
+                } else {
+                    return tab_not_found(format!("'{}'", name), &self.tabs);
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"focused_tab": name, "created": !exists})),
+                }
+            }
+            _ => Response {
+                success: false,
+                error: Some("focus_tab needs either index, or name with an optional create".to_string()),
+                error_code: Some(ErrorCode::InvalidArgument),
+                data: None,
+            },
+        }
+    }
+
+    fn active_tab_index(&self) -> Option<usize> {
+        self.tabs.iter().find(|t| t.active).map(|t| t.position)
+    }
+
+    /// Run a focused-tab action against another tab, returning focus to the
+    /// originally active tab afterwards.
+    fn with_tab_focused(&self, index: Option<u32>, action: impl FnOnce()) -> Result<Option<String>, Response> {
+        let Some(index) = index else {
+            action();
+            return Ok(self.tabs.iter().find(|t| t.active).and_then(|t| t.active_swap_layout_name.clone()));
+        };
+        let Some(tab) = self.tabs.iter().find(|t| t.position == index as usize) else {
+            return Err(Response {
+                success: false,
+                error: Some(format!("Tab {} does not exist", index)),
+                error_code: Some(ErrorCode::TabNotFound),
+                data: Some(serde_json::json!({"tabs_count": self.tabs.len()})),
+            });
+        };
+        let original = self.active_tab_index();
+        if original != Some(tab.position) {
+            host!(go_to_tab(index));
+        }
+        action();
+        if let Some(original) = original.filter(|o| *o != tab.position) {
+            host!(go_to_tab(original as u32));
+        }
+        Ok(tab.active_swap_layout_name.clone())
+    }
+
+    /// Split a group's members into panes the operation may touch and
+    /// per-member results for the ones it must skip.
+    fn group_targets(&self, name: &str, force: bool, check_protection: bool)
+        -> Result<(Vec<u32>, Vec<serde_json::Value>), Response>
+    {
+        let Some(members) = self.groups.get(name) else {
+            return Err(Response {
+                success: false,
+                error: Some(format!("No pane group named '{}'", name)),
+                error_code: Some(ErrorCode::GroupNotFound),
+                data: Some(serde_json::json!({"groups": self.groups.keys().collect::<Vec<_>>()})),
+            });
+        };
+        let mut allowed = Vec::new();
+        let mut results = Vec::new();
+        for &pane_id in members {
+            if !self.may_change(pane_id) {
+                results.push(serde_json::json!({
+                    "pane_id": pane_id,
+                    "success": false,
+                    "error": "pane not opened by the bridge skipped (use grant_pane to allow)"
+                }));
+            } else if check_protection && !force && self.is_protected_pane(pane_id) {
+                results.push(serde_json::json!({
+                    "pane_id": pane_id,
+                    "success": false,
+                    "error": "protected pane skipped (use force:true to override)"
+                }));
+            } else {
+                allowed.push(pane_id);
+            }
+        }
+        Ok((allowed, results))
+    }
+
+    /// Terminal panes in a tab whose floating state is `floating`, split into
+    /// eligible ids and protected ids that were left alone.
+    fn tab_panes_to_toggle(&self, index: usize, floating: bool, force: bool) -> Option<(Vec<u32>, Vec<u32>)> {
+        let panes = self.panes.get(&index)?;
+        let (eligible, protected): (Vec<u32>, Vec<u32>) = panes.iter()
+            .filter(|p| !p.is_plugin && p.is_selectable && !p.is_suppressed && p.is_floating == floating)
+            .map(|p| p.id)
+            .partition(|id| force || !self.is_protected_pane(*id));
+        Some((eligible, protected))
+    }
+
+    fn set_tab_floating(&self, index: usize, floating: bool, force: bool) -> Response {
+        let Some((eligible, protected)) = self.tab_panes_to_toggle(index, !floating, force) else {
+            return Response {
+                success: false,
+                error: Some(format!("Tab {} does not exist", index)),
+                error_code: Some(ErrorCode::TabNotFound),
+                data: Some(serde_json::json!({"tabs_count": self.tabs.len()})),
+            };
+        };
+        let ids: Vec<PaneId> = eligible.iter().map(|id| PaneId::Terminal(*id)).collect();
+        if !ids.is_empty() {
+            if floating {
+                host!(float_multiple_panes(ids));
+            } else {
+                host!(embed_multiple_panes(ids));
+            }
+        }
+        Response {
+            success: true,
+            error: None,
+            error_code: None,
+            data: Some(serde_json::json!({
+                "tab_index": index,
+                "floating": floating,
+                "changed": eligible,
+                "skipped_protected": protected
+            })),
+        }
+    }
+
+    fn group_response(name: &str, allowed: &[u32], mut results: Vec<serde_json::Value>) -> Response {
+        results.extend(allowed.iter().map(|id| serde_json::json!({"pane_id": id, "success": true})));
+        let failed = results.len() - allowed.len();
+        Response {
+            success: failed == 0,
+            error: if failed == 0 { None } else { Some(format!("{} group member(s) skipped", failed)) },
+            error_code: if failed == 0 { None } else { Some(ErrorCode::PartialFailure) },
+            data: Some(serde_json::json!({"group": name, "results": results})),
+        }
+    }
+
+    fn find_pane(&self, pane_id: u32) -> Option<(usize, &PaneInfo)> {
+        let &(tab_idx, position) = self.pane_index.get(&pane_id)?;
+        self.panes.get(&tab_idx)?.get(position).map(|p| (tab_idx, p))
+    }
+
+    /// First terminal pane whose title or command contains `needle_lower`
+    fn matching_pane(&self, needle_lower: &str) -> Option<(usize, &PaneInfo)> {
+        let mut tab_indices: Vec<&usize> = self.panes.keys().collect();
+        tab_indices.sort_unstable();
+        tab_indices.into_iter().find_map(|tab_idx| {
+            self.panes[tab_idx].iter()
+                .find(|p| !p.is_plugin && pane_matches(p, needle_lower))
+                .map(|p| (*tab_idx, p))
+        })
+    }
+
+    /// Whether a pane's title has left `from` for one containing `pattern_lower`,
+    /// or the pane is gone and its title will never change again
+    fn title_changed(&self, pane_id: u32, from: &str, pattern_lower: Option<&str>) -> bool {
+        match self.find_pane(pane_id) {
+            Some((_, p)) => p.title != from
+                && pattern_lower.is_none_or(|pattern| p.title.to_lowercase().contains(pattern)),
+            None => true,
+        }
+    }
+
+    /// Rename badged command panes whose title does not show their current state.
+    /// A pane opened without a name keeps the title Zellij gave it, learned from
+    /// the first manifest that lists it.
+    fn refresh_badges(&mut self) {
+        if !self.config.status_badges {
+            return;
+        }
+        let mut renames = Vec::new();
+        for (&pane_id, base) in self.badge_titles.iter_mut() {
+            // The highlight puts the badge back when it ends
+            if self.highlights.contains_key(&pane_id) {
+                continue;
+            }
+            let current = self.pane_index.get(&pane_id)
+                .and_then(|(tab_idx, position)| self.panes.get(tab_idx)?.get(*position))
+                .map(|p| p.title.as_str());
+            if base.is_none() {
+                *base = current.map(|title| strip_markers(title).to_string());
+            }
+            let Some(base) = base.as_deref() else {
+                continue;
+            };
+            let badge = match self.command_results.get(&pane_id) {
+                Some(CommandResult { exited: true, exit_code: Some(0), .. }) => "✓".to_string(),
+                Some(CommandResult { exited: true, exit_code: Some(code), .. }) => format!("✗ exit {}", code),
+                Some(CommandResult { exited: true, .. }) => "✗".to_string(),
+                _ => "⏳".to_string(),
+            };
+            let title = format!("{} {}", badge, base);
+            if current != Some(title.as_str()) {
+                renames.push((pane_id, title));
+            }
+        }
+        for (pane_id, title) in renames {
+            host!(rename_terminal_pane(pane_id, &title));
+        }
+    }
+
+    /// Give highlighted panes whose time is up their title back
+    fn end_highlights(&mut self) {
+        let now = Instant::now();
+        let ended: Vec<u32> = self.highlights.iter()
+            .filter(|(_, (_, until))| *until <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for pane_id in ended {
+            let Some((title, _)) = self.highlights.remove(&pane_id) else {
+                continue;
+            };
+            if !self.badge_titles.contains_key(&pane_id) && self.find_pane(pane_id).is_some() {
+                host!(rename_terminal_pane(pane_id, &title));
+            }
+        }
+        self.refresh_badges();
+    }
+
+    /// First terminal pane whose title is exactly `title`, ignoring bridge markers
+    fn find_pane_by_title(&self, title: &str) -> Option<(usize, &PaneInfo)> {
+        let id = *self.title_index.get(title)?.first()?;
+        self.find_pane(id)
+    }
+
+    /// Take a new manifest, re-indexing only the tabs whose panes changed. Large
+    /// sessions mostly see updates that touch one tab. The indexes are updated at
+    /// once since every command relies on them; summaries wait for the burst to end.
+    fn apply_manifest(&mut self, panes: HashMap<usize, Vec<PaneInfo>>) {
+        let changed: Vec<usize> = panes.iter()
+            .filter(|(tab_idx, list)| self.panes.get(tab_idx) != Some(list))
+            .map(|(tab_idx, _)| *tab_idx)
+            .chain(self.panes.keys().filter(|tab_idx| !panes.contains_key(tab_idx)).copied())
+            .collect();
+        if changed.is_empty() {
+            self.panes = panes;
+            return;
+        }
+        self.log_pane_changes(&changed, &panes);
+        self.panes = panes;
+
+        self.pane_index.retain(|_, (tab_idx, _)| !changed.contains(tab_idx));
+        for ids in self.title_index.values_mut() {
+            ids.retain(|id| self.pane_index.contains_key(id));
+        }
+        if self.stale_summaries.is_empty() {
+            host!(set_timeout(SUMMARY_DEBOUNCE_SECS));
+        }
+        self.last_manifest_change = Some(Instant::now());
+        for tab_idx in changed {
+            self.stale_summaries.insert(tab_idx);
+            let Some(list) = self.panes.get(&tab_idx) else {
+                continue;
+            };
+            for (position, p) in list.iter().enumerate().filter(|(_, p)| !p.is_plugin) {
+                self.pane_index.insert(p.id, (tab_idx, position));
+                self.title_index.entry(strip_markers(&p.title).to_string()).or_default().push(p.id);
+            }
+        }
+        self.title_index.retain(|_, ids| !ids.is_empty());
+    }
+
+    /// Log panes that opened, closed or changed title in the `changed` tabs.
+    /// Panes are matched by id across those tabs, so a move is not a close.
+    fn log_pane_changes(&mut self, changed: &[usize], panes: &HashMap<usize, Vec<PaneInfo>>) {
+        let terminals = |manifest: &HashMap<usize, Vec<PaneInfo>>| -> BTreeMap<u32, (usize, String)> {
+            changed.iter()
+                .filter_map(|tab_idx| manifest.get(tab_idx).map(|list| (*tab_idx, list)))
+                .flat_map(|(tab_idx, list)| {
+                    list.iter().filter(|p| !p.is_plugin).map(move |p| (p.id, (tab_idx, p.title.clone())))
+                })
+                .collect()
+        };
+        let before = terminals(&self.panes);
+        let after = terminals(panes);
+        for (&pane_id, (tab_idx, title)) in &after {
+            let kind = match before.get(&pane_id) {
+                None => "pane_opened",
+                Some((_, old_title)) if old_title != title => "pane_title_changed",
+                Some(_) => continue,
+            };
+            self.log_event(BridgeEvent {
+                kind,
+                pane_id: Some(pane_id),
+                tab: Some(*tab_idx),
+                title: Some(title.clone()),
+                ..Default::default()
+            });
+        }
+        for (&pane_id, (tab_idx, title)) in before.iter().filter(|(id, _)| !after.contains_key(id)) {
+            self.log_event(BridgeEvent {
+                kind: "pane_closed",
+                pane_id: Some(pane_id),
+                tab: Some(*tab_idx),
+                title: Some(title.clone()),
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Log tabs that were created, closed or activated. Tabs have no stable id,
+    /// so they are matched by name and a rename shows as a close and a create.
+    fn log_tab_changes(&mut self, tabs: &[TabInfo]) {
+        let mut events = Vec::new();
+        for tab in tabs.iter().filter(|t| !self.tabs.iter().any(|old| old.name == t.name)) {
+            events.push(("tab_created", tab));
+        }
+        for tab in self.tabs.iter().filter(|t| !tabs.iter().any(|new| new.name == t.name)) {
+            events.push(("tab_closed", tab));
+        }
+        let was_active = self.tabs.iter().find(|t| t.active).map(|t| &t.name);
+        if let Some(tab) = tabs.iter().find(|t| t.active).filter(|t| Some(&t.name) != was_active) {
+            events.push(("tab_activated", tab));
+        }
+        let events: Vec<BridgeEvent> = events.into_iter()
+            .map(|(kind, tab)| BridgeEvent {
+                kind,
+                tab: Some(tab.position),
+                title: Some(tab.name.clone()),
+                ..Default::default()
+            })
+            .collect();
+        for event in events {
+            self.log_event(event);
+        }
+    }
+
+    /// Oldest logged event from `since_cursor` on that passes `filter`
+    fn next_event(&self, filter: &EventFilter, since_cursor: u64) -> Option<&BridgeEvent> {
+        self.events.iter().find(|e| e.cursor >= since_cursor && filter.matches(e))
+    }
+
+    /// Append to the change feed, stamping cursor and time
+    fn log_event(&mut self, mut event: BridgeEvent) {
+        event.cursor = self.event_cursor;
+        event.ts_ms = unix_ms();
+        self.event_cursor += 1;
+        if let Some(sink) = &self.config.event_sink {
+            // The host folder is mounted at /host inside the plugin sandbox
+            let path = if sink.starts_with('/') { sink.clone() } else { format!("/host/{}", sink) };
+            let line = serde_json::to_string(&event).unwrap_or_default() + "\n";
+            if let Err(e) = write_file(&path, line.as_bytes(), true) {
+                eprintln!("zellij-pane-bridge: cannot append event to {}: {}", path, e);
+            }
+        }
+        self.events.push_back(event);
+        while self.events.len() > EVENT_LOG_CAPACITY {
+            self.events.pop_front();
+        }
+    }
+
+    /// Refresh summaries once no manifest has changed anything for the debounce
+    /// period, otherwise wait out the rest of it
+    fn refresh_settled_summaries(&mut self) {
+        if self.stale_summaries.is_empty() {
+            return;
+        }
+        let debounce = Duration::from_secs_f64(SUMMARY_DEBOUNCE_SECS);
+        let quiet_for = self.last_manifest_change.map(|t| t.elapsed()).unwrap_or(debounce);
+        if quiet_for >= debounce {
+            self.refresh_summaries();
+        } else {
+            host!(set_timeout((debounce - quiet_for).as_secs_f64()));
+        }
+    }
+
+    /// Rebuild the summaries of tabs changed since the last refresh. Runs once
+    /// updates settle, and before anything reads the cache.
+    fn refresh_summaries(&mut self) {
+        for tab_idx in std::mem::take(&mut self.stale_summaries) {
+            match self.panes.get(&tab_idx) {
+                Some(list) => {
+                    let summaries = list.iter()
+                        .map(|p| {
+                            let mut summary = PaneSummary::new(tab_idx, p);
+                            summary.ready_at_prompt = self.at_prompt(p);
+                            summary.handle = self.pane_handles.get(&p.id).cloned();
+                            serde_json::to_value(summary).unwrap_or_default()
+                        })
+                        .collect();
+                    self.summary_cache.insert(tab_idx, summaries);
+                }
+                None => {
+                    self.summary_cache.remove(&tab_idx);
+                }
+            }
+        }
+    }
+
+    /// Whether a terminal pane's title matches a prompt pattern, or `None` when
+    /// no patterns are configured
+    fn at_prompt(&self, p: &PaneInfo) -> Option<bool> {
+        if self.prompt_patterns.is_empty() || p.is_plugin {
+            return None;
+        }
+        let title = strip_markers(&p.title);
+        Some(self.prompt_patterns.iter().any(|re| re.is_match(title)))
+    }
+
+    /// Cached summaries of all panes, in tab order
+    fn all_summaries(&self) -> Vec<serde_json::Value> {
+        let mut tab_indices: Vec<&usize> = self.summary_cache.keys().collect();
+        tab_indices.sort_unstable();
+        tab_indices.into_iter().flat_map(|t| self.summary_cache[t].iter().cloned()).collect()
+    }
+
+    /// A pane's summary with its tab, geometry and the tiled panes next to it
+    fn pane_location(&self, pane_id: u32) -> Option<serde_json::Value> {
+        let &(tab_idx, position) = self.pane_index.get(&pane_id)?;
+        let pane = self.panes.get(&tab_idx)?.get(position)?;
+        let tab = self.tabs.iter().find(|t| t.position == tab_idx);
+        let mut neighbors: BTreeMap<&str, Vec<u32>> = BTreeMap::new();
+        if !pane.is_floating {
+            for other in &self.panes[&tab_idx] {
+                if other.id == pane.id || other.is_plugin || other.is_floating || other.is_suppressed {
+                    continue;
+                }
+                let side = match Self::adjacent_direction(pane, other) {
+                    Some(Direction::Left) => "left",
+                    Some(Direction::Right) => "right",
+                    Some(Direction::Up) => "up",
+                    Some(Direction::Down) => "down",
+                    None => continue,
+                };
+                neighbors.entry(side).or_default().push(other.id);
+            }
+        }
+        Some(serde_json::json!({
+            "pane": self.summary_cache.get(&tab_idx).and_then(|s| s.get(position)),
+            "tab_index": tab_idx,
+            "tab_name": tab.map(|t| &t.name),
+            "tab_active": tab.is_some_and(|t| t.active),
+            "geometry": {
+                "x": pane.pane_x,
+                "y": pane.pane_y,
+                "rows": pane.pane_rows,
+                "cols": pane.pane_columns,
+                "content_rows": pane.pane_content_rows,
+                "content_cols": pane.pane_content_columns,
+            },
+            "is_floating": pane.is_floating,
+            "is_suppressed": pane.is_suppressed,
+            "is_focused": pane.is_focused,
+            "neighbors": neighbors,
+        }))
+    }
+
+    /// Direction from `a` to `b` when the two tiled panes share an edge
+    fn adjacent_direction(a: &PaneInfo, b: &PaneInfo) -> Option<Direction> {
+        let overlaps = |a_start: usize, a_len: usize, b_start: usize, b_len: usize| {
+            a_start < b_start + b_len && b_start < a_start + a_len
+        };
+        let rows_overlap = overlaps(a.pane_y, a.pane_rows, b.pane_y, b.pane_rows);
+        let cols_overlap = overlaps(a.pane_x, a.pane_columns, b.pane_x, b.pane_columns);
+        if rows_overlap && b.pane_x == a.pane_x + a.pane_columns {
+            Some(Direction::Right)
+        } else if rows_overlap && a.pane_x == b.pane_x + b.pane_columns {
+            Some(Direction::Left)
+        } else if cols_overlap && b.pane_y == a.pane_y + a.pane_rows {
+            Some(Direction::Down)
+        } else if cols_overlap && a.pane_y == b.pane_y + b.pane_rows {
+            Some(Direction::Up)
+        } else {
+            None
+        }
+    }
+
+    fn swap(&self, pane_a: u32, pane_b: u32, force: bool) -> Response {
+        let (Some((tab_a, a)), Some((tab_b, b))) = (self.find_pane(pane_a), self.find_pane(pane_b)) else {
+            return Response {
+                success: false,
+                error: Some("Both panes must exist to swap them".to_string()),
+                error_code: Some(ErrorCode::PaneNotFound),
+                data: Some(serde_json::json!({"pane_a": pane_a, "pane_b": pane_b})),
+            };
+        };
+
+        if tab_a != tab_b {
+            if !force && (self.is_protected_pane(pane_a) || self.is_protected_pane(pane_b)) {
+                return Response {
+                    success: false,
+                    error: Some("Cannot move Claude pane to another tab (use force:true to override)".to_string()),
+                    error_code: Some(ErrorCode::Protected),
+                    data: Some(serde_json::json!({"pane_a": pane_a, "pane_b": pane_b})),
+                };
+            }
+            // Positions within the tabs are not preserved, only tab membership
+            host!(break_panes_to_tab_with_index(&[PaneId::Terminal(pane_a)], tab_b, false));
+            host!(break_panes_to_tab_with_index(&[PaneId::Terminal(pane_b)], tab_a, false));
+            return Response {
+                success: true,
+                error: None,
+                error_code: None,
+                data: Some(serde_json::json!({"swapped": [pane_a, pane_b], "method": "tabs"})),
+            };
+        }
+
+        if a.is_floating && b.is_floating {
+            let coordinates = |p: &PaneInfo| FloatingPaneCoordinates::new(
+                Some(p.pane_x.to_string()),
+                Some(p.pane_y.to_string()),
+                Some(p.pane_columns.to_string()),
+                Some(p.pane_rows.to_string()),
+                None,
+            );
+            if let (Some(a_coords), Some(b_coords)) = (coordinates(a), coordinates(b)) {
+                host!(change_floating_panes_coordinates(vec![
+                    (PaneId::Terminal(pane_a), b_coords),
+                    (PaneId::Terminal(pane_b), a_coords),
+                ]));
+                return Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"swapped": [pane_a, pane_b], "method": "floating_coordinates"})),
+                };
+            }
+        } else if !a.is_floating && !b.is_floating {
+            // Moving a tiled pane toward a neighbour exchanges the two
+            if let Some(dir) = Self::adjacent_direction(a, b) {
+                host!(move_pane_with_pane_id_in_direction(PaneId::Terminal(pane_a), dir));
+                return Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"swapped": [pane_a, pane_b], "method": "move"})),
+                };
+            }
+        }
+
+        Response {
+            success: false,
+            error: Some("Panes can only be swapped when both float, when tiled side by side, or when in different tabs".to_string()),
+            error_code: Some(ErrorCode::Unsupported),
+            data: Some(serde_json::json!({
+                "pane_a": {"id": pane_a, "is_floating": a.is_floating},
+                "pane_b": {"id": pane_b, "is_floating": b.is_floating},
+            })),
+        }
+    }
+
+    fn resize(
+        &mut self,
+        pane_id: u32,
+        direction: Option<String>,
+        amount: Option<ResizeAmount>,
+        shrink: bool,
+        rows: Option<usize>,
+        cols: Option<usize>,
+    ) -> Response {
+        let resize = if shrink { Resize::Decrease } else { Resize::Increase };
+        let direction_lower = direction.as_deref().map(str::to_lowercase);
+        let dir = match direction_lower.as_deref() {
+            Some("up") => Some(Direction::Up),
+            Some("down") => Some(Direction::Down),
+            Some("left") => Some(Direction::Left),
+            Some("right") => Some(Direction::Right),
+            // Legacy form: grow or shrink without an axis
+            Some("increase") | Some("decrease") | None => None,
+            Some(other) => {
+                return Response {
+                    success: false,
+                    error: Some(format!("Invalid resize direction '{}' (expected left, right, up, down, increase or decrease)", other)),
+                    error_code: Some(ErrorCode::InvalidArgument),
+                    data: None,
+                };
+            }
+        };
+        let resize = match direction_lower.as_deref() {
+            Some("decrease") => Resize::Decrease,
+            Some("increase") => Resize::Increase,
+            _ => resize,
+        };
+
+        // Exact targets either come directly or from an amount along the direction's axis
+        let (mut target_rows, mut target_cols) = (rows, cols);
+        if let Some(amount) = amount {
+            let Some(dir) = dir else {
+                return Response {
+                    success: false,
+                    error: Some("resize amount needs a direction of left, right, up or down".to_string()),
+                    error_code: Some(ErrorCode::InvalidArgument),
+                    data: None,
+                };
+            };
+            let Some((tab_idx, pane)) = self.find_pane(pane_id) else {
+                return Response {
+                    success: false,
+                    error: Some(format!("Pane {} not found", pane_id)),
+                    error_code: Some(ErrorCode::PaneNotFound),
+                    data: None,
+                };
+            };
+            let horizontal = matches!(dir, Direction::Left | Direction::Right);
+            let (current, extent) = {
+                let tab = self.tabs.iter().find(|t| t.position == tab_idx);
+                if horizontal {
+                    (pane.pane_content_columns, tab.map(|t| t.display_area_columns).unwrap_or(0))
+                } else {
+                    (pane.pane_content_rows, tab.map(|t| t.display_area_rows).unwrap_or(0))
+                }
+            };
+            let cells = match amount {
+                ResizeAmount::Cells(cells) => cells,
+                ResizeAmount::Percent(text) => match text.trim().trim_end_matches('%').parse::<f64>() {
+                    Ok(pct) => ((extent as f64) * pct / 100.0).round() as usize,
+                    Err(_) => {
+                        return Response {
+                            success: false,
+                            error: Some(format!("Invalid resize amount '{}' (expected cells or a percentage like \"25%\")", text)),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: None,
+                        };
+                    }
+                },
+            };
+            let target = if shrink { current.saturating_sub(cells) } else { current + cells };
+            if horizontal {
+                target_cols = Some(target);
+            } else {
+                target_rows = Some(target);
+            }
+        }
+
+        if target_rows.is_none() && target_cols.is_none() {
+            host!(resize_pane_with_id(ResizeStrategy::new(resize, dir), PaneId::Terminal(pane_id)));
+            return Response {
+                success: true,
+                error: None,
+                error_code: None,
+                data: Some(serde_json::json!({"resized": pane_id, "direction": direction, "shrink": shrink})),
+            };
+        }
+
+        let Some((_, pane)) = self.find_pane(pane_id) else {
+            return Response {
+                success: false,
+                error: Some(format!("Pane {} not found", pane_id)),
+                error_code: Some(ErrorCode::PaneNotFound),
+                data: None,
+            };
+        };
+        let mut pending = PendingResize {
+            target_rows,
+            target_cols,
+            steps_left: MAX_RESIZE_STEPS,
+            last_size: (0, 0),
+            last_diff: (0, 0),
+            stalls: 0,
+        };
+        let (rows_now, cols_now) = (pane.pane_content_rows, pane.pane_content_columns);
+        let done = !Self::step_resize(pane_id, &mut pending, rows_now, cols_now);
+        if !done {
+            self.pending_resizes.insert(pane_id, pending);
+        }
+        Response {
+            success: true,
+            error: None,
+            error_code: None,
+            data: Some(serde_json::json!({
+                "resized": pane_id,
+                "target_rows": target_rows,
+                "target_cols": target_cols,
+                "rows": rows_now,
+                "cols": cols_now,
+                "pending": !done
+            })),
+        }
+    }
+
+    /// Issue one resize step toward the pending target. Returns false once the
+    /// target is reached, overshot, blocked, or out of steps.
+    fn step_resize(pane_id: u32, pending: &mut PendingResize, rows: usize, cols: usize) -> bool {
+        let diff = |target: Option<usize>, now: usize| target.map(|t| t as i64 - now as i64).unwrap_or(0);
+        let (row_diff, col_diff) = (diff(pending.target_rows, rows), diff(pending.target_cols, cols));
+        let crossed = |prev: i64, now: i64| prev != 0 && prev.signum() != now.signum();
+        let started = pending.steps_left < MAX_RESIZE_STEPS;
+        if (row_diff == 0 && col_diff == 0)
+            || pending.steps_left == 0
+            || (started && (crossed(pending.last_diff.0, row_diff) || crossed(pending.last_diff.1, col_diff)))
+        {
+            return false;
+        }
+        if started && pending.last_size == (rows, cols) {
+            // Unrelated updates can arrive before our step lands; a pane blocked
+            // by its neighbours never changes, so give up after a few of them
+            pending.stalls += 1;
+            return pending.stalls < 3;
+        }
+        pending.stalls = 0;
+        // Columns first, then rows; the host flips direction at screen edges
+        let (axis_diff, direction) = if col_diff != 0 {
+            (col_diff, Direction::Right)
+        } else {
+            (row_diff, Direction::Down)
+        };
+        let resize = if axis_diff > 0 { Resize::Increase } else { Resize::Decrease };
+        host!(resize_pane_with_id(ResizeStrategy::new(resize, Some(direction)), PaneId::Terminal(pane_id)));
+        pending.steps_left -= 1;
+        pending.last_size = (rows, cols);
+        pending.last_diff = (row_diff, col_diff);
+        true
+    }
+
+    fn advance_pending_resizes(&mut self) {
+        let mut pending = std::mem::take(&mut self.pending_resizes);
+        pending.retain(|pane_id, resize| {
+            match self.find_pane(*pane_id) {
+                Some((_, pane)) => Self::step_resize(*pane_id, resize, pane.pane_content_rows, pane.pane_content_columns),
+                None => false,
+            }
+        });
+        self.pending_resizes = pending;
+    }
+
+    fn execute_command(&mut self, cmd: Command) -> Response {
+        match cmd {
+            // === WRITE ===
+            Command::Write { pane_id, chars, allow_exited } => {
+                if let Some(response) = self.check_exited_write(pane_id, allow_exited) {
+                    return response;
+                }
+                let written = chars.len();
+                let position = self.queue_write(pane_id, QueuedWrite::Chars(chars));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(self.write_data(pane_id, written, position)),
+                }
+            }
+
+            Command::WriteBytes { pane_id, bytes, allow_exited } => {
+                if let Some(response) = self.check_exited_write(pane_id, allow_exited) {
+                    return response;
+                }
+                let written = bytes.len();
+                let position = self.queue_write(pane_id, QueuedWrite::Bytes(bytes));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(self.write_data(pane_id, written, position)),
+                }
+            }
+
+            // === READ ===
+            Command::List => {
+                self.refresh_summaries();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::Value::Array(self.all_summaries())),
+                }
+            }
+
+            Command::Query { name } => {
+                self.refresh_summaries();
+                let name_lower = name.to_lowercase();
+                let matches: Vec<serde_json::Value> = self.panes.iter()
+                    .flat_map(|(tab_idx, pane_list)| {
+                        let summaries = self.summary_cache.get(tab_idx).into_iter().flatten();
+                        pane_list.iter()
+                            .zip(summaries)
+                            .filter(|(p, _)| pane_matches(p, &name_lower))
+                            .map(|(_, summary)| summary.clone())
+                    })
+                    .collect();
+
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::Value::Array(matches)),
+                }
+            }
+
+            Command::FindPane { pane_id, name } => {
+                let found = match (pane_id, &name) {
+                    (Some(pane_id), None) => self.find_pane(pane_id),
+                    (None, Some(name)) => self.find_pane_by_title(name)
+                        .or_else(|| self.matching_pane(&name.to_lowercase())),
+                    _ => {
+                        return Response {
+                            success: false,
+                            error: Some("find_pane needs either pane_id or name".to_string()),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: None,
+                        };
+                    }
+                };
+                let Some(id) = found.map(|(_, p)| p.id) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("No pane matches {}", pane_id.map_or_else(|| format!("'{}'", name.unwrap_or_default()), |id| id.to_string()))),
+                        error_code: Some(ErrorCode::PaneNotFound),
+                        data: None,
+                    };
+                };
+                self.refresh_summaries();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: self.pane_location(id),
+                }
+            }
+
+            Command::ListTabs => {
+                let tabs: Vec<TabSummary> = self.tabs.iter().map(TabSummary::new).collect();
+
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::to_value(tabs).unwrap_or_default()),
+                }
+            }
+
+            Command::WaitForPane { pattern, timeout_secs } => {
+                let timeout_secs = timeout_secs.unwrap_or(WAIT_FOR_PANE_DEFAULT_TIMEOUT_SECS);
+                if !timeout_secs.is_finite() || timeout_secs <= 0.0 || timeout_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!("timeout_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, timeout_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let pattern = pattern.to_lowercase();
+                // A pane that is already there answers at once
+                let pane = self.matching_pane(&pattern)
+                    .map(|(tab_idx, p)| serde_json::to_value(PaneSummary::new(tab_idx, p)).unwrap_or_default());
+                if pane.is_none() {
+                    self.defer_reply = Some(Waiting::PaneAppears { pattern, timeout_secs });
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"pane": pane})),
+                }
+            }
+
+            Command::WaitForTitleChange { pane_id, pattern, timeout_secs } => {
+                let timeout_secs = timeout_secs.unwrap_or(WAIT_FOR_PANE_DEFAULT_TIMEOUT_SECS);
+                if !timeout_secs.is_finite() || timeout_secs <= 0.0 || timeout_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!("timeout_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, timeout_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let Some((_, pane)) = self.find_pane(pane_id) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Pane {} not found", pane_id)),
+                        error_code: Some(ErrorCode::PaneNotFound),
+                        data: Some(serde_json::json!({"pane_id": pane_id})),
+                    };
+                };
+                let from = pane.title.clone();
+                self.defer_reply = Some(Waiting::TitleChange {
+                    pane_id,
+                    from: from.clone(),
+                    pattern: pattern.map(|p| p.to_lowercase()),
+                    timeout_secs,
+                });
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"pane_id": pane_id, "from": from})),
+                }
+            }
+
+            Command::WaitForQuiet { pane_id, quiet_secs, timeout_secs } => {
+                let quiet_secs = quiet_secs.unwrap_or(WAIT_FOR_QUIET_DEFAULT_SECS);
+                let timeout_secs = timeout_secs.unwrap_or(WAIT_FOR_PANE_DEFAULT_TIMEOUT_SECS);
+                if !quiet_secs.is_finite()
+                    || quiet_secs <= 0.0
+                    || !timeout_secs.is_finite()
+                    || timeout_secs <= quiet_secs
+                    || timeout_secs > MAX_WAIT_SECS
+                {
+                    return Response {
+                        success: false,
+                        error: Some(format!(
+                            "quiet_secs must be positive and below timeout_secs, which is at most {}, got {} and {}",
+                            MAX_WAIT_SECS, quiet_secs, timeout_secs
+                        )),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                if self.find_pane(pane_id).is_none() {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Pane {} not found", pane_id)),
+                        error_code: Some(ErrorCode::PaneNotFound),
+                        data: Some(serde_json::json!({"pane_id": pane_id})),
+                    };
+                }
+                host!(set_timeout(quiet_secs));
+                self.defer_reply = Some(Waiting::Quiet {
+                    pane_id,
+                    quiet_secs,
+                    fingerprint: self.pane_fingerprint(pane_id),
+                    since: Instant::now(),
+                    timeout_secs,
+                });
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"pane_id": pane_id, "quiet_secs": quiet_secs})),
+                }
+            }
+
+            Command::WaitForPrompt { pane_id, timeout_secs } => {
+                let timeout_secs = timeout_secs.unwrap_or(WAIT_FOR_PANE_DEFAULT_TIMEOUT_SECS);
+                if !timeout_secs.is_finite() || timeout_secs <= 0.0 || timeout_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!("timeout_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, timeout_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                if self.prompt_patterns.is_empty() {
+                    return Response {
+                        success: false,
+                        error: Some("No prompt_title_patterns configured, so prompts cannot be recognised".to_string()),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let Some(ready) = self.pane_at_prompt(pane_id) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Pane {} not found", pane_id)),
+                        error_code: Some(ErrorCode::PaneNotFound),
+                        data: Some(serde_json::json!({"pane_id": pane_id})),
+                    };
+                };
+                // A pane already at its prompt answers at once
+                if !ready {
+                    self.defer_reply = Some(Waiting::Prompt { pane_id, timeout_secs });
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"pane_id": pane_id, "ready_at_prompt": ready})),
+                }
+            }
+
+            Command::TabStatus { index } => {
+                let Some(tab) = self.tabs.iter().find(|t| t.position == index) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Tab {} does not exist", index)),
+                        error_code: Some(ErrorCode::TabNotFound),
+                        data: None,
+                    };
+                };
+                let panes = self.panes.get(&index).map(Vec::as_slice).unwrap_or_default();
+                let terminals: Vec<&PaneInfo> = panes.iter().filter(|p| !p.is_plugin).collect();
+                let running: Vec<u32> = terminals.iter()
+                    .filter(|p| self.command_results.get(&p.id).is_some_and(|r| !r.exited))
+                    .map(|p| p.id)
+                    .collect();
+                let failed: Vec<serde_json::Value> = terminals.iter()
+                    .filter_map(|p| {
+                        let code = self.exited_status(p.id)?;
+                        (code != Some(0)).then(|| serde_json::json!({"pane_id": p.id, "exit_code": code}))
+                    })
+                    .collect();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "index": index,
+                        "name": tab.name,
+                        "active": tab.active,
+                        "terminal_panes": terminals.len(),
+                        "plugin_panes": panes.len() - terminals.len(),
+                        "floating_panes": terminals.iter().filter(|p| p.is_floating).count(),
+                        "exited_panes": terminals.iter().filter(|p| p.exited).count(),
+                        "running": running,
+                        "failed": failed,
+                    })),
+                }
+            }
+
+            // === PANE CONTROL ===
+            Command::Focus { pane_id } => {
+                host!(focus_terminal_pane(pane_id, true));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"focused": pane_id})),
+                }
+            }
+
+            Command::Close { pane_id, force } => {
+                if !force && self.is_protected_pane(pane_id) {
+                    Response {
+                        success: false,
+                        error: Some("Cannot close Claude pane - this would terminate the agent (use force:true to override)".to_string()),
+                        error_code: Some(ErrorCode::Protected),
+                        data: Some(serde_json::json!({"protected_pane": pane_id})),
+                    }
+                } else {
+                    host!(close_terminal_pane(pane_id));
+                    Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({"closed": pane_id})),
+                    }
+                }
+            }
+
+            Command::Hide { pane_id, force } => {
+                if !force && self.is_protected_pane(pane_id) {
+                    Response {
+                        success: false,
+                        error: Some("Cannot hide Claude pane - this would disrupt the agent (use force:true to override)".to_string()),
+                        error_code: Some(ErrorCode::Protected),
+                        data: Some(serde_json::json!({"protected_pane": pane_id})),
+                    }
+                } else {
+                    host!(hide_pane_with_id(PaneId::Terminal(pane_id)));
+                    Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({"hidden": pane_id})),
+                    }
+                }
+            }
+
+            Command::Show { pane_id } => {
+                host!(show_pane_with_id(PaneId::Terminal(pane_id), false));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"shown": pane_id})),
+                }
+            }
+
+            Command::Clear { pane_id } => {
+                host!(clear_screen_for_pane_id(PaneId::Terminal(pane_id)));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"cleared": pane_id})),
+                }
+            }
+
+            Command::Fullscreen { pane_id } => {
+                host!(toggle_pane_id_fullscreen(PaneId::Terminal(pane_id)));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"toggled_fullscreen": pane_id})),
+                }
+            }
+
+            Command::Highlight { pane_id, duration_secs } => {
+                let duration_secs = duration_secs.unwrap_or(HIGHLIGHT_DEFAULT_SECS);
+                if !duration_secs.is_finite() || duration_secs <= 0.0 || duration_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!(
+                            "duration_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, duration_secs
+                        )),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let until = Instant::now() + Duration::from_secs_f64(duration_secs);
+                match self.highlights.get_mut(&pane_id) {
+                    // Highlighting again only extends the highlight
+                    Some((_, ends)) => *ends = until,
+                    None => {
+                        let title = self.find_pane(pane_id).map(|(_, p)| p.title.clone()).unwrap_or_default();
+                        host!(rename_terminal_pane(pane_id, &format!("{}{}", HIGHLIGHT_MARKER, title)));
+                        self.highlights.insert(pane_id, (title, until));
+                    }
+                }
+                host!(set_timeout(duration_secs));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"highlighted": pane_id, "duration_secs": duration_secs})),
+                }
+            }
+
+            Command::RenamePane { pane_id, name } => {
+                // Markers stay, in front of the new name
+                let highlighted = match self.highlights.get_mut(&pane_id) {
+                    Some((title, _)) => {
+                        *title = name.clone();
+                        true
+                    }
+                    None => false,
+                };
+                match self.badge_titles.get_mut(&pane_id) {
+                    Some(base) => {
+                        *base = Some(name.clone());
+                        self.refresh_badges();
+                    }
+                    None if highlighted => {
+                        host!(rename_terminal_pane(pane_id, &format!("{}{}", HIGHLIGHT_MARKER, name)));
+                    }
+                    None => host!(rename_terminal_pane(pane_id, &name)),
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"renamed": pane_id, "name": name})),
+                }
+            }
+
+            Command::Move { pane_id, direction } => {
+                let dir = match direction.to_lowercase().as_str() {
+                    "up" => Direction::Up,
+                    "down" => Direction::Down,
+                    "left" => Direction::Left,
+                    "right" => Direction::Right,
+                    _ => Direction::Right,
+                };
+                host!(move_pane_with_pane_id_in_direction(PaneId::Terminal(pane_id), dir));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"moved": pane_id, "direction": direction})),
+                }
+            }
+
+            Command::Swap { pane_a, pane_b, force } => self.swap(pane_a, pane_b, force),
+
+            Command::Resize { pane_id, direction, amount, shrink, rows, cols } => {
+                self.resize(pane_id, direction, amount, shrink, rows, cols)
+            }
+
+            Command::Undo { steps } => {
+                let mut undone = Vec::new();
+                let mut failed = 0;
+                for _ in 0..steps.unwrap_or(1) {
+                    let Some(step) = self.undo_stack.pop_back() else {
+                        break;
+                    };
+                    // Panes may have closed since; a failed step does not stop the rest
+                    let response = self.execute_command(step.inverse);
+                    if !response.success {
+                        failed += 1;
+                    }
+                    undone.push(serde_json::json!({
+                        "undoes": step.undoes,
+                        "success": response.success,
+                        "error": response.error,
+                    }));
+                }
+                Response {
+                    success: failed == 0,
+                    error: (failed > 0).then(|| format!("{} of {} undo steps failed", failed, undone.len())),
+                    error_code: (failed > 0).then_some(ErrorCode::PartialFailure),
+                    data: Some(serde_json::json!({"undone": undone, "remaining": self.undo_stack.len()})),
+                }
+            }
+
+            Command::TogglePaneFloating { pane_id } => {
+                host!(toggle_pane_embed_or_eject_for_pane_id(PaneId::Terminal(pane_id)));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"toggled_floating": pane_id})),
+                }
+            }
+
+            Command::FloatAll { index, force } => self.set_tab_floating(index, true, force),
+
+            Command::EmbedAll { index, force } => self.set_tab_floating(index, false, force),
+
+            Command::StackPanes { pane_ids } => {
+                if pane_ids.len() < 2 {
+                    return Response {
+                        success: false,
+                        error: Some("stack_panes needs at least two pane ids".to_string()),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let ids: Vec<PaneId> = pane_ids.iter().map(|id| PaneId::Terminal(*id)).collect();
+                host!(stack_panes(ids));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"stacked": pane_ids})),
+                }
+            }
+
+            Command::UnstackPanes { pane_ids } => {
+                // There is no direct unstack call: floating the panes pulls them out of
+                // the stack, and embedding them again lays them out as regular tiles
+                let ids: Vec<PaneId> = pane_ids.iter().map(|id| PaneId::Terminal(*id)).collect();
+                host!(float_multiple_panes(ids.clone()));
+                host!(embed_multiple_panes(ids));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"unstacked": pane_ids})),
+                }
+            }
+
+            Command::MoveToTab { pane_id, tab_index, focus, force } => {
+                if !force && self.is_protected_pane(pane_id) {
+                    return Response {
+                        success: false,
+                        error: Some("Cannot move Claude pane to another tab (use force:true to override)".to_string()),
+                        error_code: Some(ErrorCode::Protected),
+                        data: Some(serde_json::json!({"protected_pane": pane_id})),
+                    };
+                }
+                if !self.tabs.iter().any(|t| t.position == tab_index) {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Tab {} does not exist", tab_index)),
+                        error_code: Some(ErrorCode::TabNotFound),
+                        data: Some(serde_json::json!({"tabs_count": self.tabs.len()})),
+                    };
+                }
+                host!(break_panes_to_tab_with_index(&[PaneId::Terminal(pane_id)], tab_index, focus));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"moved": pane_id, "tab_index": tab_index})),
+                }
+            }
+
+            Command::BreakToNewTab { pane_id, group, tab_name, focus, force } => {
+                let (allowed, results) = match (pane_id, &group) {
+                    (Some(pane_id), None) => {
+                        if !force && self.is_protected_pane(pane_id) {
+                            return Response {
+                                success: false,
+                                error: Some("Cannot move Claude pane to another tab (use force:true to override)".to_string()),
+                                error_code: Some(ErrorCode::Protected),
+                                data: Some(serde_json::json!({"protected_pane": pane_id})),
+                            };
+                        }
+                        (vec![pane_id], Vec::new())
+                    }
+                    (None, Some(name)) => match self.group_targets(name, force, true) {
+                        Ok(targets) => targets,
+                        Err(response) => return response,
+                    },
+                    _ => {
+                        return Response {
+                            success: false,
+                            error: Some("break_to_new_tab needs exactly one of pane_id or group".to_string()),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: None,
+                        };
+                    }
+                };
+                if !allowed.is_empty() {
+                    let ids: Vec<PaneId> = allowed.iter().map(|id| PaneId::Terminal(*id)).collect();
+                    host!(break_panes_to_new_tab(&ids, tab_name.clone(), focus));
+                }
+                let mut response = Self::group_response(group.as_deref().unwrap_or_default(), &allowed, results);
+                if let Some(data) = response.data.as_mut().and_then(|d| d.as_object_mut()) {
+                    data.insert("group".to_string(), serde_json::json!(group));
+                    data.insert("tab_name".to_string(), serde_json::json!(tab_name));
+                }
+                response
+            }
+
+            // === PANE GROUPS ===
+            Command::GroupCreate { name, pane_ids } => {
+                let replaced = self.groups.insert(name.clone(), pane_ids.clone()).is_some();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"group": name, "pane_ids": pane_ids, "replaced": replaced})),
+                }
+            }
+
+            Command::GroupList => {
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::to_value(&self.groups).unwrap_or_default()),
+                }
+            }
+
+            Command::GroupDissolve { name } => {
+                match self.groups.remove(&name) {
+                    Some(pane_ids) => Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({"dissolved": name, "pane_ids": pane_ids})),
+                    },
+                    None => Response {
+                        success: false,
+                        error: Some(format!("No pane group named '{}'", name)),
+                        error_code: Some(ErrorCode::GroupNotFound),
+                        data: None,
+                    },
+                }
+            }
+
+            Command::GroupClose { name, force } => {
+                match self.group_targets(&name, force, true) {
+                    Ok((allowed, results)) => {
+                        for &pane_id in &allowed {
+                            host!(close_terminal_pane(pane_id));
+                        }
+                        // Skipped members survive, so the group only goes away once empty
+                        if let Some(members) = self.groups.get_mut(&name) {
+                            members.retain(|id| !allowed.contains(id));
+                            if members.is_empty() {
+                                self.groups.remove(&name);
+                            }
+                        }
+                        Self::group_response(&name, &allowed, results)
+                    }
+                    Err(response) => response,
+                }
+            }
+
+            Command::GroupHide { name, force } => {
+                match self.group_targets(&name, force, true) {
+                    Ok((allowed, results)) => {
+                        for &pane_id in &allowed {
+                            host!(hide_pane_with_id(PaneId::Terminal(pane_id)));
+                        }
+                        Self::group_response(&name, &allowed, results)
+                    }
+                    Err(response) => response,
+                }
+            }
+
+            Command::GroupMoveToTab { name, tab_index, force } => {
+                match self.group_targets(&name, force, true) {
+                    Ok((allowed, results)) => {
+                        let ids: Vec<PaneId> = allowed.iter().map(|id| PaneId::Terminal(*id)).collect();
+                        if !ids.is_empty() {
+                            host!(break_panes_to_tab_with_index(&ids, tab_index, false));
+                        }
+                        Self::group_response(&name, &allowed, results)
+                    }
+                    Err(response) => response,
+                }
+            }
+
+            Command::GroupWrite { name, chars } => {
+                match self.group_targets(&name, false, false) {
+                    Ok((allowed, results)) => {
+                        for &pane_id in &allowed {
+                            self.queue_write(pane_id, QueuedWrite::Chars(chars.clone()));
+                        }
+                        Self::group_response(&name, &allowed, results)
+                    }
+                    Err(response) => response,
+                }
+            }
+
+            // === COMMAND EXECUTION ===
+            Command::Run { command, args, cwd, floating, background, x, y, width, height, queue, label } => {
+                let placed = [&x, &y, &width, &height].iter().any(|d| d.is_some());
+                if placed && !floating {
+                    return Response {
+                        success: false,
+                        error: Some("x, y, width and height only apply with floating: true".to_string()),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let text = |d: &Option<Dimension>| d.as_ref().map(Dimension::to_string);
+                let coordinates = FloatingPaneCoordinates::new(text(&x), text(&y), text(&width), text(&height), None);
+                if placed && coordinates.is_none() {
+                    return Response {
+                        success: false,
+                        error: Some("Invalid floating coordinates (use cells like 10 or percentages like \"50%\")".to_string()),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                if self.saturated() {
+                    let running = self.running_load();
+                    let max_running = self.config.max_running;
+                    if !queue {
+                        let queued = self.jobs.values().filter(|j| j.state == JobState::Queued).count();
+                        return Response {
+                            success: false,
+                            error: Some(format!(
+                                "{} of {} bridge-owned commands are still running (pass queue: true to start once one finishes)",
+                                running, max_running
+                            )),
+                            error_code: Some(ErrorCode::LimitReached),
+                            data: Some(serde_json::json!({
+                                "running": running,
+                                "max_running": max_running,
+                                "queued": queued,
+                                "running_panes": self.running_commands(),
+                            })),
+                        };
+                    }
+                    let run = Command::Run {
+                        command,
+                        args,
+                        cwd,
+                        floating,
+                        background,
+                        x,
+                        y,
+                        width,
+                        height,
+                        queue: false,
+                        label: None,
+                    };
+                    let job_id = self.enqueue_job(run, 0, false, label);
+                    return Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({
+                            "queued": true,
+                            "job_id": job_id,
+                            "position": self.queue_position(job_id),
+                            "running": running,
+                            "max_running": max_running,
+                        })),
+                    };
+                }
+                // A labelled run is a job from the start, so it can be found by its label
+                if let Some(label) = label {
+                    let run = Command::Run {
+                        command,
+                        args,
+                        cwd,
+                        floating,
+                        background,
+                        x,
+                        y,
+                        width,
+                        height,
+                        queue: false,
+                        label: None,
+                    };
+                    let job_id = self.enqueue_job(run, 0, false, Some(label));
+                    return self.start_job(job_id);
+                }
+                if let Some(max_panes) = self.config.max_panes {
+                    let terminal_panes = self.panes.values().flatten().filter(|p| !p.is_plugin).count();
+                    if terminal_panes >= max_panes {
+                        return Response {
+                            success: false,
+                            error: Some(format!("Pane limit reached ({} of {} terminal panes)", terminal_panes, max_panes)),
+                            error_code: Some(ErrorCode::LimitReached),
+                            data: Some(serde_json::json!({"max_panes": max_panes, "panes": terminal_panes})),
+                        };
+                    }
+                }
+                let cwd = self.resolve_cwd(cwd);
+                let background = !floating && background.unwrap_or(self.config.background);
+                let cmd = CommandToRun {
+                    path: command.clone().into(),
+                    args: args.clone(),
+                    cwd: cwd.clone().map(|s| s.into()),
+                };
+                let handle = new_handle();
+                self.opening.insert(handle.clone(), Instant::now());
+                let context = BTreeMap::from([
+                    Launch::context(&command, &args, cwd.as_deref()),
+                    (HANDLE_CONTEXT_KEY.to_string(), handle.clone()),
+                ]);
+
+                if floating {
+                    host!(open_command_pane_floating(cmd, coordinates, context));
+                } else if background {
+                    host!(open_command_pane_background(cmd, context));
+                } else {
+                    host!(open_command_pane(cmd, context));
+                }
+
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "command": command,
+                        "args": args,
+                        "floating": floating,
+                        "background": background,
+                        "cwd": cwd,
+                        "handle": handle,
+                    })),
+                }
+            }
+
+            Command::RunCaptured { command, args, cwd, floating, background } => {
+                // Captures are numbered apart from jobs, which `cancel` and `jobs` look up
+                self.capture_counter += 1;
+                let capture_id = self.capture_counter;
+                // Capture ids restart with the plugin, the timestamp keeps older files apart
+                let started_ms = unix_ms();
+                let file = format!("{}/{}-{}.log", CAPTURE_DIR, started_ms, capture_id);
+                let host_file = format!("{}/{}", self.host_folder().trim_end_matches('/'), file);
+                let mut wrapped_args = vec!["-c".to_string(), CAPTURE_WRAPPER.to_string(), "sh".to_string(), host_file];
+                wrapped_args.push(command.clone());
+                wrapped_args.extend(args);
+                let mut response = self.execute_command(Command::Run {
+                    command: "sh".to_string(),
+                    args: wrapped_args,
+                    cwd,
+                    floating,
+                    background,
+                    x: None,
+                    y: None,
+                    width: None,
+                    height: None,
+                    queue: false,
+                    label: None,
+                });
+                if response.success {
+                    self.captured_jobs.insert(capture_id, CapturedJob {
+                        command: command.clone(),
+                        output: format!("/host/{}", file),
+                    });
+                    if let Some(data) = response.data.as_mut().and_then(|d| d.as_object_mut()) {
+                        data.insert("command".to_string(), command.into());
+                        data.insert("capture_id".to_string(), capture_id.into());
+                        data.insert("output_file".to_string(), file.into());
+                    }
+                }
+                response
+            }
+
+            Command::Submit { command, args, cwd, floating, background, priority, defer, label } => {
+                let run = Command::Run {
+                    command,
+                    args,
+                    cwd,
+                    floating,
+                    background,
+                    x: None,
+                    y: None,
+                    width: None,
+                    height: None,
+                    queue: false,
+                    label: None,
+                };
+                let job_id = self.enqueue_job(run, priority, defer, label);
+                self.start_queued_jobs();
+                let job = &self.jobs[&job_id];
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "job_id": job_id,
+                        "state": job.state,
+                        "priority": priority,
+                        "position": (job.state == JobState::Queued).then(|| self.queue_position(job_id)),
+                        "handle": job.handle,
+                        "artifacts": job.artifacts,
+                        "error": job.error,
+                        "running": self.running_load(),
+                        "max_running": self.config.max_running,
+                    })),
+                }
+            }
+
+            Command::Cancel { job_id: None, label: Some(_), grace_secs: Some(_) } => Response {
+                success: false,
+                error: Some("grace_secs applies to a single job; cancel by label only interrupts".to_string()),
+                error_code: Some(ErrorCode::InvalidArgument),
+                data: None,
+            },
+
+            Command::Cancel { job_id: None, label: Some(label), grace_secs: None } => {
+                let unfinished: Vec<u64> = self.labelled_jobs(&label)
+                    .filter(|(_, j)| !j.finished() && j.state != JobState::Cancelled)
+                    .map(|(id, _)| *id)
+                    .collect();
+                let mut cancelled = Vec::new();
+                for job_id in unfinished {
+                    let was = match self.cancel_unstarted(job_id) {
+                        Some(was) => was,
+                        None => {
+                            let Some(job) = self.jobs.get_mut(&job_id) else { continue };
+                            job.state = JobState::Cancelled;
+                            if let Some(pane_id) = job.pane_id {
+                                self.queue_write(pane_id, QueuedWrite::Bytes(vec![0x03]));
+                            }
+                            "running"
+                        }
+                    };
+                    cancelled.push(serde_json::json!({"job_id": job_id, "was": was}));
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"label": label, "count": cancelled.len(), "cancelled": cancelled})),
+                }
+            }
+
+            Command::Cancel { job_id: Some(job_id), label: None, grace_secs } => {
+                let Some(job) = self.jobs.get(&job_id) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("No job {}", job_id)),
+                        error_code: Some(ErrorCode::JobNotFound),
+                        data: Some(serde_json::json!({"job_id": job_id})),
+                    };
+                };
+                let was = job.state;
+                let pane_id = job.pane_id;
+                if let Some(was) = self.cancel_unstarted(job_id) {
+                    return Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({"job_id": job_id, "was": was})),
+                    };
+                }
+                match (was, pane_id) {
+                    (JobState::Running, Some(pane_id)) => {
+                        let mut response = self.execute_command(Command::Shutdown { pane_id, grace_secs, force: false });
+                        if response.success {
+                            if let Some(job) = self.jobs.get_mut(&job_id) {
+                                job.state = JobState::Cancelled;
+                            }
+                            if let Some(data) = response.data.as_mut() {
+                                data["job_id"] = job_id.into();
+                                data["was"] = "running".into();
+                            }
+                        }
+                        response
+                    }
+                    _ => Response {
+                        success: false,
+                        error: Some(format!("Job {} is no longer queued or running", job_id)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: Some(serde_json::json!({"job_id": job_id, "was": was})),
+                    },
+                }
+            }
+
+            Command::Cancel { .. } => Response {
+                success: false,
+                error: Some("cancel takes either job_id or label".to_string()),
+                error_code: Some(ErrorCode::InvalidArgument),
+                data: None,
+            },
+
+            Command::WaitJobs { label, timeout_secs } => {
+                let timeout_secs = timeout_secs.unwrap_or(WAIT_FOR_PANE_DEFAULT_TIMEOUT_SECS);
+                if !timeout_secs.is_finite() || timeout_secs <= 0.0 || timeout_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!("timeout_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, timeout_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                if self.labelled_jobs(&label).next().is_none() {
+                    return Response {
+                        success: false,
+                        error: Some(format!("No jobs labelled '{}'", label)),
+                        error_code: Some(ErrorCode::JobNotFound),
+                        data: Some(serde_json::json!({"label": label})),
+                    };
+                }
+                let jobs: Vec<serde_json::Value> = self.labelled_jobs(&label).map(|(id, j)| j.to_json(*id)).collect();
+                if !self.labelled_jobs(&label).all(|(_, j)| j.finished()) {
+                    self.defer_reply = Some(Waiting::Jobs { label, timeout_secs });
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"jobs": jobs})),
+                }
+            }
+
+            Command::PruneJobs { label } => {
+                let pruned: Vec<u64> = self.jobs.iter()
+                    .filter(|(_, j)| j.finished() && (label.is_none() || j.label == label))
+                    .map(|(id, _)| *id)
+                    .collect();
+                for job_id in &pruned {
+                    self.jobs.remove(job_id);
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"pruned": pruned, "remaining": self.jobs.len()})),
+                }
+            }
+
+            Command::Jobs { state, label } => {
+                let jobs: Vec<serde_json::Value> = self.jobs.iter()
+                    .filter(|(_, j)| state.is_none_or(|state| j.state == state))
+                    .filter(|(_, j)| label.is_none() || j.label == label)
+                    .map(|(id, j)| j.to_json(*id))
+                    .collect();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "count": jobs.len(),
+                        "jobs": jobs,
+                        "running": self.running_load(),
+                        "max_running": self.config.max_running,
+                    })),
+                }
+            }
+
+            Command::ReadOutput { capture_id, offset, limit } => {
+                let Some(job) = self.captured_jobs.get(&capture_id) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("No run_captured capture {}", capture_id)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: Some(serde_json::json!({"capture_id": capture_id})),
+                    };
+                };
+                let exit_code = std::fs::read_to_string(format!("{}.status", job.output))
+                    .ok()
+                    .and_then(|status| status.trim().parse::<i32>().ok());
+                match self.read_chunk(&job.output, offset, limit) {
+                    Ok(mut data) => {
+                        data["capture_id"] = capture_id.into();
+                        data["command"] = job.command.clone().into();
+                        data["exited"] = exit_code.is_some().into();
+                        data["exit_code"] = exit_code.into();
+                        Response {
+                            success: true,
+                            error: None,
+                            error_code: None,
+                            data: Some(data),
+                        }
+                    }
+                    Err(e) => Response {
+                        success: false,
+                        error: Some(format!("Cannot read output of capture {} (it may not have started yet): {}", capture_id, e)),
+                        error_code: Some(ErrorCode::FileError),
+                        data: Some(serde_json::json!({"capture_id": capture_id})),
+                    },
+                }
+            }
+
+            Command::Rerun { pane_id } => {
+                host!(rerun_command_pane(pane_id));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"rerun": pane_id})),
+                }
+            }
+
+            Command::Shutdown { pane_id, grace_secs, force } => {
+                let grace_secs = grace_secs.unwrap_or(SHUTDOWN_DEFAULT_GRACE_SECS);
+                if !(0.0..=MAX_WAIT_SECS).contains(&grace_secs) {
+                    return Response {
+                        success: false,
+                        error: Some(format!("grace_secs must be a non-negative number up to {}, got {}", MAX_WAIT_SECS, grace_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                if !force && self.is_protected_pane(pane_id) {
+                    return Response {
+                        success: false,
+                        error: Some("Cannot shut down Claude pane - this would disrupt the agent (use force:true to override)".to_string()),
+                        error_code: Some(ErrorCode::Protected),
+                        data: Some(serde_json::json!({"protected_pane": pane_id})),
+                    };
+                }
+                if self.pane_finished(pane_id) {
+                    host!(close_terminal_pane(pane_id));
+                    return Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({"pane_id": pane_id, "path": "already_exited"})),
+                    };
+                }
+                self.queue_write(pane_id, QueuedWrite::Bytes(vec![0x03]));
+                self.defer_reply = Some(Waiting::Shutdown { pane_id, timeout_secs: grace_secs });
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"pane_id": pane_id, "grace_secs": grace_secs})),
+                }
+            }
+
+            Command::KillMatching { pattern, signal, exclude_protected } => {
+                let signal = signal.unwrap_or_else(|| "interrupt".to_string()).to_lowercase();
+                if signal != "interrupt" && signal != "close" {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Invalid signal '{}' (expected interrupt or close)", signal)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let regex = match regex::Regex::new(&pattern) {
+                    Ok(regex) => regex,
+                    Err(e) => {
+                        return Response {
+                            success: false,
+                            error: Some(format!("Invalid pattern '{}': {}", pattern, e)),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: None,
+                        };
+                    }
+                };
+                let exclude_protected = exclude_protected.unwrap_or(true);
+                let targets: Vec<(u32, String)> = self.panes.values()
+                    .flatten()
+                    .filter(|p| !p.is_plugin && !p.exited)
+                    .filter_map(|p| p.terminal_command.as_ref().filter(|c| regex.is_match(c)).map(|c| (p.id, c.clone())))
+                    .collect();
+                let mut results = Vec::new();
+                let mut skipped = 0;
+                for (pane_id, command) in targets {
+                    let skip = if exclude_protected && self.is_protected_pane(pane_id) {
+                        Some("protected pane skipped (use exclude_protected:false to override)")
+                    } else if !self.may_change(pane_id) {
+                        Some("pane not opened by the bridge skipped (use grant_pane to allow)")
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = skip {
+                        skipped += 1;
+                        results.push(serde_json::json!({"pane_id": pane_id, "command": command, "success": false, "error": reason}));
+                        continue;
+                    }
+                    if signal == "close" {
+                        host!(close_terminal_pane(pane_id));
+                    } else {
+                        self.queue_write(pane_id, QueuedWrite::Bytes(vec![0x03]));
+                    }
+                    results.push(serde_json::json!({"pane_id": pane_id, "command": command, "success": true, "signal": signal}));
+                }
+                Response {
+                    success: skipped == 0,
+                    error: (skipped > 0).then(|| format!("{} matching pane(s) skipped", skipped)),
+                    error_code: (skipped > 0).then_some(ErrorCode::PartialFailure),
+                    data: Some(serde_json::json!({"pattern": pattern, "results": results})),
+                }
+            }
+
+            Command::RerunWith { pane_id, args, env, force } => {
+                if !force && self.is_protected_pane(pane_id) {
+                    return Response {
+                        success: false,
+                        error: Some("Cannot replace Claude pane - this would disrupt the agent (use force:true to override)".to_string()),
+                        error_code: Some(ErrorCode::Protected),
+                        data: Some(serde_json::json!({"protected_pane": pane_id})),
+                    };
+                }
+                let Some(mut launch) = self.pane_launches.get(&pane_id).cloned() else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Pane {} was not opened by the bridge, so its command is unknown", pane_id)),
+                        error_code: Some(ErrorCode::NotOwner),
+                        data: Some(serde_json::json!({"pane_id": pane_id})),
+                    };
+                };
+                if let Some(args) = args {
+                    launch.args = args;
+                }
+                launch.env.extend(env);
+                let (command, command_args) = launch.command_line();
+                let cmd = CommandToRun {
+                    path: command.into(),
+                    args: command_args,
+                    cwd: launch.cwd.clone().map(|s| s.into()),
+                };
+                // The name comes back through the context, like for any bridge-opened pane
+                let name = match self.badge_titles.get(&pane_id) {
+                    Some(base) => base.clone(),
+                    None => self.find_pane(pane_id).map(|(_, p)| strip_markers(&p.title).to_string()),
+                };
+                let mut context = BTreeMap::from([launch.context_entry()]);
+                if let Some(name) = name.filter(|n| !n.is_empty()) {
+                    context.insert("pane_name".to_string(), name);
+                }
+                // The replacement keeps the handle, so callers holding it follow the pane
+                let handle = self.pane_handles.get(&pane_id).cloned();
+                if let Some(handle) = &handle {
+                    context.insert(HANDLE_CONTEXT_KEY.to_string(), handle.clone());
+                }
+                host!(open_command_pane_in_place_of_pane_id(PaneId::Terminal(pane_id), cmd, true, context));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "replaced": pane_id,
+                        "handle": handle,
+                        "command": launch.command,
+                        "args": launch.args,
+                        "env": launch.env,
+                        "cwd": launch.cwd,
+                    })),
+                }
+            }
+
+            Command::Duplicate { pane_id, floating, tab } => {
+                let Some(launch) = self.pane_launches.get(&pane_id).cloned() else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Pane {} was not opened by the bridge, so its command is unknown", pane_id)),
+                        error_code: Some(ErrorCode::NotOwner),
+                        data: Some(serde_json::json!({"pane_id": pane_id})),
+                    };
+                };
+                if let Some(tab) = &tab {
+                    host!(focus_or_create_tab(tab));
+                }
+                let (command, args) = launch.command_line();
+                let mut response = self.execute_command(Command::Run {
+                    command,
+                    args,
+                    cwd: launch.cwd,
+                    floating,
+                    background: Some(false),
+                    x: None,
+                    y: None,
+                    width: None,
+                    height: None,
+                    queue: false,
+                    label: None,
+                });
+                if let Some(data) = response.data.as_mut().filter(|_| response.success) {
+                    data["duplicate_of"] = pane_id.into();
+                    data["tab"] = tab.into();
+                }
+                response
+            }
+
+            Command::CommandStatus { pane_id } => {
+                if let Some(result) = self.command_results.get(&pane_id) {
+                    Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(self.result_json(result)),
+                    }
+                } else {
+                    Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({
+                            "pane_id": pane_id,
+                            "exited": false,
+                            "exit_code": null
+                        })),
+                    }
+                }
+            }
+
+            Command::ListCommandResults { exited } => {
+                let mut results: Vec<&CommandResult> = self.command_results.values()
+                    .filter(|r| exited.is_none_or(|exited| r.exited == exited))
+                    .collect();
+                results.sort_by_key(|r| r.pane_id);
+                let results: Vec<serde_json::Value> = results.into_iter().map(|r| self.result_json(r)).collect();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"count": results.len(), "results": results})),
+                }
+            }
+
+            Command::PurgeResults { pane_ids, older_than_secs, include_running } => {
+                if older_than_secs.is_some_and(|secs| !secs.is_finite() || secs < 0.0) {
+                    return Response {
+                        success: false,
+                        error: Some("older_than_secs must be a non-negative number".to_string()),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let cutoff = older_than_secs.map(|secs| unix_ms().saturating_sub((secs * 1000.0) as u64));
+                let mut purged: Vec<u32> = self.command_results.values()
+                    .filter(|r| include_running || r.exited)
+                    .filter(|r| pane_ids.is_empty() || pane_ids.contains(&r.pane_id))
+                    // Results restored from before timestamps were kept count as old
+                    .filter(|r| cutoff.is_none_or(|cutoff| r.exited_ms.or(r.started_ms).is_none_or(|t| t <= cutoff)))
+                    .map(|r| r.pane_id)
+                    .collect();
+                purged.sort_unstable();
+                for pane_id in &purged {
+                    self.command_results.remove(pane_id);
+                }
+                self.refresh_badges();
+                Response {
+                    success: true,
                     error: None,
-                    data: Some(serde_json::json!({"written": chars.len(), "pane_id": pane_id})),
+                    error_code: None,
+                    data: Some(serde_json::json!({"purged": purged, "remaining": self.command_results.len()})),
                 }
             }
 
-            Command::WriteBytes { pane_id, bytes } => {
-                write_to_pane_id(bytes.clone(), PaneId::Terminal(pane_id));
+            // === TAB OPERATIONS ===
+            Command::NewTab { name, cwd } => {
+                let cwd = self.resolve_cwd(cwd);
+                host!(new_tab(name.as_deref(), cwd.as_deref()));
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"written": bytes.len(), "pane_id": pane_id})),
+                    error_code: None,
+                    data: Some(serde_json::json!({"created_tab": name, "cwd": cwd})),
                 }
             }
 
-            // === READ ===
-            Command::List => {
-                let panes: Vec<PaneSummary> = self.panes.iter()
-                    .flat_map(|(tab_idx, pane_list)| {
-                        pane_list.iter().map(move |p| PaneSummary {
-                            id: p.id,
-                            is_plugin: p.is_plugin,
-                            title: p.title.clone(),
-                            is_focused: p.is_focused,
-                            is_floating: p.is_floating,
-                            is_fullscreen: p.is_fullscreen,
-                            is_suppressed: p.is_suppressed,
-                            tab_index: *tab_idx,
-                            command: p.terminal_command.clone(),
-                            exit_status: p.exit_status,
-                            exited: p.exited,
-                            rows: p.pane_content_rows,
-                            cols: p.pane_content_columns,
+            Command::CloseTab { index, force } => {
+                // Check if this tab contains a Claude pane
+                if !force {
+                    if let Some(panes) = self.panes.get(&(index as usize)) {
+                        for p in panes {
+                            if self.is_protected_pane(p.id) {
+                                return Response {
+                                    success: false,
+                                    error: Some("Cannot close tab containing Claude pane (use force:true to override)".to_string()),
+                                    error_code: Some(ErrorCode::Protected),
+                                    data: Some(serde_json::json!({"protected_tab": index, "claude_pane_id": p.id})),
+                                };
+                            }
+                        }
+                    }
+                }
+                host!(close_tab_with_index(index as usize));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"closed_tab": index})),
+                }
+            }
+
+            Command::FocusTab { index, name, create, tab_offset } => {
+                let index = match tab_offset {
+                    Some(_) if index.is_some() || name.is_some() => {
+                        return Response {
+                            success: false,
+                            error: Some("tab_offset cannot be combined with index or name".to_string()),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: None,
+                        };
+                    }
+                    Some(offset) => {
+                        let (Some(active), count) = (self.active_tab_index(), self.tabs.len()) else {
+                            return Response {
+                                success: false,
+                                error: Some("No tab is active yet".to_string()),
+                                error_code: Some(ErrorCode::TabNotFound),
+                                data: None,
+                            };
+                        };
+                        // Positions run 0..count, so stepping wraps within them
+                        Some((active as i64 + offset).rem_euclid(count as i64) as u32)
+                    }
+                    None => index,
+                };
+                self.focus_tab(index, name, create)
+            }
+
+            Command::GotoTab { index } => self.focus_tab(Some(index), None, false),
+
+            Command::GotoPreviousTab => match self.previous_tab.clone() {
+                Some(name) => self.focus_tab(None, Some(name), false),
+                None => Response {
+                    success: false,
+                    error: Some("No tab has been active before the current one".to_string()),
+                    error_code: Some(ErrorCode::TabNotFound),
+                    data: None,
+                },
+            },
+
+            Command::NextSwapLayout { index } => {
+                match self.with_tab_focused(index, next_swap_layout) {
+                    Ok(previous_layout) => Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({
+                            "swap_layout": "next",
+                            "tab_index": index,
+                            "previous_layout": previous_layout
+                        })),
+                    },
+                    Err(response) => response,
+                }
+            }
+
+            Command::PreviousSwapLayout { index } => {
+                match self.with_tab_focused(index, previous_swap_layout) {
+                    Ok(previous_layout) => Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({
+                            "swap_layout": "previous",
+                            "tab_index": index,
+                            "previous_layout": previous_layout
+                        })),
+                    },
+                    Err(response) => response,
+                }
+            }
+
+            // === SESSION ===
+            Command::NextPage { token } => {
+                let page = self.pending_pages.get_mut(&token).and_then(|pages| pages.pop_front());
+                if self.pending_pages.get(&token).is_some_and(|pages| pages.is_empty()) {
+                    self.pending_pages.remove(&token);
+                    self.pending_page_order.retain(|t| *t != token);
+                }
+                match page {
+                    Some(page) => Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(page),
+                    },
+                    None => Response {
+                        success: false,
+                        error: Some(format!("No pages left for token '{}' (finished or expired)", token)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    },
+                }
+            }
+
+            Command::Status => {
+                let exited = self.command_results.values().filter(|r| r.exited);
+                let failed = exited.clone().filter(|r| r.exit_code != Some(0)).count();
+                let pending_permissions = self.permissions.values()
+                    .filter(|s| **s == PermissionState::Pending)
+                    .count();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "running": self.running_commands().len(),
+                        "exited": exited.count(),
+                        "failed": failed,
+                        "waiting_replies": self.deferred_replies.len(),
+                        "pending_resizes": self.pending_resizes.len(),
+                        "pending_permissions": pending_permissions,
+                        "protection_violations": self.protection_violations.values().sum::<u64>(),
+                        "read_only": self.config.read_only,
+                        "draining": self.config.drain,
+                    })),
+                }
+            }
+
+            Command::Timings { command } => {
+                let mut timings: Vec<(&String, &CommandTiming)> = self.timings.iter()
+                    .filter(|(line, _)| command.as_ref().is_none_or(|c| line.contains(c.as_str())))
+                    .collect();
+                timings.sort_by_key(|(_, t)| std::cmp::Reverse(t.runs));
+                let timings: Vec<serde_json::Value> = timings.into_iter().map(|(line, t)| t.to_json(line)).collect();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"timings": timings})),
+                }
+            }
+
+            Command::Stats => {
+                let commands: BTreeMap<&String, serde_json::Value> = self.stats.iter()
+                    .map(|(name, stats)| (name, stats.to_json()))
+                    .collect();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "uptime_secs": self.loaded_at.map(|t| t.elapsed().as_secs()).unwrap_or(0),
+                        "queue_depth": self.deferred_replies.len(),
+                        "pending_resizes": self.pending_resizes.len(),
+                        "commands": commands,
+                        "storage": {
+                            "command_results": self.command_results.len(),
+                            "max_command_results": self.config.max_command_results,
+                            "groups": self.groups.len(),
+                            "recordings": self.recordings.len(),
+                            "pending_pages": self.pending_pages.len(),
+                            "jobs": self.jobs.len(),
+                            "write_queues": self.write_queues.values().map(VecDeque::len).sum::<usize>(),
+                            "tracked_writes": self.last_write.len(),
+                        },
+                    })),
+                }
+            }
+
+            Command::Forward { session, name, payload, timeout_secs } => {
+                let timeout_secs = timeout_secs.unwrap_or(FORWARD_DEFAULT_TIMEOUT_SECS);
+                if !timeout_secs.is_finite() || timeout_secs <= 0.0 || timeout_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!("timeout_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, timeout_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                self.forward_counter += 1;
+                let id = self.forward_counter;
+                self.forwards.insert(id, None);
+                let payload = payload.map(|p| p.to_string()).unwrap_or_else(|| "{}".to_string());
+                // `zellij pipe` does not exit on its own after the reply, so `timeout` ends it
+                let timeout = format!("{}", timeout_secs.ceil());
+                let context = BTreeMap::from([(FORWARD_CONTEXT_KEY.to_string(), id.to_string())]);
+                host!(run_command(
+                    &[
+                        "timeout", &timeout, "zellij", "-s", &session, "pipe",
+                        "-p", &self.config.plugin_location, "-n", &name, "--", &payload,
+                    ],
+                    context,
+                ));
+                self.defer_reply = Some(Waiting::Forward { id, timeout_secs: timeout_secs + 1.0 });
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"session": session, "command": name})),
+                }
+            }
+
+            Command::Fetch { url, method, headers, body, timeout_secs } => {
+                let timeout_secs = timeout_secs.unwrap_or(FETCH_DEFAULT_TIMEOUT_SECS);
+                if !timeout_secs.is_finite() || timeout_secs <= 0.0 || timeout_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!("timeout_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, timeout_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let method = method.unwrap_or_else(|| "GET".to_string()).to_uppercase();
+                let verb = match method.as_str() {
+                    "GET" => HttpVerb::Get,
+                    "POST" => HttpVerb::Post,
+                    "PUT" => HttpVerb::Put,
+                    "DELETE" => HttpVerb::Delete,
+                    _ => {
+                        return Response {
+                            success: false,
+                            error: Some(format!("Unsupported method '{}' (expected GET, POST, PUT or DELETE)", method)),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: None,
+                        };
+                    }
+                };
+                if !(url.starts_with("http://") || url.starts_with("https://")) {
+                    return Response {
+                        success: false,
+                        error: Some(format!("URL must start with http:// or https://, got '{}'", url)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                self.fetch_counter += 1;
+                let id = self.fetch_counter;
+                self.fetches.insert(id, None);
+                let context = BTreeMap::from([(FETCH_CONTEXT_KEY.to_string(), id.to_string())]);
+                host!(web_request(&url, verb, headers, body.unwrap_or_default().into_bytes(), context));
+                self.defer_reply = Some(Waiting::Fetch { id, timeout_secs });
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"url": url, "method": method})),
+                }
+            }
+
+            Command::ReadFile { path, offset, limit } => {
+                let full_path = match self.host_path(&path) {
+                    Ok(full_path) => full_path,
+                    Err(response) => return response,
+                };
+                match self.read_chunk(&full_path, offset, limit) {
+                    Ok(mut data) => {
+                        data["path"] = path.into();
+                        Response {
+                            success: true,
+                            error: None,
+                            error_code: None,
+                            data: Some(data),
+                        }
+                    }
+                    Err(e) => Response {
+                        success: false,
+                        error: Some(format!("Cannot read '{}': {}", path, e)),
+                        error_code: Some(ErrorCode::FileError),
+                        data: Some(serde_json::json!({"path": path})),
+                    },
+                }
+            }
+
+            Command::WriteFile { path, content, append } => {
+                let full_path = match self.host_path(&path) {
+                    Ok(full_path) => full_path,
+                    Err(response) => return response,
+                };
+                if content.len() > self.config.max_file_bytes {
+                    return Response {
+                        success: false,
+                        error: Some(format!(
+                            "Content is {} bytes, over the {} byte limit", content.len(), self.config.max_file_bytes
+                        )),
+                        error_code: Some(ErrorCode::LimitReached),
+                        data: Some(serde_json::json!({"max_file_bytes": self.config.max_file_bytes})),
+                    };
+                }
+                match write_file(&full_path, content.as_bytes(), append) {
+                    Ok(()) => Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(serde_json::json!({"path": path, "bytes": content.len(), "appended": append})),
+                    },
+                    Err(e) => Response {
+                        success: false,
+                        error: Some(format!("Cannot write '{}': {}", path, e)),
+                        error_code: Some(ErrorCode::FileError),
+                        data: Some(serde_json::json!({"path": path})),
+                    },
+                }
+            }
+
+            Command::ListDir { path } => {
+                let path = path.unwrap_or_else(|| ".".to_string());
+                let full_path = match self.host_path(&path) {
+                    Ok(full_path) => full_path,
+                    Err(response) => return response,
+                };
+                let listed = std::fs::read_dir(&full_path).and_then(|entries| {
+                    entries
+                        .map(|entry| {
+                            let entry = entry?;
+                            let metadata = entry.metadata()?;
+                            Ok(serde_json::json!({
+                                "name": entry.file_name().to_string_lossy(),
+                                "is_dir": metadata.is_dir(),
+                                "size": metadata.len(),
+                            }))
                         })
-                    })
+                        .collect::<std::io::Result<Vec<_>>>()
+                });
+                match listed {
+                    Ok(mut entries) => {
+                        entries.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+                        Response {
+                            success: true,
+                            error: None,
+                            error_code: None,
+                            data: Some(serde_json::json!({"path": path, "entries": entries})),
+                        }
+                    }
+                    Err(e) => Response {
+                        success: false,
+                        error: Some(format!("Cannot list '{}': {}", path, e)),
+                        error_code: Some(ErrorCode::FileError),
+                        data: Some(serde_json::json!({"path": path})),
+                    },
+                }
+            }
+
+            Command::ChangeHostDir { path } => {
+                let Some(path) = self.resolve_cwd(Some(path.clone())).filter(|p| p.starts_with('/')) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Relative path '{}' needs a host folder set first; give an absolute path", path)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                };
+                host!(change_host_folder(std::path::PathBuf::from(&path)));
+                let previous = self.host_dir.replace(path.clone());
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"host_dir": path, "previous": previous})),
+                }
+            }
+
+            Command::PollEvents { since_cursor } => {
+                let since = since_cursor.unwrap_or(0);
+                let oldest = self.events.front().map(|e| e.cursor).unwrap_or(self.event_cursor);
+                let events: Vec<&BridgeEvent> = self.events.iter().filter(|e| e.cursor >= since).collect();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "events": events,
+                        "next_cursor": self.event_cursor,
+                        // Events the client never saw because they fell out of the log
+                        "missed": oldest.saturating_sub(since),
+                    })),
+                }
+            }
+
+            Command::AwaitEvent { types, pane_id, title_pattern, since_cursor, timeout_secs } => {
+                let timeout_secs = timeout_secs.unwrap_or(WAIT_FOR_PANE_DEFAULT_TIMEOUT_SECS);
+                if !timeout_secs.is_finite() || timeout_secs <= 0.0 || timeout_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!("timeout_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, timeout_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let unknown: Vec<&String> = types.iter().filter(|t| !EVENT_TYPES.contains(&t.as_str())).collect();
+                if !unknown.is_empty() {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Unknown event type(s) {:?} (expected: {})", unknown, EVENT_TYPES.join(", "))),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let filter = EventFilter {
+                    types,
+                    pane_id,
+                    title_pattern: title_pattern.map(|p| p.to_lowercase()),
+                };
+                let since_cursor = since_cursor.unwrap_or(self.event_cursor);
+                let event = self.next_event(&filter, since_cursor).cloned();
+                if event.is_none() {
+                    self.defer_reply = Some(Waiting::Event { filter, since_cursor, timeout_secs });
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"event": event})),
+                }
+            }
+
+            Command::Describe { command } => {
+                let (mut commands, defs) = command_schemas().clone();
+                if let Some(name) = command {
+                    let Some(found) = commands.iter_mut().find(|c| c["command"] == name.as_str()) else {
+                        return Response {
+                            success: false,
+                            error: Some(format!("Unknown command '{}'", name)),
+                            error_code: Some(ErrorCode::InvalidCommand),
+                            data: None,
+                        };
+                    };
+                    let mut data = found.take();
+                    if let Some(defs) = defs {
+                        data["schema"]["$defs"] = defs;
+                    }
+                    return Response {
+                        success: true,
+                        error: None,
+                        error_code: None,
+                        data: Some(data),
+                    };
+                }
+                commands.sort_by(|a, b| a["command"].as_str().cmp(&b["command"].as_str()));
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"commands": commands, "$defs": defs})),
+                }
+            }
+
+            Command::Ping { echo } => {
+                let received_at_ms = unix_ms();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "pong": true,
+                        "echo": echo,
+                        "protocol_version": PROTOCOL_VERSION,
+                        "plugin_version": env!("CARGO_PKG_VERSION"),
+                        "zellij_version": self.zellij_version,
+                        "uptime_secs": self.loaded_at.map(|t| t.elapsed().as_secs()).unwrap_or(0),
+                        "received_at_ms": received_at_ms,
+                        "manifest_received": !self.panes.is_empty(),
+                    })),
+                }
+            }
+
+            Command::SelfTest => {
+                self.start_self_test();
+                // The reply waits for the asynchronous probes before it is sent
+                self.defer_reply = Some(Waiting::SelfTest);
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "permissions": self.permissions,
+                        "probes": self.probes,
+                    })),
+                }
+            }
+
+            Command::RecordStart => {
+                if self.recording.is_some() {
+                    return Response {
+                        success: false,
+                        error: Some("A recording is already in progress; stop it with record_stop first".to_string()),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                self.recording = Some(Vec::new());
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"recording": true})),
+                }
+            }
+
+            Command::RecordStop { name } => {
+                match self.recording.take() {
+                    Some(steps) => {
+                        let data = serde_json::json!({"name": name, "steps": steps});
+                        self.recordings.insert(name, steps);
+                        Response {
+                            success: true,
+                            error: None,
+                            error_code: None,
+                            data: Some(data),
+                        }
+                    }
+                    None => Response {
+                        success: false,
+                        error: Some("No recording in progress; start one with record_start".to_string()),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    },
+                }
+            }
+
+            Command::DefineTemplate { name, tabs, runs } => {
+                if tabs.is_empty() && runs.is_empty() {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Template '{}' needs at least one tab or run", name)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                let template = Template { tabs, runs };
+                let data = serde_json::json!({"name": name, "template": template});
+                let replaced = self.templates.insert(name, template).is_some();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"defined": data, "replaced": replaced})),
+                }
+            }
+
+            Command::InstantiateTemplate { name, vars } => {
+                let Some(template) = self.templates.get(&name) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Template '{}' not found", name)),
+                        error_code: Some(ErrorCode::TemplateNotFound),
+                        data: Some(serde_json::json!({"templates": self.templates.keys().collect::<Vec<_>>()})),
+                    };
+                };
+                let template = match template.instantiate(&vars) {
+                    Ok(template) => template,
+                    Err(missing) => {
+                        return Response {
+                            success: false,
+                            error: Some(format!("Template '{}' needs values for: {}", name, missing.join(", "))),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: Some(serde_json::json!({"missing_vars": missing})),
+                        };
+                    }
+                };
+                for tab in &template.tabs {
+                    let cwd = self.resolve_cwd(tab.cwd.clone());
+                    host!(new_tab(Some(tab.name.as_str()), cwd.as_deref()));
+                }
+                for entry in &template.runs {
+                    self.launch_entry(entry);
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "template": name,
+                        "tabs": template.tabs.iter().map(|t| &t.name).collect::<Vec<_>>(),
+                        "runs": template.runs.iter().map(|r| r.name.as_deref().unwrap_or(&r.command)).collect::<Vec<_>>(),
+                    })),
+                }
+            }
+
+            Command::DefineTabTemplate { name, tab_name, cwd, panes } => {
+                let template = TabTemplate { tab_name, cwd, panes };
+                let data = serde_json::json!({"name": name, "template": template});
+                let replaced = self.tab_templates.insert(name, template).is_some();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"defined": data, "replaced": replaced})),
+                }
+            }
+
+            Command::NewTabFromTemplate { template: name, vars } => {
+                let Some(template) = self.tab_templates.get(&name) else {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Tab template '{}' not found", name)),
+                        error_code: Some(ErrorCode::TemplateNotFound),
+                        data: Some(serde_json::json!({"tab_templates": self.tab_templates.keys().collect::<Vec<_>>()})),
+                    };
+                };
+                let template = match fill_placeholders(template, &vars) {
+                    Ok(template) => template,
+                    Err(missing) => {
+                        return Response {
+                            success: false,
+                            error: Some(format!("Tab template '{}' needs values for: {}", name, missing.join(", "))),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: Some(serde_json::json!({"missing_vars": missing})),
+                        };
+                    }
+                };
+                let cwd = self.resolve_cwd(template.cwd.clone());
+                host!(new_tab(Some(template.tab_name.as_str()), cwd.as_deref()));
+                for entry in &template.panes {
+                    let mut entry = entry.clone();
+                    entry.tab = Some(template.tab_name.clone());
+                    entry.cwd = entry.cwd.or_else(|| template.cwd.clone());
+                    self.launch_entry(&entry);
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "template": name,
+                        "tab": template.tab_name,
+                        "panes": template.panes.iter().map(|p| p.name.as_deref().unwrap_or(&p.command)).collect::<Vec<_>>(),
+                    })),
+                }
+            }
+
+            Command::SaveSnapshot { name, description } => {
+                self.refresh_summaries();
+                let snapshot = Snapshot { description, created_ms: unix_ms(), layout: self.snapshot() };
+                let replaced = self.snapshots.insert(name.clone(), snapshot).is_some();
+                if let Err(e) = self.save_snapshots() {
+                    return Response {
+                        success: false,
+                        error: Some(format!("Snapshot '{}' kept until the plugin unloads: {}", name, e)),
+                        error_code: Some(ErrorCode::FileError),
+                        data: Some(serde_json::json!({"name": name})),
+                    };
+                }
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::json!({"name": name, "replaced": replaced})),
+                }
+            }
+
+            Command::ListSnapshots => {
+                let snapshots: Vec<serde_json::Value> = self.snapshots.iter()
+                    .map(|(name, s)| serde_json::json!({
+                        "name": name,
+                        "description": s.description,
+                        "created_ms": s.created_ms,
+                        "tabs": s.layout["tabs"].as_array().map_or(0, Vec::len),
+                        "panes": s.layout["panes"].as_array().map_or(0, Vec::len),
+                    }))
                     .collect();
+                Response {
+                    success: true,
+                    error: None,
+                    error_code: None,
+                    data: Some(serde_json::Value::Array(snapshots)),
+                }
+            }
 
+            Command::DeleteSnapshot { name } => {
+                if self.snapshots.remove(&name).is_none() {
+                    return self.snapshot_not_found(&name);
+                }
+                if let Err(e) = self.save_snapshots() {
+                    return Response {
+                        success: false,
+                        error: Some(e),
+                        error_code: Some(ErrorCode::FileError),
+                        data: Some(serde_json::json!({"name": name})),
+                    };
+                }
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::to_value(panes).unwrap_or_default()),
+                    error_code: None,
+                    data: Some(serde_json::json!({"deleted": name})),
                 }
             }
 
-            Command::Query { name } => {
-                let name_lower = name.to_lowercase();
-                let matches: Vec<PaneSummary> = self.panes.iter()
-                    .flat_map(|(tab_idx, pane_list)| {
-                        pane_list.iter()
-                            .filter(|p| {
-                                p.title.to_lowercase().contains(&name_lower) ||
-                                p.terminal_command.as_ref()
-                                    .map(|c| c.to_lowercase().contains(&name_lower))
-                                    .unwrap_or(false)
-                            })
-                            .map(move |p| PaneSummary {
-                                id: p.id,
-                                is_plugin: p.is_plugin,
-                                title: p.title.clone(),
-                                is_focused: p.is_focused,
-                                is_floating: p.is_floating,
-                                is_fullscreen: p.is_fullscreen,
-                                is_suppressed: p.is_suppressed,
-                                tab_index: *tab_idx,
-                                command: p.terminal_command.clone(),
-                                exit_status: p.exit_status,
-                                exited: p.exited,
-                                rows: p.pane_content_rows,
-                                cols: p.pane_content_columns,
-                            })
-                    })
-                    .collect();
+            Command::RestoreSnapshot { name } => self.restore_snapshot(&name),
 
+            Command::DiffSnapshot { name } => {
+                self.refresh_summaries();
+                let Some(snapshot) = self.snapshots.get(&name) else {
+                    return self.snapshot_not_found(&name);
+                };
+                let mut diff = diff_layouts(&snapshot.layout, &self.snapshot());
+                diff["name"] = name.into();
+                diff["created_ms"] = snapshot.created_ms.into();
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::to_value(matches).unwrap_or_default()),
+                    error_code: None,
+                    data: Some(diff),
                 }
             }
 
-            Command::ListTabs => {
-                let tabs: Vec<TabSummary> = self.tabs.iter()
-                    .map(|t| TabSummary {
-                        index: t.position,
-                        name: t.name.clone(),
-                        active: t.active,
-                        is_fullscreen: t.is_fullscreen_active,
-                        is_sync: t.is_sync_panes_active,
-                    })
+            Command::ExportState => {
+                let pane_names = self.groups.values()
+                    .flatten()
+                    .filter_map(|&id| self.find_pane(id).map(|(_, p)| (id, strip_markers(&p.title).to_string())))
                     .collect();
-
+                let state = ExportedState {
+                    protocol_version: PROTOCOL_VERSION,
+                    config_overrides: self.config_overrides.clone(),
+                    aliases: self.config.aliases.clone(),
+                    groups: self.groups.clone(),
+                    recordings: self.recordings.clone(),
+                    templates: self.templates.clone(),
+                    tab_templates: self.tab_templates.clone(),
+                    pane_names,
+                };
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::to_value(tabs).unwrap_or_default()),
+                    error_code: None,
+                    data: Some(serde_json::json!({"state": state})),
                 }
             }
 
-            // === PANE CONTROL ===
-            Command::Focus { pane_id } => {
-                focus_terminal_pane(pane_id, true);
+            Command::ImportState { state, replace } => {
+                let previous = self.config_overrides.clone();
+                self.config_overrides.extend(state.config_overrides);
+                if !state.aliases.is_empty() {
+                    let aliases: Vec<String> = state.aliases.iter().map(|(a, c)| format!("{}={}", a, c)).collect();
+                    self.config_overrides.insert("aliases".to_string(), aliases.join(","));
+                }
+                let warnings = self.apply_config();
+                if !warnings.is_empty() {
+                    self.config_overrides = previous;
+                    self.apply_config();
+                    return self.config_response(warnings);
+                }
+
+                if replace {
+                    self.groups.clear();
+                    self.recordings.clear();
+                    self.templates.clear();
+                    self.tab_templates.clear();
+                }
+                // Find grouped panes again by title; ids that match nothing are dropped
+                let mut unresolved = Vec::new();
+                for (name, members) in state.groups {
+                    let ids: Vec<u32> = members.into_iter()
+                        .filter_map(|id| {
+                            let by_title = state.pane_names.get(&id).and_then(|title| self.find_pane_by_title(title));
+                            let resolved = by_title.map(|(_, p)| p.id)
+                                .or_else(|| (self.panes.is_empty() || self.find_pane(id).is_some()).then_some(id));
+                            if resolved.is_none() {
+                                unresolved.push(serde_json::json!({"group": name, "pane_id": id}));
+                            }
+                            resolved
+                        })
+                        .collect();
+                    if !ids.is_empty() {
+                        self.groups.insert(name, ids);
+                    }
+                }
+                self.recordings.extend(state.recordings);
+                self.templates.extend(state.templates);
+                self.tab_templates.extend(state.tab_templates);
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"focused": pane_id})),
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "groups": self.groups,
+                        "recordings": self.recordings.keys().collect::<Vec<_>>(),
+                        "templates": self.templates.keys().collect::<Vec<_>>(),
+                        "tab_templates": self.tab_templates.keys().collect::<Vec<_>>(),
+                        "overrides": self.config_overrides,
+                        "unresolved_panes": unresolved,
+                    })),
                 }
             }
 
-            Command::Close { pane_id, force } => {
-                if !force && self.is_protected_pane(pane_id) {
-                    Response {
+            Command::Replay { name, stop_on_error } => {
+                let Some(steps) = self.recordings.get(&name).cloned() else {
+                    return Response {
                         success: false,
-                        error: Some("Cannot close Claude pane - this would terminate the agent (use force:true to override)".to_string()),
-                        data: Some(serde_json::json!({"protected_pane": pane_id})),
+                        error: Some(format!("Recording '{}' not found", name)),
+                        error_code: Some(ErrorCode::RecordingNotFound),
+                        data: Some(serde_json::json!({"recordings": self.recordings.keys().collect::<Vec<_>>()})),
+                    };
+                };
+                let mut results = Vec::new();
+                let mut failed = 0;
+                let mut out_of_time = false;
+                for step in &steps {
+                    // Stop between steps rather than stall the plugin's event loop
+                    if self.over_budget() {
+                        out_of_time = true;
+                        break;
                     }
-                } else {
-                    close_terminal_pane(pane_id);
-                    Response {
-                        success: true,
-                        error: None,
-                        data: Some(serde_json::json!({"closed": pane_id})),
+                    let (mut command, remapped) = self.remap_step(step);
+                    let cmd_name = command.get("cmd").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+                    if let Some(obj) = command.as_object_mut() {
+                        obj.remove("cmd");
                     }
-                }
-            }
-
-            Command::Hide { pane_id, force } => {
-                if !force && self.is_protected_pane(pane_id) {
-                    Response {
-                        success: false,
-                        error: Some("Cannot hide Claude pane - this would disrupt the agent (use force:true to override)".to_string()),
-                        data: Some(serde_json::json!({"protected_pane": pane_id})),
+                    // Each step goes through the same checks as a piped command
+                    let response = self.handle_command(&cmd_name, &command.to_string());
+                    failed += usize::from(!response.success);
+                    if let Some((pipe_id, envelope)) = &self.progress_target {
+                        let text = format!("{}/{} steps replayed ({} failed)", results.len() + 1, steps.len(), failed);
+                        self.send_progress(pipe_id, envelope, &text);
                     }
-                } else {
-                    hide_pane_with_id(PaneId::Terminal(pane_id));
-                    Response {
-                        success: true,
-                        error: None,
-                        data: Some(serde_json::json!({"hidden": pane_id})),
+                    results.push(serde_json::json!({
+                        "cmd": cmd_name,
+                        "success": response.success,
+                        "error": response.error,
+                        "remapped": remapped,
+                    }));
+                    if stop_on_error && !response.success {
+                        break;
                     }
                 }
+                // Replays answer at once rather than waiting on the last step's pane
+                self.defer_reply = None;
+                if out_of_time {
+                    return Response {
+                        success: false,
+                        error: Some(format!(
+                            "Replay of '{}' stopped after {} of {} steps: over the {}ms request budget",
+                            name, results.len(), steps.len(), self.config.max_request_ms
+                        )),
+                        error_code: Some(ErrorCode::Timeout),
+                        data: Some(serde_json::json!({
+                            "name": name,
+                            "steps": results,
+                            "total": steps.len(),
+                            "remaining": steps.len() - results.len(),
+                        })),
+                    };
+                }
+                Response {
+                    success: failed == 0,
+                    error: (failed > 0).then(|| format!("{} of {} replayed steps failed", failed, results.len())),
+                    error_code: (failed > 0).then_some(ErrorCode::PartialFailure),
+                    data: Some(serde_json::json!({
+                        "name": name,
+                        "steps": results,
+                        "total": steps.len(),
+                    })),
+                }
             }
 
-            Command::Show { pane_id } => {
-                show_pane_with_id(PaneId::Terminal(pane_id), false);
+            Command::Quiesce { timeout_secs } => {
+                let timeout_secs = timeout_secs.unwrap_or(QUIESCE_DEFAULT_TIMEOUT_SECS);
+                if !timeout_secs.is_finite() || timeout_secs <= 0.0 || timeout_secs > MAX_WAIT_SECS {
+                    return Response {
+                        success: false,
+                        error: Some(format!("timeout_secs must be a positive number up to {}, got {}", MAX_WAIT_SECS, timeout_secs)),
+                        error_code: Some(ErrorCode::InvalidArgument),
+                        data: None,
+                    };
+                }
+                // With nothing running the summary is final, so answer right away
+                let panes = self.running_commands();
+                if !panes.is_empty() {
+                    self.defer_reply = Some(Waiting::Quiesce { panes, timeout_secs });
+                }
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"shown": pane_id})),
+                    error_code: None,
+                    data: Some(serde_json::json!({"summary": self.quiesce_summary()})),
                 }
             }
 
-            Command::Clear { pane_id } => {
-                clear_screen_for_pane_id(PaneId::Terminal(pane_id));
+            Command::TogglePaneFrames { restore } => {
+                // Only undo our own toggle when restoring, so a second restore is a no-op
+                let toggled = !restore || self.pane_frames_toggled;
+                if toggled {
+                    host!(toggle_pane_frames());
+                    self.pane_frames_toggled = !self.pane_frames_toggled;
+                }
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"cleared": pane_id})),
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "toggled_pane_frames": toggled,
+                        "frames_changed_by_bridge": self.pane_frames_toggled
+                    })),
                 }
             }
 
-            Command::Fullscreen { pane_id } => {
-                toggle_pane_id_fullscreen(PaneId::Terminal(pane_id));
+            Command::ExportLayoutKdl { include_all } => {
+                let (kdl, panes) = self.layout_kdl(include_all);
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"toggled_fullscreen": pane_id})),
+                    error_code: None,
+                    data: Some(serde_json::json!({"kdl": kdl, "tabs": self.tabs.len(), "panes": panes})),
                 }
             }
 
-            Command::RenamePane { pane_id, name } => {
-                rename_terminal_pane(pane_id, &name);
+            Command::SessionInfo => {
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"renamed": pane_id, "name": name})),
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "tabs_count": self.tabs.len(),
+                        "panes_count": self.panes.values().map(|v| v.len()).sum::<usize>(),
+                        "tabs": self.tabs.iter().map(|t| &t.name).collect::<Vec<_>>(),
+                        "protected_pane_id": self.protected_pane_id,
+                        "protocol_version": PROTOCOL_VERSION,
+                        // Only the first session_info after a reload reports the recovery
+                        "recovered": self.recovery.is_some(),
+                        "recovery": self.recovery.take(),
+                    })),
                 }
             }
 
-            Command::Move { pane_id, direction } => {
-                let dir = match direction.to_lowercase().as_str() {
-                    "up" => Direction::Up,
-                    "down" => Direction::Down,
-                    "left" => Direction::Left,
-                    "right" => Direction::Right,
-                    _ => Direction::Right,
-                };
-                move_pane_with_pane_id_in_direction(PaneId::Terminal(pane_id), dir);
+            Command::Detach => {
+                host!(detach());
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"moved": pane_id, "direction": direction})),
+                    error_code: None,
+                    data: Some(serde_json::json!({"message": "Detaching"})),
                 }
             }
 
-            Command::Resize { pane_id, direction } => {
-                let resize = match direction.to_lowercase().as_str() {
-                    "increase" | "up" | "right" => Resize::Increase,
-                    "decrease" | "down" | "left" => Resize::Decrease,
-                    _ => Resize::Increase,
-                };
-                let strategy = ResizeStrategy::new(resize, None);
-                resize_pane_with_id(strategy, PaneId::Terminal(pane_id));
-                Response {
-                    success: true,
-                    error: None,
-                    data: Some(serde_json::json!({"resized": pane_id, "direction": direction})),
+            // === CONFIGURATION ===
+            Command::GetConfig => self.config_response(Vec::new()),
+
+            Command::SetConfig { values } => {
+                let previous = self.config_overrides.clone();
+                for (key, value) in values {
+                    let value = match value {
+                        serde_json::Value::String(s) => s,
+                        serde_json::Value::Array(items) => items.iter()
+                            .map(|v| v.as_str().map(str::to_string).unwrap_or_else(|| v.to_string()))
+                            .collect::<Vec<_>>()
+                            .join(","),
+                        other => other.to_string(),
+                    };
+                    self.config_overrides.insert(key, value);
+                }
+                let warnings = self.apply_config();
+                if !warnings.is_empty() {
+                    // Reject the whole change rather than applying part of it
+                    self.config_overrides = previous;
+                    self.apply_config();
                 }
+                self.config_response(warnings)
             }
 
-            Command::TogglePaneFloating { pane_id } => {
-                toggle_pane_embed_or_eject_for_pane_id(PaneId::Terminal(pane_id));
+            Command::SetReadOnly { enabled } => {
+                self.set_read_only(enabled);
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"toggled_floating": pane_id})),
+                    error_code: None,
+                    data: Some(serde_json::json!({"read_only": self.config.read_only})),
                 }
             }
 
-            // === COMMAND EXECUTION ===
-            Command::Run { command, args, cwd, floating } => {
-                let cmd = CommandToRun {
-                    path: command.clone().into(),
-                    args: args.clone(),
-                    cwd: cwd.clone().map(|s| s.into()),
-                };
-                let context = BTreeMap::new();
-
-                if floating {
-                    open_command_pane_floating(cmd, None, context);
-                } else {
-                    open_command_pane(cmd, context);
+            Command::Drain { enabled, retry_after_secs } => {
+                self.config_overrides.insert("drain".to_string(), enabled.to_string());
+                if let Some(secs) = retry_after_secs {
+                    self.config_overrides.insert("drain_retry_after_secs".to_string(), secs.to_string());
                 }
-
+                self.apply_config();
+                let running = self.command_results.values().filter(|r| !r.exited).count();
                 Response {
                     success: true,
                     error: None,
+                    error_code: None,
                     data: Some(serde_json::json!({
-                        "command": command,
-                        "args": args,
-                        "floating": floating,
-                        "cwd": cwd
+                        "draining": self.config.drain,
+                        "retry_after_secs": self.config.drain_retry_after_secs,
+                        "running_commands": running,
                     })),
                 }
             }
 
-            Command::Rerun { pane_id } => {
-                rerun_command_pane(pane_id);
+            Command::SetProfile { name } => {
+                let previous = self.config_overrides.clone();
+                self.config_overrides.insert("profile".to_string(), name);
+                let warnings = self.apply_config();
+                if !warnings.is_empty() {
+                    self.config_overrides = previous;
+                    self.apply_config();
+                }
+                self.config_response(warnings)
+            }
+
+            Command::Panic => {
+                let report = self.panic();
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"rerun": pane_id})),
+                    error_code: None,
+                    data: Some(report),
                 }
             }
 
-            Command::CommandStatus { pane_id } => {
-                if let Some(result) = self.command_results.get(&pane_id) {
-                    Response {
-                        success: true,
-                        error: None,
-                        data: Some(serde_json::to_value(result).unwrap_or_default()),
-                    }
-                } else {
-                    Response {
-                        success: true,
-                        error: None,
-                        data: Some(serde_json::json!({
-                            "pane_id": pane_id,
-                            "exited": false,
-                            "exit_code": null
-                        })),
-                    }
-                }
+            Command::ReloadConfig => {
+                self.config_overrides.clear();
+                let warnings = self.apply_config();
+                self.config_response(warnings)
             }
 
-            // === TAB OPERATIONS ===
-            Command::NewTab { name, cwd } => {
-                new_tab(name.as_deref(), cwd.as_deref());
+            // === PERMISSIONS ===
+            Command::Permissions => {
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"created_tab": name, "cwd": cwd})),
+                    error_code: None,
+                    data: Some(self.permissions_data()),
                 }
             }
 
-            Command::CloseTab { index, force } => {
-                // Check if this tab contains a Claude pane
-                if !force {
-                    if let Some(panes) = self.panes.get(&(index as usize)) {
-                        for p in panes {
-                            if self.is_protected_pane(p.id) {
-                                return Response {
-                                    success: false,
-                                    error: Some("Cannot close tab containing Claude pane (use force:true to override)".to_string()),
-                                    data: Some(serde_json::json!({"protected_tab": index, "claude_pane_id": p.id})),
-                                };
-                            }
-                        }
-                    }
+            Command::RequestPermissions => {
+                // On-demand permissions are asked again only once a command has needed them
+                let requested: Vec<PermissionType> = REQUIRED_PERMISSIONS.iter()
+                    .chain(ON_DEMAND_PERMISSIONS.iter().filter(|p| self.permissions.contains_key(&format!("{:?}", p))))
+                    .copied()
+                    .filter(|p| self.permissions.get(&format!("{:?}", p)) != Some(&PermissionState::Granted))
+                    .collect();
+                if !requested.is_empty() {
+                    self.request_permissions(&requested);
                 }
-                close_tab_with_index(index as usize);
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"closed_tab": index})),
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "requested": requested.iter().map(|p| format!("{:?}", p)).collect::<Vec<_>>(),
+                        "state": self.permissions_data(),
+                    })),
                 }
             }
 
-            Command::FocusTab { name, create } => {
-                if create {
-                    focus_or_create_tab(&name);
-                } else {
-                    go_to_tab_name(&name);
-                }
+            // === PROTECTION ===
+            Command::Protect { pane_id } => {
+                self.protected_pane_id = Some(pane_id);
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"focused_tab": name, "create": create})),
+                    error_code: None,
+                    data: Some(serde_json::json!({"protected_pane_id": pane_id})),
                 }
             }
 
-            Command::GotoTab { index } => {
-                go_to_tab(index);
+            Command::ProtectPattern { regex } => {
+                let re = match regex::Regex::new(&regex) {
+                    Ok(re) => re,
+                    Err(e) => {
+                        return Response {
+                            success: false,
+                            error: Some(format!("Invalid regex '{}': {}", regex, e)),
+                            error_code: Some(ErrorCode::InvalidArgument),
+                            data: None,
+                        };
+                    }
+                };
+                if !self.protect_patterns.iter().any(|p| p.as_str() == re.as_str()) {
+                    self.protect_patterns.push(re);
+                }
+                let matching: Vec<u32> = self.panes.values()
+                    .flatten()
+                    .filter(|p| !p.is_plugin && self.is_protected_pane(p.id))
+                    .map(|p| p.id)
+                    .collect();
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"goto_tab": index})),
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "protect_patterns": self.protect_patterns.iter().map(|p| p.as_str()).collect::<Vec<_>>(),
+                        "protected_now": matching,
+                    })),
                 }
             }
 
-            // === SESSION ===
-            Command::SessionInfo => {
+            Command::UnprotectPattern { regex } => {
+                let before = self.protect_patterns.len();
+                self.protect_patterns.retain(|p| p.as_str() != regex);
+                let removed = self.protect_patterns.len() < before;
                 Response {
-                    success: true,
-                    error: None,
+                    success: removed,
+                    error: (!removed).then(|| format!("'{}' is not a protect pattern", regex)),
+                    error_code: (!removed).then_some(ErrorCode::InvalidArgument),
                     data: Some(serde_json::json!({
-                        "tabs_count": self.tabs.len(),
-                        "panes_count": self.panes.values().map(|v| v.len()).sum::<usize>(),
-                        "tabs": self.tabs.iter().map(|t| &t.name).collect::<Vec<_>>(),
-                        "protected_pane_id": self.protected_pane_id,
+                        "protect_patterns": self.protect_patterns.iter().map(|p| p.as_str()).collect::<Vec<_>>(),
                     })),
                 }
             }
 
-            Command::Detach => {
-                detach();
+            Command::GrantPane { pane_id } => {
+                self.granted_panes.insert(pane_id);
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"message": "Detaching"})),
+                    error_code: None,
+                    data: Some(serde_json::json!({"granted_panes": self.granted_panes})),
                 }
             }
 
-            // === PROTECTION ===
-            Command::Protect { pane_id } => {
-                self.protected_pane_id = Some(pane_id);
+            Command::RevokePane { pane_id } => {
+                let revoked = self.granted_panes.remove(&pane_id);
+                Response {
+                    success: revoked,
+                    error: (!revoked).then(|| format!("Pane {} has no grant", pane_id)),
+                    error_code: (!revoked).then_some(ErrorCode::InvalidArgument),
+                    data: Some(serde_json::json!({"granted_panes": self.granted_panes})),
+                }
+            }
+
+            Command::ProtectionReport => {
+                let mut by_pane: BTreeMap<String, u64> = BTreeMap::new();
+                for violation in &self.protection_log {
+                    let pane = violation.pane_id.map(|id| id.to_string()).unwrap_or_else(|| "unknown".to_string());
+                    *by_pane.entry(pane).or_default() += 1;
+                }
                 Response {
                     success: true,
                     error: None,
-                    data: Some(serde_json::json!({"protected_pane_id": pane_id})),
+                    error_code: None,
+                    data: Some(serde_json::json!({
+                        "total": self.protection_violations.values().sum::<u64>(),
+                        "by_command": self.protection_violations,
+                        // Only over the violations still in the log
+                        "recent_by_pane": by_pane,
+                        "recent": self.protection_log,
+                    })),
                 }
             }
 
@@ -687,12 +8672,378 @@ impl State {
                 Response {
                     success: true,
                     error: None,
+                    error_code: None,
                     data: Some(serde_json::json!({
                         "protected_pane_id": self.protected_pane_id,
-                        "auto_detected": self.protected_pane_id.is_some()
+                        "auto_detected": self.protected_pane_id.is_some(),
+                        "protect_patterns": self.protect_patterns.iter().map(|p| p.as_str()).collect::<Vec<_>>(),
                     })),
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pane(id: u32, title: &str) -> PaneInfo {
+        PaneInfo { id, title: title.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn dry_run_holds_back_host_calls() {
+        let mut st = State::default();
+        st.apply_manifest(HashMap::from([(0, vec![pane(1, "shell")])]));
+        let r = st.dry_run(Command::Highlight { pane_id: 1, duration_secs: Some(2.0) });
+        assert!(r.success);
+        let data = r.data.unwrap();
+        let calls: Vec<&str> = data["host_calls"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["call"].as_str().unwrap())
+            .collect();
+        assert_eq!(calls, ["rename_terminal_pane", "set_timeout"]);
+        assert_eq!(data["result"]["highlighted"], 1);
+        assert!(st.highlights.is_empty());
+        assert!(!trace::capturing());
+    }
+
+    #[test]
+    fn dry_run_leaves_files_alone() {
+        let mut st = State::default();
+        let snapshot = Snapshot { description: None, created_ms: 0, layout: serde_json::Value::Null };
+        st.snapshots.insert("base".to_string(), snapshot);
+        let on_disk = std::fs::read(SNAPSHOTS_PATH).ok();
+        let r = st.handle_command("delete_snapshot", r#"{"name": "base", "dry_run": true}"#);
+        assert!(r.success, "{:?}", r.error);
+        assert_eq!(r.data.unwrap()["result"]["deleted"], "base");
+        assert!(st.snapshots.contains_key("base"));
+        assert_eq!(std::fs::read(SNAPSHOTS_PATH).ok(), on_disk);
+    }
+
+    #[test]
+    fn restricted_profile_refuses_policy_changes() {
+        let mut st = State::default();
+        st.base_configuration = BTreeMap::from([("profile".to_string(), "restricted".to_string())]);
+        assert!(st.apply_config().is_empty());
+        let r = st.handle_command("set_profile", r#"{"name": "trusted"}"#);
+        assert_eq!(r.error_code, Some(ErrorCode::NotAllowed));
+        let r = st.handle_command("set_config", r#"{"values": {"allowed_commands": ""}}"#);
+        assert_eq!(r.error_code, Some(ErrorCode::NotAllowed));
+        assert_eq!(st.config.profile.as_deref(), Some("restricted"));
+        assert!(!st.config.allowed_commands.is_empty());
+
+        st.base_configuration.insert("profile".to_string(), "trusted".to_string());
+        assert!(st.apply_config().is_empty());
+        assert!(st.handle_command("set_profile", r#"{"name": "observe-only"}"#).success);
+        assert!(st.config.read_only);
+    }
+
+    #[test]
+    fn builtin_profiles_are_enforced() {
+        let mut st = State::default();
+        st.apply_manifest(HashMap::from([(0, vec![pane(1, "shell"), pane(2, "build")])]));
+        st.base_configuration = BTreeMap::from([("profile".to_string(), "restricted".to_string())]);
+        assert!(st.apply_config().is_empty());
+        assert!(st.handle_command("list", "").success);
+        let rename = r#"{"pane_id": 2, "name": "tests"}"#;
+        assert_eq!(st.handle_command("rename_pane", rename).error_code, Some(ErrorCode::NotOwner));
+        st.owned_panes.insert(2);
+        assert!(st.handle_command("rename_pane", rename).success);
+        let r = st.handle_command("close", r#"{"pane_id": 2}"#);
+        assert_eq!(r.error_code, Some(ErrorCode::NotAllowed));
+
+        st.base_configuration.insert("profile".to_string(), "observe-only".to_string());
+        assert!(st.apply_config().is_empty());
+        assert!(st.config.read_only);
+        assert!(st.handle_command("list", "").success);
+        assert_eq!(st.handle_command("rename_pane", rename).error_code, Some(ErrorCode::ReadOnly));
+        assert_eq!(st.handle_command("close", r#"{"pane_id": 2}"#).error_code, Some(ErrorCode::ReadOnly));
+    }
+
+    #[test]
+    fn read_only_holds_bridge_state_too() {
+        let mut st = State::default();
+        st.set_read_only(true);
+        for (name, payload) in [
+            ("set_read_only", r#"{"enabled": false}"#),
+            ("set_config", r#"{"values": {"read_only": "false"}}"#),
+            ("reload_config", ""),
+            ("delete_snapshot", r#"{"name": "base"}"#),
+            ("unprotect_pattern", r#"{"regex": "claude"}"#),
+            ("prune_jobs", "{}"),
+        ] {
+            assert_eq!(st.handle_command(name, payload).error_code, Some(ErrorCode::ReadOnly), "{}", name);
+        }
+        assert!(st.config.read_only);
+        assert!(st.handle_command("set_read_only", r#"{"enabled": true}"#).success);
+
+        st.base_configuration.insert("profile".to_string(), "trusted".to_string());
+        st.apply_config();
+        assert!(st.handle_command("set_read_only", r#"{"enabled": false}"#).success);
+        assert!(!st.config.read_only);
+    }
+
+    fn run(command: &str) -> Command {
+        serde_json::from_value(serde_json::json!({"cmd": "run", "command": command})).unwrap()
+    }
+
+    #[test]
+    fn queued_jobs_wait_out_drain_and_fail_under_read_only() {
+        let mut st = State::default();
+        let job_id = st.enqueue_job(run("make"), 0, false, None);
+        st.config_overrides.insert("drain".to_string(), "true".to_string());
+        st.apply_config();
+        st.start_queued_jobs();
+        assert!(st.jobs[&job_id].state == JobState::Queued);
+
+        st.config_overrides.insert("drain".to_string(), "false".to_string());
+        st.set_read_only(true);
+        st.start_queued_jobs();
+        let job = &st.jobs[&job_id];
+        assert!(job.state == JobState::Failed);
+        assert_eq!(job.error_code, Some(ErrorCode::ReadOnly));
+    }
+
+    #[test]
+    fn jobs_start_by_priority_and_defer_to_higher_work() {
+        let mut st = State::default();
+        let low = st.enqueue_job(run("lint"), 0, false, None);
+        let urgent = st.enqueue_job(run("deploy"), 5, false, None);
+        let also_urgent = st.enqueue_job(run("notify"), 5, true, None);
+        let idle = st.enqueue_job(run("index"), 1, true, None);
+        let positions: Vec<usize> = [low, urgent, also_urgent, idle].iter().map(|id| st.queue_position(*id)).collect();
+        assert_eq!(positions, [4, 1, 2, 3]);
+        assert_eq!(st.next_job(), Some(urgent));
+
+        st.jobs.get_mut(&urgent).unwrap().state = JobState::Running;
+        assert_eq!(st.next_job(), Some(also_urgent));
+        st.jobs.get_mut(&also_urgent).unwrap().state = JobState::Running;
+        assert_eq!(st.next_job(), Some(low));
+        st.jobs.get_mut(&low).unwrap().state = JobState::Running;
+        assert_eq!(st.next_job(), None);
+
+        for id in [urgent, also_urgent, low] {
+            st.jobs.get_mut(&id).unwrap().state = JobState::Done;
+        }
+        assert_eq!(st.next_job(), Some(idle));
+    }
+
+    #[test]
+    fn panic_cancels_queued_and_opening_jobs() {
+        let mut st = State::default();
+        st.config_overrides.insert("max_running".to_string(), "1".to_string());
+        st.apply_config();
+        for _ in 0..3 {
+            assert!(st.handle_command("submit", r#"{"command": "make"}"#).success);
+        }
+        assert_eq!(st.jobs.values().filter(|j| j.state == JobState::Queued).count(), 2);
+        let report = st.panic();
+        assert_eq!(report["cancelled_jobs"], serde_json::json!([1, 2, 3]));
+        assert_eq!(report["closing_on_open"], 1);
+        assert!(st.jobs.values().all(|j| j.state == JobState::Cancelled));
+        st.start_queued_jobs();
+        assert!(st.jobs.values().all(|j| j.state == JobState::Cancelled));
+    }
+
+    #[test]
+    fn panic_closes_owned_panes_and_ends_timers() {
+        let mut st = State::default();
+        st.apply_manifest(HashMap::from([(0, vec![pane(1, "shell"), pane(5, "done"), pane(6, "claude")])]));
+        st.owned_panes.extend([5, 6]);
+        st.protected_pane_id = Some(6);
+        st.highlights.insert(1, ("shell".to_string(), Instant::now() + Duration::from_secs(60)));
+        st.next_checkpoint = Some(Instant::now() + Duration::from_secs(60));
+        let report = st.panic();
+        assert_eq!(report["closed_panes"], serde_json::json!([5]));
+        assert_eq!(report["ended_highlights"], serde_json::json!([1]));
+        assert_eq!(report["cancelled_checkpoint"], true);
+        assert!(st.highlights.is_empty());
+        assert!(st.next_checkpoint.is_none());
+    }
+
+    #[test]
+    fn oversized_waits_are_refused() {
+        let mut st = State::default();
+        st.apply_manifest(HashMap::from([(0, vec![pane(1, "shell")])]));
+        for (name, payload) in [
+            ("wait_for_pane", r#"{"pattern": "x", "timeout_secs": 1e20}"#),
+            ("wait_for_quiet", r#"{"pane_id": 1, "timeout_secs": 1e20}"#),
+            ("quiesce", r#"{"timeout_secs": 1e20}"#),
+            ("shutdown", r#"{"pane_id": 1, "grace_secs": 1e20}"#),
+        ] {
+            assert_eq!(st.handle_command(name, payload).error_code, Some(ErrorCode::InvalidArgument), "{}", name);
+        }
+        assert!(st.defer_reply.is_none());
+    }
+
+    #[test]
+    fn oversized_highlight_is_refused() {
+        let mut st = State::default();
+        st.apply_manifest(HashMap::from([(0, vec![pane(1, "shell")])]));
+        let r = st.handle_command("highlight", r#"{"pane_id": 1, "duration_secs": 1e20}"#);
+        assert_eq!(r.error_code, Some(ErrorCode::InvalidArgument));
+        assert!(st.highlights.is_empty());
+    }
+
+    #[test]
+    fn web_access_is_asked_for_on_first_fetch() {
+        let mut st = State::default();
+        assert!(!REQUIRED_PERMISSIONS.contains(&PermissionType::WebAccess));
+        let fetch = r#"{"url": "https://example.com"}"#;
+        let r = st.handle_command("fetch", fetch);
+        assert_eq!(r.error_code, Some(ErrorCode::PermissionDenied));
+        assert_eq!(r.data.unwrap()["permission_missing"], "WebAccess");
+        assert!(st.permissions.get("WebAccess") == Some(&PermissionState::Pending));
+
+        st.permissions.insert("WebAccess".to_string(), PermissionState::Granted);
+        assert!(st.handle_command("fetch", fetch).success);
+    }
+
+    #[test]
+    fn captures_are_numbered_apart_from_jobs() {
+        let mut st = State::default();
+        let job_id = st.enqueue_job(run("make"), 0, false, None);
+        let data = st.handle_command("run_captured", r#"{"command": "ls"}"#).data.unwrap();
+        assert_eq!(data["capture_id"], 1);
+        assert!(data.get("job_id").is_none());
+        assert_eq!(st.jobs.keys().collect::<Vec<_>>(), [&job_id]);
+        let r = st.handle_command("read_output", r#"{"capture_id": 1}"#);
+        assert_eq!(r.error_code, Some(ErrorCode::FileError));
+    }
+
+    #[test]
+    fn host_paths_stay_inside_the_allowlist() {
+        let mut st = State::default();
+        assert_eq!(st.host_path("notes/../a/./b.txt").ok(), Some("/host/a/b.txt".to_string()));
+        assert_eq!(st.host_path("/host/a").ok(), Some("/host/a".to_string()));
+        for escape in ["..", "a/../../etc/passwd", "/host/../etc", "/etc/passwd", "/hostile/a"] {
+            assert_eq!(st.host_path(escape).err().and_then(|r| r.error_code), Some(ErrorCode::InvalidArgument), "{}", escape);
+        }
+
+        st.config_overrides.insert("file_allowlist".to_string(), "a, ab/".to_string());
+        assert!(st.apply_config().is_empty());
+        for inside in ["a", "a/x", "ab", "ab/x", "/host/ab/x", "c/../a/x"] {
+            assert!(st.host_path(inside).is_ok(), "{}", inside);
+        }
+        for outside in ["abc", "abc/x", "b", "", "a/../abc"] {
+            assert_eq!(st.host_path(outside).err().and_then(|r| r.error_code), Some(ErrorCode::NotAllowed), "{}", outside);
+        }
+    }
+
+    #[test]
+    fn replayed_requests_are_refused() {
+        let mut st = State::default();
+        st.config_overrides.insert("replay_protection".to_string(), "true".to_string());
+        st.config_overrides.insert("replay_window_secs".to_string(), "30".to_string());
+        assert!(st.apply_config().is_empty());
+        let replayed = |st: &mut State, envelope: serde_json::Value, payload: &str| {
+            let envelope: Envelope = serde_json::from_value(envelope).unwrap();
+            st.check_replay(&envelope, "close", payload).and_then(|r| r.error_code)
+        };
+
+        assert_eq!(replayed(&mut st, serde_json::json!({}), "{}"), Some(ErrorCode::Replayed));
+        assert_eq!(replayed(&mut st, serde_json::json!({"nonce": 7}), "{}"), None);
+        assert_eq!(replayed(&mut st, serde_json::json!({"nonce": 7}), "{}"), Some(ErrorCode::Replayed));
+        assert_eq!(replayed(&mut st, serde_json::json!({"nonce": 3}), "{}"), Some(ErrorCode::Replayed));
+        assert_eq!(replayed(&mut st, serde_json::json!({"nonce": 8}), "{}"), None);
+
+        let now = unix_ms();
+        let stamped = serde_json::json!({"ts_ms": now});
+        assert_eq!(replayed(&mut st, stamped.clone(), r#"{"pane_id": 1}"#), None);
+        assert_eq!(replayed(&mut st, stamped.clone(), r#"{"pane_id": 1}"#), Some(ErrorCode::Replayed));
+        assert_eq!(replayed(&mut st, stamped, r#"{"pane_id": 2}"#), None);
+        for stale in [now - 60_000, now + 60_000] {
+            assert_eq!(replayed(&mut st, serde_json::json!({"ts_ms": stale}), "{}"), Some(ErrorCode::Replayed));
+        }
+    }
+
+    /// Steps a resize toward `target_cols` while the host applies `grow` per
+    /// step, returning how many steps were issued before it stopped
+    fn resize_steps(cols: usize, target_cols: usize, grow: impl Fn(usize) -> usize) -> u32 {
+        let mut pending = PendingResize {
+            target_rows: None,
+            target_cols: Some(target_cols),
+            steps_left: MAX_RESIZE_STEPS,
+            last_size: (0, 0),
+            last_diff: (0, 0),
+            stalls: 0,
+        };
+        let (mut cols, mut steps) = (cols, 0);
+        while State::step_resize(1, &mut pending, 24, cols) {
+            steps += 1;
+            assert!(steps <= MAX_RESIZE_STEPS + 3, "resize never stopped");
+            cols = grow(cols);
+        }
+        steps
+    }
+
+    #[test]
+    fn resizes_stop_at_target_overshoot_stall_or_step_limit() {
+        assert_eq!(resize_steps(76, 80, |c| c + 2), 2);
+        assert_eq!(resize_steps(80, 76, |c| c - 2), 2);
+        assert_eq!(resize_steps(78, 81, |c| c + 2), 2);
+        assert_eq!(resize_steps(80, 80, |c| c + 2), 0);
+        // A blocked pane: the first step, then two updates without change
+        assert_eq!(resize_steps(76, 80, |c| c), 3);
+        // The host flips direction at a screen edge and the pane keeps shrinking
+        assert_eq!(resize_steps(500, 1000, |c| c - 1), MAX_RESIZE_STEPS);
+    }
+
+    #[test]
+    fn adjacent_direction_needs_a_shared_edge() {
+        let placed = |id, x, y, columns, rows| PaneInfo {
+            pane_x: x,
+            pane_y: y,
+            pane_columns: columns,
+            pane_rows: rows,
+            ..pane(id, "shell")
+        };
+        let left = placed(1, 0, 0, 40, 20);
+        let top_right = placed(2, 40, 0, 40, 10);
+        let bottom_right = placed(3, 40, 10, 40, 10);
+        let below_corner = placed(4, 40, 20, 40, 10);
+        let apart = placed(5, 90, 0, 10, 10);
+        assert_eq!(State::adjacent_direction(&left, &top_right), Some(Direction::Right));
+        assert_eq!(State::adjacent_direction(&bottom_right, &left), Some(Direction::Left));
+        assert_eq!(State::adjacent_direction(&top_right, &bottom_right), Some(Direction::Down));
+        assert_eq!(State::adjacent_direction(&bottom_right, &top_right), Some(Direction::Up));
+        assert_eq!(State::adjacent_direction(&left, &below_corner), None);
+        assert_eq!(State::adjacent_direction(&top_right, &apart), None);
+        assert_eq!(State::adjacent_direction(&left, &left), None);
+    }
+
+    #[test]
+    fn relax_json_reads_json5() {
+        let relaxed = |text| relax_json(text).unwrap();
+        assert_eq!(relaxed(r"{cmd: 'say', text: 'it\'s'}"), serde_json::json!({"cmd": "say", "text": "it's"}));
+        assert_eq!(relaxed(r#"{text: 'say "hi"'}"#), serde_json::json!({"text": "say \"hi\""}));
+        assert_eq!(
+            relaxed("{url: 'http://host/a', path: \"/* kept */\"}"),
+            serde_json::json!({"url": "http://host/a", "path": "/* kept */"})
+        );
+        assert_eq!(
+            relaxed("{pane-id: 3, dry-run: true, offset: -2}"),
+            serde_json::json!({"pane-id": 3, "dry-run": true, "offset": -2})
+        );
+        assert_eq!(
+            relaxed("{args: ['-l', 'a',  // the last\n], /* done */}"),
+            serde_json::json!({"args": ["-l", "a"]})
+        );
+        assert_eq!(relaxed("[1, 2, /* three */]"), serde_json::json!([1, 2]));
+        assert!(relax_json("{cmd: }").is_none());
+    }
+
+    #[test]
+    fn edit_distance_counts_edits() {
+        assert_eq!(edit_distance("focus", "focus"), 0);
+        assert_eq!(edit_distance("focsu", "focus"), 2);
+        assert_eq!(edit_distance("clos", "close"), 1);
+        assert_eq!(edit_distance("rename_pane", "rename_tab"), 3);
+        assert_eq!(edit_distance("", "ping"), 4);
+        assert_eq!(edit_distance("ping", ""), 4);
+    }
+}
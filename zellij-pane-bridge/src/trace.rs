@@ -0,0 +1,135 @@
+//! Opt-in structured trace of what the bridge does.
+//!
+//! Each line of `/data/trace.jsonl` (the plugin's data directory) is one JSON
+//! record: an event received, a command executed or a host call made. The
+//! file rotates once it grows past the configured size, keeping a few old
+//! generations as `trace.jsonl.1`, `trace.jsonl.2`, ...
+
+use std::cell::RefCell;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TRACE_PATH: &str = "/data/trace.jsonl";
+
+/// Rotated files kept besides the live one
+const TRACE_GENERATIONS: usize = 3;
+
+thread_local! {
+    /// Size limit of the live trace file, `None` while tracing is off
+    static MAX_BYTES: RefCell<Option<u64>> = const { RefCell::new(None) };
+
+    /// Host calls held back during a dry run, `None` outside one
+    static CAPTURED: RefCell<Option<Vec<serde_json::Value>>> = const { RefCell::new(None) };
+}
+
+/// Turn tracing on with a size limit per file, or off with `None`
+pub fn configure(max_bytes: Option<u64>) {
+    MAX_BYTES.with(|m| *m.borrow_mut() = max_bytes);
+}
+
+pub fn enabled() -> bool {
+    MAX_BYTES.with(|m| m.borrow().is_some())
+}
+
+/// Append one record of `kind`, merging in the fields of `fields`
+pub fn record(kind: &str, fields: serde_json::Value) {
+    let Some(max_bytes) = MAX_BYTES.with(|m| *m.borrow()) else {
+        return;
+    };
+    let ts_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let mut line = serde_json::json!({"ts_ms": ts_ms, "kind": kind});
+    if let (Some(line), serde_json::Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+    let mut line = line.to_string();
+    line.push('\n');
+
+    let size = fs::metadata(TRACE_PATH).map(|m| m.len()).unwrap_or(0);
+    if size > 0 && size + line.len() as u64 > max_bytes {
+        rotate();
+    }
+    // A trace that cannot be written must never break the command being traced
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(TRACE_PATH)
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        eprintln!("zellij-pane-bridge: cannot write trace to {}: {}", TRACE_PATH, e);
+    }
+}
+
+/// Record a call into the Zellij host
+pub fn host_call(call: &str, args: String) {
+    record("host_call", serde_json::json!({"call": call, "args": args}));
+}
+
+/// Hold back host calls from now on, recording them instead. Returns false
+/// if a capture is already running, which then keeps collecting.
+pub fn start_capture() -> bool {
+    CAPTURED.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.is_some() {
+            return false;
+        }
+        *c = Some(Vec::new());
+        true
+    })
+}
+
+pub fn capturing() -> bool {
+    CAPTURED.with(|c| c.borrow().is_some())
+}
+
+/// Note a host call that a dry run held back
+pub fn capture(call: &str, args: String) {
+    CAPTURED.with(|c| {
+        if let Some(calls) = c.borrow_mut().as_mut() {
+            calls.push(serde_json::json!({"call": call, "args": args}));
+        }
+    });
+}
+
+/// Make host calls again, returning the ones held back
+pub fn finish_capture() -> Vec<serde_json::Value> {
+    CAPTURED.with(|c| c.borrow_mut().take()).unwrap_or_default()
+}
+
+fn rotate() {
+    for generation in (1..TRACE_GENERATIONS).rev() {
+        let _ = fs::rename(
+            format!("{}.{}", TRACE_PATH, generation),
+            format!("{}.{}", TRACE_PATH, generation + 1),
+        );
+    }
+    let _ = fs::rename(TRACE_PATH, format!("{}.1", TRACE_PATH));
+}
+
+/// Make a host call, tracing it first when tracing is on. During a dry run
+/// the call is only recorded.
+///
+/// Each argument is bound once (hygiene keeps the `arg` bindings apart), so
+/// moved values can be both traced and passed on.
+macro_rules! host {
+    ($call:ident($($arg:expr),* $(,)?)) => {
+        host!(@bind $call [] $($arg),*)
+    };
+    (@bind $call:ident [$($bound:ident)*] $head:expr $(, $rest:expr)*) => {{
+        let arg = $head;
+        host!(@bind $call [$($bound)* arg] $($rest),*)
+    }};
+    (@bind $call:ident [$($bound:ident)*]) => {{
+        if $crate::trace::capturing() {
+            $crate::trace::capture(stringify!($call), format!("{:?}", ($(&$bound,)*)));
+        } else {
+            if $crate::trace::enabled() {
+                $crate::trace::host_call(stringify!($call), format!("{:?}", ($(&$bound,)*)));
+            }
+            $call($($bound),*)
+        }
+    }};
+}
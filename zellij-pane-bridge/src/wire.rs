@@ -0,0 +1,356 @@
+//! Wire encodings for pipe payloads and replies.
+//!
+//! JSON is the default. Clients that want compact payloads can ask for
+//! MessagePack or CBOR, which travel base64-encoded since pipes carry text.
+//! Payloads may also be written in KDL, Zellij's configuration language, so
+//! pipes bound to keys in a Zellij config read naturally; replies to those
+//! are JSON.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+    Kdl,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name.trim().to_lowercase().as_str() {
+            "json" => Some(Encoding::Json),
+            "msgpack" | "messagepack" => Some(Encoding::Msgpack),
+            "cbor" => Some(Encoding::Cbor),
+            "kdl" => Some(Encoding::Kdl),
+            _ => None,
+        }
+    }
+}
+
+/// Turn a payload in `encoding` into JSON text for the command parser
+pub fn decode(encoding: Encoding, payload: &str) -> Result<String, String> {
+    if encoding == Encoding::Json || payload.trim().is_empty() {
+        return Ok(payload.to_string());
+    }
+    if encoding == Encoding::Kdl {
+        let value = kdl_to_json(payload).map_err(|e| format!("payload is not valid KDL: {}", e))?;
+        return serde_json::to_string(&value).map_err(|e| e.to_string());
+    }
+    let bytes = STANDARD.decode(payload.trim())
+        .map_err(|e| format!("payload is not valid base64: {}", e))?;
+    let value: serde_json::Value = match encoding {
+        Encoding::Msgpack => rmp_serde::from_slice(&bytes)
+            .map_err(|e| format!("payload is not valid MessagePack: {}", e))?,
+        Encoding::Cbor => ciborium::from_reader(bytes.as_slice())
+            .map_err(|e| format!("payload is not valid CBOR: {}", e))?,
+        Encoding::Json | Encoding::Kdl => unreachable!(),
+    };
+    serde_json::to_string(&value).map_err(|e| e.to_string())
+}
+
+/// Serialize a reply in `encoding`
+pub fn encode<T: Serialize>(encoding: Encoding, value: &T) -> Result<String, String> {
+    match encoding {
+        Encoding::Json | Encoding::Kdl => serde_json::to_string(value).map_err(|e| e.to_string()),
+        Encoding::Msgpack => rmp_serde::to_vec_named(value)
+            .map(|bytes| STANDARD.encode(bytes))
+            .map_err(|e| e.to_string()),
+        Encoding::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(value, &mut bytes)
+                .map(|_| STANDARD.encode(bytes))
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Whether an unflagged payload is KDL rather than JSON: it starts with a
+/// node name instead of `{` or `[`
+pub fn looks_like_kdl(payload: &str) -> bool {
+    payload.trim_start().chars().next().is_some_and(|c| c.is_alphabetic() || c == '_' || c == '"')
+        && serde_json::from_str::<serde_json::Value>(payload).is_err()
+}
+
+/// Read a KDL document as a JSON object, one field per node:
+///
+/// - `pane_id 3` is a value and `args "-l" "-a"` an array; a repeated node
+///   collects its values into an array too
+/// - `force` alone is `true`
+/// - `env { FOO "bar" }` and `env FOO="bar"` are objects
+/// - `args { - "-l" }` is an array, for lists of a single item
+fn kdl_to_json(text: &str) -> Result<serde_json::Value, String> {
+    let mut parser = KdlParser { chars: text.chars().collect(), pos: 0 };
+    let nodes = parser.nodes(false)?;
+    Ok(serde_json::Value::Object(nodes))
+}
+
+struct KdlParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl KdlParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars().enumerate().all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn error(&self, what: &str) -> String {
+        let line = self.chars[..self.pos.min(self.chars.len())].iter().filter(|&&c| c == '\n').count() + 1;
+        format!("{} on line {}", what, line)
+    }
+
+    /// Skip spaces, comments and escaped line breaks, stopping at a line break
+    fn skip_inline(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() && c != '\n' => self.pos += 1,
+                Some('\\') => {
+                    // A line continuation: the node carries on on the next line
+                    self.pos += 1;
+                    self.skip_inline();
+                    if self.peek() == Some('\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some('/') if self.starts_with("/*") => {
+                    let rest = &self.chars[self.pos + 2..];
+                    let end = rest.windows(2).position(|w| w == ['*', '/']).map_or(rest.len(), |n| n + 2);
+                    self.pos += 2 + end;
+                }
+                Some('/') if self.starts_with("//") => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Nodes up to the end of the text, or up to the `}` closing a child block
+    fn nodes(&mut self, in_block: bool) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+        let mut map = serde_json::Map::new();
+        loop {
+            self.skip_inline();
+            match self.peek() {
+                None if in_block => return Err(self.error("unclosed '{'")),
+                None => return Ok(map),
+                Some('}') if in_block => {
+                    self.pos += 1;
+                    return Ok(map);
+                }
+                Some('}') => return Err(self.error("unexpected '}'")),
+                Some('\n') | Some(';') => self.pos += 1,
+                Some('/') if self.starts_with("/-") => {
+                    // Slashdash comments out the next node
+                    self.pos += 2;
+                    self.skip_inline();
+                    self.node()?;
+                }
+                Some(_) => {
+                    let (name, value) = self.node()?;
+                    match map.get_mut(&name) {
+                        Some(serde_json::Value::Array(items)) if !value.is_array() => items.push(value),
+                        Some(existing) => {
+                            let first = existing.take();
+                            *existing = serde_json::Value::Array(vec![first, value]);
+                        }
+                        None => {
+                            map.insert(name, value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// A node name with its arguments, properties and children, as a field
+    fn node(&mut self) -> Result<(String, serde_json::Value), String> {
+        let name = match self.value()? {
+            serde_json::Value::String(name) => name,
+            other => return Err(self.error(&format!("node name expected, found {}", other))),
+        };
+        let mut args = Vec::new();
+        let mut props = serde_json::Map::new();
+        let mut children = None;
+        loop {
+            self.skip_inline();
+            match self.peek() {
+                None | Some('\n') | Some(';') | Some('}') => break,
+                Some('{') => {
+                    self.pos += 1;
+                    children = Some(self.nodes(true)?);
+                    break;
+                }
+                Some(_) => {
+                    let value = self.value()?;
+                    if self.peek() == Some('=') {
+                        self.pos += 1;
+                        let key = value.as_str().ok_or_else(|| self.error("property name must be a string"))?.to_string();
+                        props.insert(key, self.value()?);
+                    } else {
+                        args.push(value);
+                    }
+                }
+            }
+        }
+        let value = match (args.len(), props.is_empty(), children) {
+            // `- value` children make a list
+            (0, true, Some(children)) if !children.is_empty() && children.keys().all(|k| k == "-") => {
+                match children.into_iter().next().map(|(_, v)| v) {
+                    Some(serde_json::Value::Array(items)) => serde_json::Value::Array(items),
+                    Some(item) => serde_json::Value::Array(vec![item]),
+                    None => serde_json::Value::Array(Vec::new()),
+                }
+            }
+            (0, _, children) => {
+                if props.is_empty() && children.is_none() {
+                    serde_json::Value::Bool(true)
+                } else {
+                    props.extend(children.unwrap_or_default());
+                    serde_json::Value::Object(props)
+                }
+            }
+            (_, true, None) if args.len() == 1 => args.remove(0),
+            (_, true, None) => serde_json::Value::Array(args),
+            _ => return Err(self.error(&format!("node '{}' mixes values with properties or children", name))),
+        };
+        Ok((name, value))
+    }
+
+    /// A string, raw string, number, keyword or bare identifier
+    fn value(&mut self) -> Result<serde_json::Value, String> {
+        // Type annotations such as `(u32)3` carry nothing JSON needs
+        if self.peek() == Some('(') {
+            while self.peek().is_some_and(|c| c != ')') {
+                self.pos += 1;
+            }
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some('"') => self.string().map(serde_json::Value::String),
+            Some('r') if self.starts_with("r\"") || self.starts_with("r#") => self.raw_string().map(serde_json::Value::String),
+            Some('#') if self.starts_with("#\"") || self.starts_with("##") => self.raw_string().map(serde_json::Value::String),
+            Some(_) => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| !c.is_whitespace() && !"{}();=\"".contains(c)) {
+                    self.pos += 1;
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                Ok(match word.trim_start_matches('#') {
+                    _ if word.is_empty() => return Err(self.error("value expected")),
+                    "true" => serde_json::Value::Bool(true),
+                    "false" => serde_json::Value::Bool(false),
+                    "null" => serde_json::Value::Null,
+                    number if number.trim_start_matches(['-', '+']).starts_with(|c: char| c.is_ascii_digit()) => {
+                        let number = number.trim_start_matches('+').replace('_', "");
+                        serde_json::from_str(&number).map_err(|_| self.error(&format!("bad number '{}'", word)))?
+                    }
+                    _ => serde_json::Value::String(word),
+                })
+            }
+            None => Err(self.error("value expected")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escaped = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    match escaped {
+                        'n' => out.push('\n'),
+                        't' => out.push('\t'),
+                        'r' => out.push('\r'),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        's' => out.push(' '),
+                        'u' if self.peek() == Some('{') => {
+                            let start = self.pos + 1;
+                            let end = self.chars[start..].iter().position(|&c| c == '}')
+                                .ok_or_else(|| self.error("unterminated unicode escape"))? + start;
+                            let hex: String = self.chars[start..end].iter().collect();
+                            let ch = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                                .ok_or_else(|| self.error(&format!("bad unicode escape '{}'", hex)))?;
+                            out.push(ch);
+                            self.pos = end + 1;
+                        }
+                        other => out.push(other),
+                    }
+                }
+                other => out.push(other),
+            }
+        }
+    }
+
+    /// `r#"..."#` (KDL 1) or `#"..."#` (KDL 2), with any number of hashes
+    fn raw_string(&mut self) -> Result<String, String> {
+        if self.peek() == Some('r') {
+            self.pos += 1;
+        }
+        let mut hashes = 0;
+        while self.peek() == Some('#') {
+            hashes += 1;
+            self.pos += 1;
+        }
+        if self.peek() != Some('"') {
+            return Err(self.error("raw string expected"));
+        }
+        self.pos += 1;
+        let closing: String = std::iter::once('"').chain(std::iter::repeat_n('#', hashes)).collect();
+        let start = self.pos;
+        while !self.starts_with(&closing) {
+            if self.peek().is_none() {
+                return Err(self.error("unterminated raw string"));
+            }
+            self.pos += 1;
+        }
+        let text = self.chars[start..self.pos].iter().collect();
+        self.pos += closing.chars().count();
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn kdl_values_and_lists() {
+        let kdl = |text| kdl_to_json(text).unwrap();
+        assert_eq!(kdl("cmd \"run\"\npane_id 3\nforce"), json!({"cmd": "run", "pane_id": 3, "force": true}));
+        assert_eq!(kdl("args \"-l\" \"-a\""), json!({"args": ["-l", "-a"]}));
+        assert_eq!(kdl("args { - \"-l\" }"), json!({"args": ["-l"]}));
+        assert_eq!(kdl("args {\n    - \"-l\"\n    - \"-a\"\n}"), json!({"args": ["-l", "-a"]}));
+        assert_eq!(kdl("tag \"a\"\ntag \"b\""), json!({"tag": ["a", "b"]}));
+        assert_eq!(kdl("env FOO=\"bar\""), json!({"env": {"FOO": "bar"}}));
+        assert_eq!(kdl("env { FOO \"bar\"; }"), json!({"env": {"FOO": "bar"}}));
+    }
+
+    #[test]
+    fn kdl_words_strings_and_comments() {
+        let kdl = |text| kdl_to_json(text).unwrap();
+        assert_eq!(kdl("command ls -la --color\noffset -2"), json!({"command": ["ls", "-la", "--color"], "offset": -2}));
+        assert_eq!(kdl("url \"http://host/a\" // trailing"), json!({"url": "http://host/a"}));
+        assert_eq!(kdl("text \"say \\\"hi\\\"\" /* inline */"), json!({"text": "say \"hi\""}));
+        assert_eq!(kdl("text r#\"C:\\dir \"x\"\"#"), json!({"text": "C:\\dir \"x\""}));
+        assert_eq!(kdl("/-pane_id 1\npane_id (u32)2"), json!({"pane_id": 2}));
+        assert!(kdl_to_json("env { FOO \"bar\"").is_err());
+        assert!(kdl_to_json("args \"a\" key=1").is_err());
+    }
+}
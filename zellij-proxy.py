@@ -146,10 +146,12 @@ class ZellijProxy:
             )
             stdout = result.stdout.strip()
             if stdout:
-                # Handle duplicate JSON responses
-                if '}{' in stdout:
-                    stdout = stdout.split('}{')[0] + '}'
-                return json.loads(stdout)
+                # Replies are one per line, after any {"progress": ...} lines of long requests
+                for line in stdout.splitlines():
+                    reply = json.loads(line)
+                    if isinstance(reply, dict) and 'progress' not in reply:
+                        return reply
+                return {'success': False, 'error': 'No reply', 'raw': stdout}
             return {'success': False, 'error': 'No output'}
         except json.JSONDecodeError as e:
             return {'success': False, 'error': f'JSON decode error: {e}', 'raw': stdout}